@@ -1,7 +1,11 @@
+use crate::generators::outpainter::Outpainter;
 use anyhow::{anyhow, Result};
 use base64::engine::general_purpose;
 use base64::Engine;
+use image::{ImageFormat, RgbaImage};
 use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use std::io::Cursor;
 use timing_macro::timed;
 
 /// A struct representing an image generator that interacts with the OpenAI API.
@@ -69,4 +73,49 @@ impl ImageGenerator {
                 .map_err(|e| anyhow!("Failed to decode base64 image: {}", e))
         })
     }
-}
\ No newline at end of file
+}
+
+impl Outpainter for ImageGenerator {
+    /// Fills the transparent part of an image using the OpenAI image edit endpoint.
+    ///
+    /// The image is sent as a PNG without a separate mask, so its transparent pixels are the ones
+    /// the endpoint paints, and the completed image is decoded from the base64 response.
+    #[timed]
+    fn outpaint(&self, image: &RgbaImage, prompt: &str) -> Result<RgbaImage> {
+        let mut png = Vec::new();
+        image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+
+        let image_data: String = Python::with_gil(|py| -> PyResult<String> {
+            let openai = PyModule::import(py, "openai")?;
+
+            // Create keyword arguments dictionary
+            let kwargs = pyo3::types::PyDict::new(py);
+            kwargs.set_item("api_key", self.api_key.clone())?;
+
+            // Call OpenAI() with keyword arguments
+            let client = openai.getattr("OpenAI")?.call((), Some(&kwargs))?;
+
+            // Create the image edit request, passing the PNG as a named file
+            let edit_kwargs = pyo3::types::PyDict::new(py);
+            edit_kwargs.set_item("model", "dall-e-2")?;
+            edit_kwargs.set_item("image", ("image.png", PyBytes::new(py, &png), "image/png"))?;
+            edit_kwargs.set_item("prompt", prompt)?;
+            edit_kwargs.set_item("size", format!("{}x{}", image.width(), image.height()))?;
+            edit_kwargs.set_item("n", 1)?;
+            edit_kwargs.set_item("response_format", "b64_json")?;
+
+            let response = client.getattr("images")?
+                .call_method("edit", (), Some(&edit_kwargs))?;
+
+            // Extract base64 image data
+            response.getattr("data")?.get_item(0)?.getattr("b64_json")?.extract()
+        })?;
+
+        println!("Outpainted image, base64 length: {}", image_data.len());
+
+        let bytes = general_purpose::STANDARD
+            .decode(&image_data)
+            .map_err(|e| anyhow!("Failed to decode base64 image: {}", e))?;
+        Ok(image::load_from_memory(&bytes)?.to_rgba8())
+    }
+}
@@ -1,2 +1,3 @@
 pub mod prompt_generator;
-pub mod image_generator;
\ No newline at end of file
+pub mod image_generator;
+pub mod outpainter;
\ No newline at end of file
@@ -0,0 +1,133 @@
+use anyhow::{anyhow, Result};
+use image::{imageops, Rgba, RgbaImage};
+use std::str::FromStr;
+
+/// A backend that fills the transparent part of an image with content continuing the rest of it.
+pub trait Outpainter {
+    /// Fills the fully transparent pixels of an image, keeping its size.
+    ///
+    /// # Arguments
+    /// * `image` - The image to complete, transparent where new content is wanted.
+    /// * `prompt` - The prompt the image was generated from, describing the content to continue.
+    ///
+    /// # Returns
+    /// A `Result` containing the completed image if successful, or an error otherwise.
+    fn outpaint(&self, image: &RgbaImage, prompt: &str) -> Result<RgbaImage>;
+}
+
+/// A local stand-in for an outpainting endpoint, which continues an image by mirroring it.
+///
+/// It needs no network access or API key, so it is used by tests and for trying out wide strips
+/// offline. The mirrored content repeats visibly, unlike that of a real endpoint.
+pub struct MirrorOutpainter;
+
+impl Outpainter for MirrorOutpainter {
+    fn outpaint(&self, image: &RgbaImage, _prompt: &str) -> Result<RgbaImage> {
+        let (width, height) = image.dimensions();
+        let covered: Vec<u32> = (0..width).filter(|&x| (0..height).any(|y| image.get_pixel(x, y)[3] > 0)).collect();
+        let (Some(&left), Some(&right)) = (covered.first(), covered.last()) else {
+            return Err(anyhow!("Cannot outpaint a fully transparent image."));
+        };
+
+        // Columns outside the covered range reflect it back and forth, so both edges continue seamlessly
+        let span = (right - left + 1) as i64;
+        Ok(RgbaImage::from_fn(width, height, |x, y| {
+            let reflected = (x as i64 - left as i64).rem_euclid(2 * span);
+            let source = if reflected < span { reflected } else { 2 * span - 1 - reflected };
+            *image.get_pixel(left + source as u32, y)
+        }))
+    }
+}
+
+/// How far and with which backend the source image is extended before it is split into layers.
+///
+/// Written as `<steps>[:local]`, for example `2` to extend the image by two of its widths with
+/// the image generator, or `2:local` to use the `MirrorOutpainter` instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Outpainting {
+    /// The number of extensions on each side, each adding half the width of the image.
+    pub steps: u32,
+    /// Whether the local stand-in is used instead of the image generator.
+    pub local: bool,
+}
+
+impl FromStr for Outpainting {
+    type Err = String;
+
+    fn from_str(spec: &str) -> std::result::Result<Self, Self::Err> {
+        let (steps, local) = match spec.split_once(':') {
+            Some((steps, "local")) => (steps, true),
+            Some((_, backend)) => return Err(format!("Unknown outpainting backend '{}', expected 'local'.", backend)),
+            None => (spec, false),
+        };
+        match steps.parse::<u32>() {
+            Ok(steps) if steps > 0 => Ok(Self { steps, local }),
+            _ => Err(format!("Invalid outpainting '{}', expected <steps>[:local] with at least one step.", spec)),
+        }
+    }
+}
+
+/// Extends an image to the left and right by outpainting, stitching the new content onto it.
+///
+/// Each step outpaints a window the size of the original image twice: once holding the right half
+/// of the strip so far with the other half transparent, and once mirrored for the left. Only the
+/// new halves are kept, so the original pixels and those from earlier steps are never changed.
+///
+/// # Arguments
+/// * `outpainter` - The backend completing each window.
+/// * `image` - The image to extend.
+/// * `steps` - The number of extensions on each side, each adding half the width of the image.
+/// * `prompt` - The prompt the image was generated from.
+///
+/// # Returns
+/// A `Result` containing the strip, `steps + 1` times as wide as the image, or an error if the backend fails.
+pub fn extend_horizontally(outpainter: &dyn Outpainter, image: &RgbaImage, steps: u32, prompt: &str) -> Result<RgbaImage> {
+    let (width, height) = image.dimensions();
+    let added = width / 2;
+    let kept = width - added;
+    let mut strip = image.clone();
+
+    for step in 1..=steps {
+        let mut window = RgbaImage::from_pixel(width, height, Rgba([0, 0, 0, 0]));
+        imageops::replace(&mut window, &*imageops::crop_imm(&strip, strip.width() - kept, 0, kept, height), 0, 0);
+        let right = outpainter.outpaint(&window, prompt)?;
+
+        let mut window = RgbaImage::from_pixel(width, height, Rgba([0, 0, 0, 0]));
+        imageops::replace(&mut window, &*imageops::crop_imm(&strip, 0, 0, kept, height), added as i64, 0);
+        let left = outpainter.outpaint(&window, prompt)?;
+
+        if right.dimensions() != (width, height) || left.dimensions() != (width, height) {
+            return Err(anyhow!("Outpainting returned an image of a different size than the {}x{} it was given.", width, height));
+        }
+
+        let mut extended = RgbaImage::new(strip.width() + 2 * added, height);
+        imageops::replace(&mut extended, &*imageops::crop_imm(&left, 0, 0, added, height), 0, 0);
+        imageops::replace(&mut extended, &strip, added as i64, 0);
+        imageops::replace(&mut extended, &*imageops::crop_imm(&right, kept, 0, added, height), (added + strip.width()) as i64, 0);
+        strip = extended;
+
+        println!("Outpainting step {} of {}: strip is now {}x{}", step, steps, strip.width(), height);
+    }
+
+    Ok(strip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_grows_on_both_sides_and_keeps_the_original() {
+        let image = RgbaImage::from_fn(4, 2, |x, _| Rgba([x as u8 * 10, 0, 0, 255]));
+
+        let strip = extend_horizontally(&MirrorOutpainter, &image, 2, "mountains").unwrap();
+
+        assert_eq!(strip.dimensions(), (12, 2));
+        assert_eq!(*imageops::crop_imm(&strip, 4, 0, 4, 2).to_image(), *image);
+        // The mirrored halves continue the image outward from both of its edges
+        let row: Vec<u8> = (0..12).map(|x| strip.get_pixel(x, 1)[0]).collect();
+        assert_eq!(row, vec![0, 10, 10, 0, 0, 10, 20, 30, 30, 20, 20, 30]);
+        assert_eq!("3:local".parse::<Outpainting>().unwrap(), Outpainting { steps: 3, local: true });
+        assert!("0".parse::<Outpainting>().is_err() && "2:cloud".parse::<Outpainting>().is_err());
+    }
+}
@@ -0,0 +1,203 @@
+use crate::graphics::compositing::BlendMode;
+use crate::graphics::sprites::{animation_frame, draw_sprite_resampled, scale_frame, try_load_sprites_from_map, SpriteFrame, SpriteTransform};
+use crate::state::constants::graphics::{ACTOR_DEPTH, ACTOR_FRAMES_PER_SECOND, ACTOR_GROUND_LINE, ACTOR_SCROLL_FACTOR, ACTOR_SPEED};
+use crate::state::structs::State;
+use std::error::Error;
+use std::path::Path;
+
+/// An animated character running across the scene, drawn from the frames of a sprite sheet.
+pub struct Actor {
+    /// The animation frames, played in order and looped.
+    frames: Vec<SpriteFrame>,
+    /// How many animation frames are played per second.
+    pub frames_per_second: f32,
+    /// The fraction of the scene's height the actor's feet stand on, from 0.0 at the top to 1.0 at the bottom.
+    pub ground_line: f32,
+    /// How far the actor runs to the right per second, in pixels of the scene.
+    pub speed: f32,
+    /// The fraction of the camera movement the actor follows, like the layer it runs on.
+    pub scroll_factor: f32,
+    /// The number of parallax layers drawn beneath the actor.
+    pub depth: usize,
+}
+
+impl Actor {
+    /// Creates an actor running along the default ground line between layers 3 and 4.
+    ///
+    /// # Arguments
+    /// * `frames` - The animation frames, which all need the same size.
+    ///
+    /// # Returns
+    /// The `Actor`, or an error if there are no frames or their sizes differ.
+    pub fn new(frames: Vec<SpriteFrame>) -> Result<Self, Box<dyn Error>> {
+        let Some(first) = frames.first() else {
+            return Err("An actor needs at least one animation frame.".into());
+        };
+        if frames.iter().any(|frame| (frame.width, frame.height) != (first.width, first.height)) {
+            return Err("All animation frames of an actor need the same size.".into());
+        }
+
+        Ok(Self {
+            frames,
+            frames_per_second: ACTOR_FRAMES_PER_SECOND,
+            ground_line: ACTOR_GROUND_LINE,
+            speed: ACTOR_SPEED,
+            scroll_factor: ACTOR_SCROLL_FACTOR,
+            depth: ACTOR_DEPTH,
+        })
+    }
+
+    /// Loads an actor from a spec written as `path:<width>x<height>[:fps]`.
+    ///
+    /// The sprite sheet at `path` is sliced into frames of the given size, row by row, with
+    /// `try_load_sprites_from_map`. For example `sprites/runner.png:64x64:12` plays the 64x64 frames
+    /// of the runner sheet at twelve frames per second.
+    ///
+    /// # Arguments
+    /// * `spec` - The sprite sheet, frame size and optional frame rate.
+    pub fn from_spec(spec: &str) -> Result<Self, Box<dyn Error>> {
+        let spec = ActorSpec::parse(spec)?;
+
+        if !Path::new(spec.path).is_file() {
+            return Err(format!("Actor sprite sheet '{}' does not exist.", spec.path).into());
+        }
+
+        let mut actor = Self::new(try_load_sprites_from_map(spec.path, spec.width, spec.height)?)?;
+        actor.frames_per_second = spec.frames_per_second.unwrap_or(actor.frames_per_second);
+        Ok(actor)
+    }
+
+    /// Resamples the animation frames by a factor, so the actor keeps its size relative to the
+    /// layers when they are scaled to the viewport.
+    ///
+    /// # Arguments
+    /// * `scale` - The factor the frames are scaled by, where 1.0 leaves them unchanged.
+    pub fn scale_frames(&mut self, scale: f32) {
+        self.frames = self.frames.iter().map(|frame| scale_frame(frame, scale)).collect();
+    }
+
+    /// The animation frame shown at a time on the timeline, in seconds.
+    fn frame_at(&self, time: f32) -> &SpriteFrame {
+        animation_frame(&self.frames, self.frames_per_second, time)
+    }
+}
+
+/// The fields of an actor spec written as `path:<width>x<height>[:fps]`.
+#[derive(Debug, PartialEq)]
+struct ActorSpec<'a> {
+    /// The sprite sheet holding the animation frames.
+    path: &'a str,
+    /// The width of a frame in pixels.
+    width: u32,
+    /// The height of a frame in pixels.
+    height: u32,
+    /// The frame rate, if one is given.
+    frames_per_second: Option<f32>,
+}
+
+impl<'a> ActorSpec<'a> {
+    /// Splits a spec into its fields, taking them from the right so paths may contain colons, such
+    /// as Windows drive letters.
+    ///
+    /// # Returns
+    /// The `ActorSpec`, or an error if the size or frame rate is invalid.
+    fn parse(spec: &'a str) -> Result<Self, Box<dyn Error>> {
+        let missing_size = || format!("Actor '{}' has no frame size, expected path:<width>x<height>[:fps].", spec);
+        let (rest, last) = spec.rsplit_once(':').ok_or_else(missing_size)?;
+        let (path, size, frames_per_second) = match last.contains('x') {
+            true => (rest, last, None),
+            false => {
+                let (path, size) = rest.rsplit_once(':').ok_or_else(missing_size)?;
+                (path, size, Some(last))
+            }
+        };
+
+        let invalid_size = || format!("Invalid actor frame size '{}', expected <width>x<height>.", size);
+        let (width, height) = size.split_once('x').ok_or_else(invalid_size)?;
+        let (width, height): (u32, u32) = (width.parse().map_err(|_| invalid_size())?, height.parse().map_err(|_| invalid_size())?);
+        if width == 0 || height == 0 {
+            return Err(invalid_size().into());
+        }
+
+        let frames_per_second = frames_per_second
+            .map(|fps| match fps.parse::<f32>() {
+                Ok(fps) if fps > 0.0 && fps.is_finite() => Ok(fps),
+                _ => Err(format!("Invalid actor frame rate '{}', expected a positive number.", fps)),
+            })
+            .transpose()?;
+
+        Ok(Self { path, width, height, frames_per_second })
+    }
+}
+
+/// Draws the actor if it sits above a number of parallax layers.
+///
+/// The actor runs to the right at its own speed while the camera moves it by its scroll factor,
+/// and wraps around to the left edge once it has left the window. Its feet stay on the ground
+/// line, which moves with vertical camera pans and zoom like the layer it runs on. Its speed and
+/// ground line are in pixels of the scene, scaled to the viewport like the layers.
+///
+/// # Arguments
+/// * `state` - The application state, with the actor, camera and window buffer.
+/// * `depth` - The number of layers drawn so far. An actor deeper than the last layer is drawn with it.
+pub fn draw_actor(state: &mut State, depth: usize) {
+    let Some(actor) = &state.actor else {
+        return;
+    };
+    if actor.depth.min(state.layers.len()) != depth {
+        return;
+    }
+
+    let sprite = actor.frame_at(state.time);
+    let scale = state.render_scale;
+    let travel = state.window_width as f32 + sprite.width as f32;
+    let x = ((actor.speed * state.time - state.camera.x * actor.scroll_factor) * scale).rem_euclid(travel) - sprite.width as f32;
+    let ground_y = actor.ground_line * state.sprites.scene_height as f32;
+    let y = (ground_y + state.camera.y * actor.scroll_factor) * scale - sprite.height as f32;
+
+    let transform = SpriteTransform {
+        offset_x: -x,
+        offset_y: y,
+        scale: 1.0 + (state.camera.zoom - 1.0) * actor.scroll_factor,
+        focus_x: state.camera.focus_x,
+        focus_y: state.camera.focus_y,
+        repeat_x: false,
+        mirror_x: false,
+        repeat_y: false,
+    };
+
+    draw_sprite_resampled(sprite, transform, 1.0, [255, 255, 255], BlendMode::Normal, state.window_buffer, state.window_width);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames_follow_the_frame_rate_and_sizes_must_match() {
+        let frames = (0..4u32).map(|index| SpriteFrame::new(2, 2, vec![0xFF000000 | index; 4])).collect();
+        let mut actor = Actor::new(frames).unwrap();
+        actor.frames_per_second = 5.0;
+
+        // Sampled every tenth of a second, each animation frame is shown twice
+        let shown: Vec<u32> = (0..10).map(|frame| actor.frame_at(frame as f32 / 10.0).pixels()[0] & 0xFF).collect();
+        assert_eq!(shown, vec![0, 0, 1, 1, 2, 2, 3, 3, 0, 0]);
+
+        assert!(Actor::new(Vec::new()).is_err());
+        assert!(Actor::new(vec![SpriteFrame::new(2, 2, vec![0; 4]), SpriteFrame::new(1, 1, vec![0])]).is_err());
+        assert!(Actor::from_spec("missing.png:64x64").is_err());
+        assert!(Actor::from_spec("missing.png").is_err());
+    }
+
+    #[test]
+    fn test_specs_are_parsed_from_the_right() {
+        let spec = |path, width, height, frames_per_second| ActorSpec { path, width, height, frames_per_second };
+        assert_eq!(ActorSpec::parse("sprites/runner.png:64x32:12").unwrap(), spec("sprites/runner.png", 64, 32, Some(12.0)));
+        assert_eq!(ActorSpec::parse(r"C:\art\hero.png:64x64").unwrap(), spec(r"C:\art\hero.png", 64, 64, None));
+        assert_eq!(ActorSpec::parse(r"C:\art\hero.png:64x64:8").unwrap(), spec(r"C:\art\hero.png", 64, 64, Some(8.0)));
+
+        for spec in ["runner.png:64x64:12:5", "runner.png:0x64", "runner.png:64x64:-5", "runner.png:64x64:NaN", "runner.png:64x64:inf"] {
+            assert!(ActorSpec::parse(spec).is_err(), "{} was accepted", spec);
+        }
+    }
+}
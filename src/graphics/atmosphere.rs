@@ -0,0 +1,233 @@
+use crate::graphics::compositing::PremultipliedColor;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+
+/// Fades a layer toward a sky color the farther away it is.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Haze {
+    /// The RGB color distant layers fade into.
+    pub color: [u8; 3],
+    /// How much of the color a layer standing still gets, from 0.0 to 1.0. Layers scrolling with
+    /// the camera get none, and the layers in between get an amount proportional to their depth.
+    pub amount: f32,
+}
+
+/// Horizontal bands of fog that undulate and drift sideways over a layer.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FogBands {
+    /// The RGB color of the fog.
+    pub color: [u8; 3],
+    /// How opaque the fog is at the middle of a band, from 0.0 to 1.0.
+    pub opacity: f32,
+    /// The vertical distance between two bands, in pixels.
+    #[serde(default = "default_band_spacing")]
+    pub spacing: f32,
+    /// How far the undulation of the bands drifts per second, in pixels.
+    #[serde(default = "default_fog_speed")]
+    pub speed: f32,
+}
+
+fn default_band_spacing() -> f32 {
+    96.0
+}
+
+fn default_fog_speed() -> f32 {
+    40.0
+}
+
+/// The atmospheric effects applied to a layer before it is composited onto the layers behind it.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct LayerEffects {
+    /// The distance haze, if any.
+    #[serde(default)]
+    pub haze: Option<Haze>,
+    /// The animated fog bands, if any.
+    #[serde(default)]
+    pub fog: Option<FogBands>,
+    /// The radius of the depth-of-field blur in pixels, where 0 keeps the layer sharp.
+    #[serde(default)]
+    pub blur_radius: usize,
+}
+
+impl LayerEffects {
+    /// Whether the layer is drawn as is, so it can be drawn straight onto the window.
+    pub fn is_empty(&self) -> bool {
+        self.haze.is_none() && self.fog.is_none() && self.blur_radius == 0
+    }
+
+    /// Applies the haze, the fog and then the blur to a rendered layer.
+    ///
+    /// # Arguments
+    /// * `pixels` - The layer rendered on its own, in premultiplied colors.
+    /// * `width` - The width of the layer in pixels.
+    /// * `depth` - How far away the layer is, from 0.0 for the camera plane to 1.0 for the sky.
+    /// * `time` - The time of the frame being rendered on the timeline, in seconds, which animates the fog.
+    pub fn apply(&self, pixels: &mut [PremultipliedColor], width: usize, depth: f32, time: f32) {
+        if let Some(haze) = self.haze {
+            let amount = (haze.amount * depth).clamp(0.0, 1.0);
+            pixels.par_iter_mut().for_each(|pixel| *pixel = fade_toward(*pixel, haze.color, amount));
+        }
+
+        if let Some(fog) = self.fog {
+            apply_fog(fog, pixels, width, time);
+        }
+
+        if self.blur_radius > 0 {
+            box_blur(pixels, width, self.blur_radius);
+        }
+    }
+}
+
+/// Moves the color of a pixel toward another color, leaving its coverage unchanged.
+fn fade_toward(pixel: PremultipliedColor, color: [u8; 3], amount: f32) -> PremultipliedColor {
+    let channel = |value: f32, target: u8| value + (target as f32 / 255.0 * pixel.alpha - value) * amount;
+    PremultipliedColor {
+        alpha: pixel.alpha,
+        red: channel(pixel.red, color[0]),
+        green: channel(pixel.green, color[1]),
+        blue: channel(pixel.blue, color[2]),
+    }
+}
+
+/// Fades the pixels of a layer toward the fog color by the density of the fog bands.
+///
+/// The bands are a vertical sine wave whose phase is bent by a slower horizontal wave, which
+/// drifts by `speed` pixels per second so the bands roll across the layer.
+fn apply_fog(fog: FogBands, pixels: &mut [PremultipliedColor], width: usize, time: f32) {
+    let spacing = fog.spacing.max(1.0);
+    let drift = time * fog.speed;
+
+    // The horizontal undulation only depends on the column, so it is computed once per frame
+    let undulation: Vec<f32> = (0..width).map(|x| 1.5 * (2.0 * PI * (x as f32 + drift) / (spacing * 4.0)).sin()).collect();
+
+    pixels.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+        let band_phase = 2.0 * PI * y as f32 / spacing;
+        for (pixel, bend) in row.iter_mut().zip(&undulation) {
+            let wave = ((band_phase + bend).sin() + 1.0) / 2.0;
+            let density = fog.opacity * wave.powi(4);
+            *pixel = fade_toward(*pixel, fog.color, density.clamp(0.0, 1.0));
+        }
+    });
+}
+
+/// Blurs premultiplied pixels with a box filter, horizontally and then vertically.
+///
+/// Samples outside the layer are left out of the average rather than treated as transparent,
+/// so the edges of the window do not fade out.
+fn box_blur(pixels: &mut [PremultipliedColor], width: usize, radius: usize) {
+    if width == 0 {
+        return;
+    }
+    let height = pixels.len() / width;
+    let to_array = |pixel: &PremultipliedColor| [pixel.alpha, pixel.red, pixel.green, pixel.blue];
+
+    // Horizontal pass, each row with a running sum
+    pixels.par_chunks_mut(width).for_each(|row| {
+        let source: Vec<[f32; 4]> = row.iter().map(to_array).collect();
+        let mut sum = [0.0f32; 4];
+        let mut count = 0.0;
+        for sample in source.iter().take(radius) {
+            add(&mut sum, sample, 1.0);
+            count += 1.0;
+        }
+
+        for (x, pixel) in row.iter_mut().enumerate() {
+            if let Some(entering) = source.get(x + radius) {
+                add(&mut sum, entering, 1.0);
+                count += 1.0;
+            }
+            if x > radius {
+                add(&mut sum, &source[x - radius - 1], -1.0);
+                count -= 1.0;
+            }
+            *pixel = average(sum, count);
+        }
+    });
+
+    // Vertical pass, with a running sum over whole rows
+    let source: Vec<[f32; 4]> = pixels.iter().map(to_array).collect();
+    let row = |y: usize| &source[y * width..][..width];
+    let mut sums = vec![[0.0f32; 4]; width];
+    let mut count = 0.0;
+    for y in 0..radius.min(height) {
+        for (sum, sample) in sums.iter_mut().zip(row(y)) {
+            add(sum, sample, 1.0);
+        }
+        count += 1.0;
+    }
+
+    for (y, output) in pixels.chunks_mut(width).enumerate() {
+        if y + radius < height {
+            for (sum, sample) in sums.iter_mut().zip(row(y + radius)) {
+                add(sum, sample, 1.0);
+            }
+            count += 1.0;
+        }
+        if y > radius {
+            for (sum, sample) in sums.iter_mut().zip(row(y - radius - 1)) {
+                add(sum, sample, -1.0);
+            }
+            count -= 1.0;
+        }
+        for (pixel, sum) in output.iter_mut().zip(&sums) {
+            *pixel = average(*sum, count);
+        }
+    }
+}
+
+fn add(sum: &mut [f32; 4], sample: &[f32; 4], sign: f32) {
+    for (total, value) in sum.iter_mut().zip(sample) {
+        *total += value * sign;
+    }
+}
+
+fn average(sum: [f32; 4], count: f32) -> PremultipliedColor {
+    // Running sums can drift slightly below zero after many subtractions
+    let channel = |value: f32| (value / count).max(0.0);
+    PremultipliedColor { alpha: channel(sum[0]), red: channel(sum[1]), green: channel(sum[2]), blue: channel(sum[3]) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opaque(value: u32) -> PremultipliedColor {
+        PremultipliedColor::from_argb(0xFF000000 | (value * 0x010101))
+    }
+
+    #[test]
+    fn test_haze_grows_with_depth_and_blur_spreads_coverage() {
+        let effects = LayerEffects { haze: Some(Haze { color: [255, 255, 255], amount: 0.5 }), ..LayerEffects::default() };
+
+        let mut near = vec![opaque(0)];
+        effects.apply(&mut near, 1, 0.0, 0.0);
+        let mut far = vec![opaque(0)];
+        effects.apply(&mut far, 1, 1.0, 0.0);
+        assert_eq!((near[0].to_argb(), far[0].to_argb()), (0xFF000000, 0xFF808080));
+
+        let mut row = vec![PremultipliedColor::default(), opaque(255), PremultipliedColor::default(), PremultipliedColor::default()];
+        box_blur(&mut row, 4, 1);
+        let alphas: Vec<f32> = row.iter().map(|pixel| (pixel.alpha * 3.0).round()).collect();
+        assert_eq!(alphas, vec![2.0, 1.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn test_fog_only_covers_the_layer_and_drifts_over_time() {
+        let effects = LayerEffects {
+            fog: Some(FogBands { color: [255, 255, 255], opacity: 1.0, spacing: 8.0, speed: 30.0 }),
+            ..LayerEffects::default()
+        };
+        let render = |time: f32| {
+            let mut pixels = vec![opaque(0); 64];
+            pixels[0] = PremultipliedColor::default();
+            effects.apply(&mut pixels, 8, 0.0, time);
+            pixels
+        };
+
+        let first = render(0.0);
+        assert_eq!(first[0], PremultipliedColor::default());
+        assert!(first.iter().any(|pixel| pixel.red > 0.5));
+        assert_ne!(first, render(0.1));
+    }
+}
@@ -2,6 +2,10 @@ use std::collections::HashMap;
 use std::error::Error;
 use std::path::Path;
 use image::{DynamicImage, GenericImageView, Rgb};
+use crate::state::constants::graphics::MAX_PALETTE_SIZE;
+
+/// A flat RGB color map paired with a mapping of packed RGB values to their palette indices.
+pub type PaletteMaps = (Vec<u8>, HashMap<u32, u8>);
 
 /// Represents a color in RGB format.
 /// Each color component (red, green, blue) is stored as an 8-bit unsigned integer.
@@ -45,7 +49,7 @@ impl Color {
     ///
     /// # Returns
     /// A `String` containing the hexadecimal representation of the color.
-    pub fn to_hex(&self) -> String {
+    pub fn to_hex(self) -> String {
         format!("#{:02x}{:02x}{:02x}", self.r, self.g, self.b)
     }
 
@@ -168,6 +172,18 @@ impl PaletteExtractor {
         Ok(palette)
     }
 
+    /// Extracts a color palette from colors that are already in memory, such as rendered frames.
+    ///
+    /// # Arguments
+    /// * `pixels` - The colors to cluster.
+    ///
+    /// # Returns
+    /// A `Result` containing a vector of `Color` instances representing the extracted palette,
+    /// or an error if the extraction fails.
+    pub fn extract_palette_from_colors(&self, pixels: Vec<Color>) -> Result<Vec<Color>, Box<dyn Error>> {
+        self.kmeans_clustering(pixels)
+    }
+
     /// Resizes the image while maintaining its aspect ratio.
     /// This reduces the number of pixels for faster processing.
     ///
@@ -194,7 +210,7 @@ impl PaletteExtractor {
     fn extract_pixels(&self, img: &DynamicImage) -> Vec<Color> {
         let rgb_img = img.to_rgb8(); // Convert image to RGB format
         rgb_img.pixels()
-            .map(|pixel| Color::from_rgb(pixel)) // Map each pixel to a `Color`
+            .map(Color::from_rgb) // Map each pixel to a `Color`
             .collect()
     }
 
@@ -253,14 +269,15 @@ impl PaletteExtractor {
 
             let mut new_centroids = Vec::new();
             for i in 0..self.num_colors {
-                if counts[i] > 0 {
-                    let avg_r = (sums_r[i] / counts[i]) as u8;
-                    let avg_g = (sums_g[i] / counts[i]) as u8;
-                    let avg_b = (sums_b[i] / counts[i]) as u8;
-                    new_centroids.push(Color::new(avg_r, avg_g, avg_b));
-                } else {
+                match counts[i] {
                     // Retain the old centroid if no pixels are assigned
-                    new_centroids.push(centroids[i]);
+                    0 => new_centroids.push(centroids[i]),
+                    count => {
+                        let avg_r = (sums_r[i] / count) as u8;
+                        let avg_g = (sums_g[i] / count) as u8;
+                        let avg_b = (sums_b[i] / count) as u8;
+                        new_centroids.push(Color::new(avg_r, avg_g, avg_b));
+                    }
                 }
             }
 
@@ -285,7 +302,7 @@ impl PaletteExtractor {
             .collect();
 
         // Sort by count (most frequent colors first)
-        indexed_centroids.sort_by(|a, b| b.2.cmp(&a.2));
+        indexed_centroids.sort_by_key(|centroid| std::cmp::Reverse(centroid.2));
 
         // Remove empty clusters and return the final palette
         let result: Vec<Color> = indexed_centroids
@@ -373,8 +390,9 @@ impl PaletteExtractor {
 /// ```
 /// let (color_map, color_to_index_map) = extract_palette("path/to/image.png")?;
 /// ```
-pub fn extract_palette(input_image_path: &str) -> Result<(Vec<u8>, HashMap<u32, u8>), Box<dyn Error>> {
-    let extractor = PaletteExtractor::new(256)
+pub fn extract_palette(input_image_path: &str) -> Result<PaletteMaps, Box<dyn Error>> {
+    // Leave one index free for the transparent pixels of frame deltas
+    let extractor = PaletteExtractor::new(MAX_PALETTE_SIZE - 1)
         .with_resize_width(150)
         .with_max_iterations(50);
 
@@ -384,13 +402,80 @@ pub fn extract_palette(input_image_path: &str) -> Result<(Vec<u8>, HashMap<u32,
         println!("Color {}: {} ({})", i + 1, color, color.to_hex());
     }
 
+    Ok(build_palette_maps(&palette))
+}
+
+/// Extracts a smaller color palette from rendered ARGB pixels using K-means clustering.
+///
+/// Used when a GIF has to fit a size budget and the palette extracted from the source image
+/// is too large. Clustering the rendered frames keeps the colors that actually end up on screen.
+///
+/// # Arguments
+/// * `pixels` - The ARGB pixels of one or more rendered frames.
+/// * `num_colors` - The number of colors to extract.
+///
+/// # Returns
+/// A `Result` containing the color map and the mapping of packed RGB values to palette indices,
+/// or an error if the extraction fails.
+pub fn extract_palette_from_pixels(pixels: &[u32], num_colors: usize) -> Result<PaletteMaps, Box<dyn Error>> {
+    // Sample roughly as many pixels as a 150px wide image would provide
+    let step = (pixels.len() / (150 * 150)).max(1);
+    let samples: Vec<Color> = pixels
+        .iter()
+        .step_by(step)
+        .map(|&pixel| Color::new((pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8))
+        .collect();
+
+    let palette = PaletteExtractor::new(num_colors)
+        .with_max_iterations(50)
+        .extract_palette_from_colors(samples)?;
+    println!("Extracted {} colors from rendered frames using K-means", palette.len());
+
+    Ok(build_palette_maps(&palette))
+}
+
+/// Turns a list of colors into a palette of at most `num_colors` colors.
+///
+/// Duplicates are removed first, and K-means clustering is only used when there are still
+/// more colors than fit.
+///
+/// # Arguments
+/// * `colors` - The colors the palette should cover.
+/// * `num_colors` - The maximum number of colors in the palette.
+///
+/// # Returns
+/// A `Result` containing the color map and the mapping of packed RGB values to palette indices,
+/// or an error if the extraction fails.
+pub fn reduce_palette(colors: Vec<Color>, num_colors: usize) -> Result<PaletteMaps, Box<dyn Error>> {
+    let mut unique = colors.clone();
+    unique.sort_by_key(|color| (color.r, color.g, color.b));
+    unique.dedup();
+
+    if unique.len() <= num_colors {
+        return Ok(build_palette_maps(&unique));
+    }
+
+    let palette = PaletteExtractor::new(num_colors)
+        .with_max_iterations(50)
+        .extract_palette_from_colors(colors)?;
+    println!("Reduced {} colors to {} using K-means", unique.len(), palette.len());
+
+    Ok(build_palette_maps(&palette))
+}
+
+/// Builds the flat color map and the packed RGB to index mapping for a palette.
+///
+/// # Arguments
+/// * `palette` - The palette colors, in index order.
+///
+/// # Returns
+/// The color map and the mapping of packed RGB values to palette indices.
+fn build_palette_maps(palette: &[Color]) -> PaletteMaps {
     let color_map: Vec<u8> = palette.iter().flat_map(|color| vec![color.r, color.g, color.b]).collect();
     let color_to_index_map: HashMap<u32, u8> = palette.iter().enumerate().map(|(i, color)| {
         let packed_color = ((color.r as u32) << 16) | ((color.g as u32) << 8) | (color.b as u32);
-        println!("Mapping color {} (RGB: {}, {}, {}) to index {}", packed_color, color.r, color.g, color.b, i);
         (packed_color, i as u8)
     }).collect();
 
-
-    Ok((color_map, color_to_index_map))
+    (color_map, color_to_index_map)
 }
\ No newline at end of file
@@ -0,0 +1,159 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// How a layer is combined with the layers behind it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BlendMode {
+    /// The layer is drawn over the layers behind it according to its alpha.
+    #[default]
+    Normal,
+    /// The colors are multiplied, which only ever darkens. Suited for shadows and tinted glass.
+    Multiply,
+    /// The inverted colors are multiplied, which only ever lightens. Suited for light shafts and haze.
+    Screen,
+    /// Multiplies dark and screens light parts of the layers behind, raising their contrast.
+    Overlay,
+    /// The colors are added, saturating at white. Suited for glows and fireflies.
+    Additive,
+}
+
+impl BlendMode {
+    /// Blends a single color channel of the source onto the backdrop, both from 0.0 to 1.0.
+    fn blend_channel(self, backdrop: f32, source: f32) -> f32 {
+        match self {
+            BlendMode::Normal | BlendMode::Additive => source,
+            BlendMode::Multiply => source * backdrop,
+            BlendMode::Screen => source + backdrop - source * backdrop,
+            BlendMode::Overlay if backdrop <= 0.5 => 2.0 * source * backdrop,
+            BlendMode::Overlay => 1.0 - 2.0 * (1.0 - source) * (1.0 - backdrop),
+        }
+    }
+}
+
+/// A color with its channels multiplied by its alpha, all from 0.0 to 1.0.
+///
+/// Premultiplied colors can be filtered and mixed without the transparent black around a sprite
+/// bleeding into its edges, which is why resampled sprites are blended in this form.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct PremultipliedColor {
+    pub alpha: f32,
+    pub red: f32,
+    pub green: f32,
+    pub blue: f32,
+}
+
+impl PremultipliedColor {
+    /// Converts a packed ARGB pixel.
+    pub fn from_argb(pixel: u32) -> Self {
+        let alpha = (pixel >> 24) as f32 / 255.0;
+        let channel = |shift: u32| ((pixel >> shift) & 0xFF) as f32 / 255.0 * alpha;
+        Self { alpha, red: channel(16), green: channel(8), blue: channel(0) }
+    }
+
+    /// Converts back to a packed ARGB pixel, dividing the alpha back out of the colors.
+    pub fn to_argb(self) -> u32 {
+        if self.alpha <= 0.0 {
+            return 0;
+        }
+
+        let to_byte = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u32;
+        let channel = |value: f32, shift: u32| to_byte(value / self.alpha) << shift;
+        (to_byte(self.alpha) << 24)
+            | channel(self.red, 16)
+            | channel(self.green, 8)
+            | channel(self.blue, 0)
+    }
+
+    /// Scales every channel, which changes the opacity of the color without changing its hue.
+    pub fn scale(self, factor: f32) -> Self {
+        Self { alpha: self.alpha * factor, red: self.red * factor, green: self.green * factor, blue: self.blue * factor }
+    }
+}
+
+/// Composites a source color onto a backdrop pixel with a blend mode.
+///
+/// Follows the W3C compositing model: where both are opaque the blend mode decides the color,
+/// where only one of them covers the pixel its own color shows through, and the coverage of the
+/// result is the source over the backdrop. Additive blending instead adds the premultiplied colors.
+///
+/// # Arguments
+/// * `backdrop` - The packed ARGB pixel already in the window buffer.
+/// * `source` - The premultiplied color drawn on top, with any layer opacity already applied.
+/// * `mode` - How the colors are combined.
+///
+/// # Returns
+/// The composited pixel in packed ARGB format.
+pub fn composite(backdrop: u32, source: PremultipliedColor, mode: BlendMode) -> u32 {
+    let backdrop = PremultipliedColor::from_argb(backdrop);
+
+    if mode == BlendMode::Additive {
+        return PremultipliedColor {
+            alpha: (source.alpha + backdrop.alpha).min(1.0),
+            red: (source.red + backdrop.red).min(1.0),
+            green: (source.green + backdrop.green).min(1.0),
+            blue: (source.blue + backdrop.blue).min(1.0),
+        }
+        .to_argb();
+    }
+
+    let channel = |source_channel: f32, backdrop_channel: f32| {
+        let both = match (source.alpha > 0.0, backdrop.alpha > 0.0) {
+            (true, true) => source.alpha * backdrop.alpha * mode.blend_channel(backdrop_channel / backdrop.alpha, source_channel / source.alpha),
+            _ => 0.0,
+        };
+        (1.0 - source.alpha) * backdrop_channel + (1.0 - backdrop.alpha) * source_channel + both
+    };
+
+    PremultipliedColor {
+        alpha: source.alpha + backdrop.alpha * (1.0 - source.alpha),
+        red: channel(source.red, backdrop.red),
+        green: channel(source.green, backdrop.green),
+        blue: channel(source.blue, backdrop.blue),
+    }
+    .to_argb()
+}
+
+/// Composites a whole layer rendered on its own onto the window buffer.
+///
+/// # Arguments
+/// * `window_buffer` - The pixels of the window in ARGB format, with the layers behind already drawn.
+/// * `layer` - The premultiplied pixels of the layer, the same size as the window.
+/// * `opacity` - The factor the layer's alpha is multiplied by, from 0.0 to 1.0.
+/// * `mode` - How the layer's colors are combined with the window.
+pub fn composite_layer(window_buffer: &mut [u32], layer: &[PremultipliedColor], opacity: f32, mode: BlendMode) {
+    window_buffer.par_iter_mut().zip(layer).for_each(|(window_pixel, source)| {
+        if source.alpha > 0.0 {
+            *window_pixel = composite(*window_pixel, source.scale(opacity), mode);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GREY: u32 = 0xFF808080;
+
+    fn half_red() -> PremultipliedColor {
+        PremultipliedColor::from_argb(0xFFFF0000).scale(0.5)
+    }
+
+    #[test]
+    fn test_blend_modes_on_an_opaque_backdrop() {
+        let opaque_source = PremultipliedColor::from_argb(0xFF404040);
+
+        assert_eq!(composite(GREY, opaque_source, BlendMode::Normal), 0xFF404040);
+        assert_eq!(composite(GREY, opaque_source, BlendMode::Multiply), 0xFF202020);
+        assert_eq!(composite(GREY, opaque_source, BlendMode::Screen), 0xFFA0A0A0);
+        assert_eq!(composite(0xFF404040, PremultipliedColor::from_argb(GREY), BlendMode::Overlay), 0xFF404040);
+        assert_eq!(composite(GREY, opaque_source, BlendMode::Additive), 0xFFC0C0C0);
+        assert_eq!(composite(GREY, half_red(), BlendMode::Normal), 0xFFC04040);
+    }
+
+    #[test]
+    fn test_transparent_backdrop_keeps_the_source_and_its_alpha() {
+        assert_eq!(composite(0x00000000, half_red(), BlendMode::Multiply), 0x80FF0000);
+        assert_eq!(composite(GREY, PremultipliedColor::default(), BlendMode::Screen), GREY);
+    }
+}
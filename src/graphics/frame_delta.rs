@@ -0,0 +1,156 @@
+/// The part of a frame that differs from the frame before it.
+///
+/// `left`, `top`, `width` and `height` describe the sub-rectangle written to the GIF, and
+/// `buffer` holds its palette indices row by row.
+///
+/// Deltas have no disposal method of their own. Each one is diffed against the whole previous
+/// frame, so every frame is written with `DisposalMethod::Keep` to leave that frame in place for
+/// the next delta to be drawn on.
+pub struct FrameDelta {
+    pub left: u16,
+    pub top: u16,
+    pub width: u16,
+    pub height: u16,
+    pub buffer: Vec<u8>,
+    /// The palette index marking pixels that should show the previous frame, if any were marked.
+    pub transparent: Option<u8>,
+}
+
+impl FrameDelta {
+    /// Wraps a full frame that is not diffed against anything, such as the first frame of a GIF.
+    ///
+    /// # Arguments
+    /// * `buffer` - The palette indices of the whole frame.
+    /// * `width` - The width of the frame in pixels.
+    /// * `height` - The height of the frame in pixels.
+    pub fn full(buffer: Vec<u8>, width: u16, height: u16) -> Self {
+        Self {
+            left: 0,
+            top: 0,
+            width,
+            height,
+            buffer,
+            transparent: None,
+        }
+    }
+}
+
+/// Diffs two consecutive index buffers and extracts the changed sub-rectangle.
+///
+/// Parallax layers scroll at different speeds, so large parts of consecutive frames are
+/// often identical, especially in the slow far layer. Only the bounding rectangle of the
+/// changed pixels is emitted. When the palette has a spare `transparent_index`, unchanged
+/// pixels inside that rectangle are replaced with it so they compress to long runs.
+///
+/// # Arguments
+/// * `previous` - The palette indices of the previous frame.
+/// * `current` - The palette indices of the current frame.
+/// * `width` - The width of both frames in pixels.
+/// * `height` - The height of both frames in pixels.
+/// * `transparent_index` - A palette index no pixel uses, or `None` if the palette is full.
+///
+/// # Returns
+/// A `FrameDelta` covering the changed pixels. An unchanged frame becomes a single
+/// pixel so the frame, and its delay, is still part of the animation.
+pub fn diff_frames(previous: &[u8], current: &[u8], width: u16, height: u16, transparent_index: Option<u8>) -> FrameDelta {
+    let row_len = width as usize;
+
+    let changed_rows: Vec<usize> = (0..height as usize)
+        .filter(|&row| previous[row * row_len..(row + 1) * row_len] != current[row * row_len..(row + 1) * row_len])
+        .collect();
+
+    let (Some(&top), Some(&bottom)) = (changed_rows.first(), changed_rows.last()) else {
+        return FrameDelta {
+            left: 0,
+            top: 0,
+            width: 1,
+            height: 1,
+            buffer: vec![transparent_index.unwrap_or(current[0])],
+            transparent: transparent_index,
+        };
+    };
+
+    // Narrow the rectangle horizontally to the outermost changed columns of the changed rows
+    let mut left = row_len;
+    let mut right = 0;
+    for &row in &changed_rows {
+        let start = row * row_len;
+        let differs = |col: &usize| previous[start + col] != current[start + col];
+        if let Some(first) = (0..row_len).find(differs) {
+            left = left.min(first);
+        }
+        if let Some(last) = (0..row_len).rev().find(differs) {
+            right = right.max(last);
+        }
+    }
+
+    let delta_width = right - left + 1;
+    let delta_height = bottom - top + 1;
+    let mut buffer = Vec::with_capacity(delta_width * delta_height);
+
+    for row in top..=bottom {
+        let start = row * row_len + left;
+        let previous_row = &previous[start..start + delta_width];
+        let current_row = &current[start..start + delta_width];
+
+        match transparent_index {
+            Some(transparent) => buffer.extend(
+                previous_row
+                    .iter()
+                    .zip(current_row)
+                    .map(|(&before, &after)| if before == after { transparent } else { after }),
+            ),
+            None => buffer.extend_from_slice(current_row),
+        }
+    }
+
+    FrameDelta {
+        left: left as u16,
+        top: top as u16,
+        width: delta_width as u16,
+        height: delta_height as u16,
+        buffer,
+        transparent: transparent_index,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta_covers_only_changed_rectangle() {
+        let previous = vec![1u8; 4 * 4];
+        let mut current = previous.clone();
+        current[4 + 1] = 2; // (1, 1)
+        current[2 * 4 + 2] = 3; // (2, 2)
+
+        let delta = diff_frames(&previous, &current, 4, 4, Some(9));
+
+        assert_eq!((delta.left, delta.top, delta.width, delta.height), (1, 1, 2, 2));
+        assert_eq!(delta.buffer, vec![2, 9, 9, 3]);
+        assert_eq!(delta.transparent, Some(9));
+    }
+
+    #[test]
+    fn test_delta_without_spare_index_copies_pixels() {
+        let previous = vec![0u8, 0, 0, 0];
+        let current = vec![0u8, 5, 0, 6];
+
+        let delta = diff_frames(&previous, &current, 2, 2, None);
+
+        assert_eq!((delta.left, delta.top, delta.width, delta.height), (1, 0, 1, 2));
+        assert_eq!(delta.buffer, vec![5, 6]);
+        assert_eq!(delta.transparent, None);
+    }
+
+    #[test]
+    fn test_unchanged_frame_becomes_single_pixel() {
+        let frame = vec![7u8; 9];
+
+        let delta = diff_frames(&frame, &frame, 3, 3, Some(200));
+
+        assert_eq!((delta.width, delta.height), (1, 1));
+        assert_eq!(delta.buffer, vec![200]);
+    }
+}
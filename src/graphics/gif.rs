@@ -1,10 +1,49 @@
-use gif::{Encoder, Frame, Repeat};
+use gif::{DisposalMethod, Encoder, Frame, Repeat};
+use rayon::prelude::*;
 use std::borrow::Cow;
-use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::process::exit;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Write;
+use std::sync::RwLock;
 use timing_macro::timed;
-use crate::state::constants::graphics::{WINDOW_HEIGHT, WINDOW_WIDTH};
+use crate::graphics::frame_delta::FrameDelta;
+use crate::state::constants::graphics::{MAX_PALETTE_SIZE, ROWS_PER_CHUNK};
+
+/// Cache of pixel values to their nearest palette index, shared by every encoding worker.
+///
+/// It is kept apart from the palette's own color-to-index table so that approximated
+/// pixels never end up looking like palette entries.
+pub type PixelIndexCache = RwLock<HashMap<u32, u8>>;
+
+/// 4x4 Bayer threshold matrix used for ordered dithering.
+const BAYER_MATRIX: [[u8; 4]; 4] = [
+    [0, 8, 2, 10],
+    [12, 4, 14, 6],
+    [3, 11, 1, 9],
+    [15, 7, 13, 5],
+];
+
+/// The dimensions and quantization options of the frames written to a GIF.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameFormat {
+    /// The width of each frame in pixels.
+    pub width: u16,
+    /// The height of each frame in pixels.
+    pub height: u16,
+    /// Whether ordered dithering is applied while mapping pixels to the palette.
+    pub dither: bool,
+}
+
+impl FrameFormat {
+    /// Creates a new `FrameFormat` without dithering.
+    ///
+    /// # Arguments
+    /// * `width` - The width of each frame in pixels.
+    /// * `height` - The height of each frame in pixels.
+    pub fn new(width: u16, height: u16) -> Self {
+        Self { width, height, dither: false }
+    }
+}
 
 /// Initializes a GIF encoder with the specified image file, width, and height.
 ///
@@ -13,60 +52,199 @@ use crate::state::constants::graphics::{WINDOW_HEIGHT, WINDOW_WIDTH};
 /// infinitely.
 ///
 /// # Arguments
-/// * `image` - A mutable reference to the file where the GIF will be written.
+/// * `image` - The writer (typically a file) where the GIF will be written.
 /// * `width` - The width of the GIF in pixels.
 /// * `height` - The height of the GIF in pixels.
 ///
 /// # Returns
 /// An `Encoder` instance configured for the GIF file.
-pub fn initialize_gif_encoder(image: &mut File, width: u16, height: u16) -> Encoder<&mut File> {
+pub fn initialize_gif_encoder<W: Write>(image: W, width: u16, height: u16) -> Encoder<W> {
     let color_map = &[];
     let mut encoder = Encoder::new(image, width, height, color_map).unwrap();
     encoder.set_repeat(Repeat::Infinite).unwrap();
     encoder
 }
 
-/// Processes a single frame for the GIF encoder.
+/// The palette a GIF is quantized against.
 ///
-/// When the number of colors in the image exceeds 256, we use Euclidean color
-/// distance to map each pixel to the nearest color in the palette. This is
-/// necessary because GIFs have a hard limit of 256 colors in their palette.
-///
-/// # Arguments
-/// * `scaled_buffer` - A mutable reference to the pixel buffer of the image.
-/// * `encoder` - The GIF encoder instance.
-/// * `width` - The width of the frame in pixels.
-/// * `height` - The height of the frame in pixels.
-/// * `frame_count` - A mutable reference to the current frame count.
-/// * `color_map` - The palette of colors used in the GIF.
-/// * `map` - A mutable hash map for mapping pixel values to palette indices.
-#[timed]
-pub fn process_frame(
-    window_buffer: &mut Vec<u32>,
-    encoder: &mut Encoder<&mut File>,
-    frame_count: &mut usize,
-    color_map: &Option<Vec<u8>>,
-    map: &mut Option<HashMap<u32, u8>>,
-) {
-    *frame_count += 1;
+/// Built once per recording and shared read-only by the encoding workers, which is why
+/// the palette is validated up front instead of per frame.
+pub struct GifPalette {
+    /// Flat RGB bytes as written to each frame's local color table.
+    color_map: Vec<u8>,
+    /// The palette colors as RGB tuples, used for nearest-color searches.
+    colors: Vec<(u8, u8, u8)>,
+    /// The palette's own table of packed RGB colors to palette indices.
+    index_map: HashMap<u32, u8>,
+    /// A spare index appended after the palette colors to mark unchanged pixels, unless the palette is full.
+    transparent_index: Option<u8>,
+}
+
+impl GifPalette {
+    /// Creates a new `GifPalette` from the color map and index table produced by `extract_palette`.
+    ///
+    /// # Arguments
+    /// * `color_map` - The flat RGB color map, if one was extracted.
+    /// * `index_map` - The mapping of packed RGB values to palette indices, if one was extracted.
+    ///
+    /// # Returns
+    /// A `GifPalette`, or an error if the palette is missing, empty or holds more colors than a GIF palette can index.
+    pub fn new(color_map: Option<&[u8]>, index_map: Option<&HashMap<u32, u8>>) -> Result<Self, Box<dyn Error>> {
+        let (color_map, index_map) = match (color_map, index_map) {
+            (Some(color_map), Some(index_map)) => (color_map, index_map),
+            _ => return Err("No palette available to quantize frames against.".into()),
+        };
 
-    let palette: Vec<(u8, u8, u8)> = if let Some(color_map) = color_map {
-        color_map
+        let colors: Vec<(u8, u8, u8)> = color_map
             .chunks(3)
             .map(|chunk| (chunk[0], chunk[1], chunk[2]))
-            .collect()
-    } else {
-        vec![]
-    };
+            .collect();
+
+        if colors.is_empty() {
+            return Err("Cannot map pixels to an empty palette.".into());
+        }
+
+        if colors.len() > MAX_PALETTE_SIZE {
+            return Err(format!("Palette holds {} colors, but a GIF palette is limited to {}.", colors.len(), MAX_PALETTE_SIZE).into());
+        }
+
+        // Reserve the first unused index for transparency, padded with black in the color table
+        let mut color_map = color_map.to_vec();
+        let transparent_index = if colors.len() < MAX_PALETTE_SIZE {
+            color_map.extend_from_slice(&[0, 0, 0]);
+            Some(colors.len() as u8)
+        } else {
+            None
+        };
 
+        Ok(Self {
+            color_map,
+            colors,
+            index_map: index_map.clone(),
+            transparent_index,
+        })
+    }
+
+    /// The number of colors in the palette, excluding the reserved transparent index.
+    pub fn color_count(&self) -> usize {
+        self.colors.len()
+    }
+
+    /// The palette index reserved for transparent pixels, if the palette had room for one.
+    pub fn transparent_index(&self) -> Option<u8> {
+        self.transparent_index
+    }
+
+    /// Finds the index of the palette color closest to the given packed RGB value.
+    fn nearest_index(&self, packed_rgb: u32) -> u8 {
+        let pixel_rgb = (
+            ((packed_rgb >> 16) & 0xFF) as u8,
+            ((packed_rgb >> 8) & 0xFF) as u8,
+            (packed_rgb & 0xFF) as u8,
+        );
+
+        self.colors
+            .iter()
+            .enumerate()
+            .min_by(|(_, &color_a), (_, &color_b)| {
+                let dist_a = color_distance(pixel_rgb, color_a);
+                let dist_b = color_distance(pixel_rgb, color_b);
+                dist_a.partial_cmp(&dist_b).unwrap_or(std::cmp::Ordering::Equal)
+            })
+            .map(|(index, _)| index as u8)
+            .unwrap_or(0) // Default to the first color in the palette if no unique closest color is found
+    }
+
+    /// Offsets a packed RGB value by the Bayer threshold for its position.
+    ///
+    /// The spread shrinks as the palette grows, so dithering only ever nudges a pixel about
+    /// half the typical distance between neighbouring palette colors.
+    fn dither(&self, packed_rgb: u32, x: usize, y: usize) -> u32 {
+        let spread = 256.0 / (self.colors.len() as f32).cbrt();
+        let offset = ((BAYER_MATRIX[y % 4][x % 4] as f32 + 0.5) / 16.0 - 0.5) * spread;
+
+        let channel = |shift: u32| {
+            let value = ((packed_rgb >> shift) & 0xFF) as f32 + offset;
+            (value.round().clamp(0.0, 255.0) as u32) << shift
+        };
+
+        channel(16) | channel(8) | channel(0)
+    }
+}
+
+/// Quantizes a single composited frame into palette indices.
+///
+/// The palette mapping is split into chunks of `ROWS_PER_CHUNK` rows which are mapped in parallel.
+///
+/// # Arguments
+/// * `pixels` - The pixel buffer of the composited frame.
+/// * `format` - The format of the frame, providing its width and whether to dither.
+/// * `palette` - The palette the frame is quantized against.
+/// * `pixel_index_cache` - The cache of pixel values to palette indices, shared across frames.
+///
+/// # Returns
+/// One palette index per pixel.
+pub fn quantize_frame(pixels: &[u32], format: FrameFormat, palette: &GifPalette, pixel_index_cache: &PixelIndexCache) -> Vec<u8> {
+    let chunk_len = format.width as usize * ROWS_PER_CHUNK;
+    let mut indices = vec![0u8; pixels.len()];
+
+    indices
+        .par_chunks_mut(chunk_len)
+        .zip(pixels.par_chunks(chunk_len))
+        .enumerate()
+        .for_each(|(chunk, (index_chunk, pixel_chunk))| {
+            map_pixels_to_indices(pixel_chunk, index_chunk, chunk * ROWS_PER_CHUNK, format, palette, pixel_index_cache);
+        });
+
+    indices
+}
 
-    let buffer = if let Some(ref mut map) = map {
-        map_pixels_to_indices(window_buffer, map, &palette)
-    } else {
-        vec![]
+/// LZW-compresses a frame delta so that writing it to the GIF is a plain copy.
+///
+/// # Arguments
+/// * `delta` - The sub-rectangle of the frame to encode.
+/// * `palette` - The palette the frame was quantized against.
+/// * `delay` - How long the frame is shown, in hundredths of a second.
+///
+/// # Returns
+/// A pre-encoded `Frame` ready for `write_frame_to_gif`.
+pub fn encode_frame(delta: FrameDelta, palette: &GifPalette, delay: u16) -> Frame<'static> {
+    let mut frame = Frame {
+        left: delta.left,
+        top: delta.top,
+        width: delta.width,
+        height: delta.height,
+        palette: Some(palette.color_map.clone()),
+        transparent: delta.transparent,
+        // Every delta is drawn on top of the whole previous frame, which has to stay in place
+        dispose: DisposalMethod::Keep,
+        buffer: Cow::Owned(delta.buffer),
+        delay,
+        ..Frame::default()
     };
+    frame.make_lzw_pre_encoded();
+    frame
+}
 
-    write_frame_to_gif(encoder, WINDOW_WIDTH as u16, WINDOW_HEIGHT as u16, color_map.as_deref().unwrap_or(&[]), &buffer, *frame_count);
+/// Encodes each candidate delta of a frame and keeps the one that compresses best.
+///
+/// Marking unchanged pixels as transparent pays off when they form large areas, but when
+/// most of a frame scrolls, scattered transparent pixels break up the LZW runs and the
+/// opaque crop ends up smaller, so both are tried.
+///
+/// # Arguments
+/// * `candidates` - The alternative deltas describing the same frame.
+/// * `palette` - The palette the frame was quantized against.
+/// * `delay` - How long the frame is shown, in hundredths of a second.
+///
+/// # Returns
+/// The smallest pre-encoded `Frame`.
+pub fn encode_smallest_frame(candidates: Vec<FrameDelta>, palette: &GifPalette, delay: u16) -> Frame<'static> {
+    candidates
+        .into_iter()
+        .map(|delta| encode_frame(delta, palette, delay))
+        .min_by_key(|frame| frame.buffer.len())
+        .expect("At least one candidate delta is required")
 }
 
 /// Maps pixel values to their nearest palette indices using Euclidean color distance.
@@ -75,53 +253,53 @@ pub fn process_frame(
 /// to approximate each pixel's color by finding the closest match in the palette.
 /// Euclidean color distance is used to measure the similarity between colors.
 ///
-/// # Arguments
-/// * `buffer` - A slice of pixel values.
-/// * `color_to_index_map` - A mutable hash map for caching pixel-to-index mappings.
-/// * `palette` - A slice of RGB tuples representing the palette.
+/// Pixels whose color is part of the palette are resolved through the palette's own table.
+/// Every other pixel is looked up in the shared `pixel_index_cache` under a single read lock;
+/// misses are resolved locally and merged back under a single write lock, so parallel chunks
+/// only contend on the cache twice each.
 ///
-/// # Returns
-/// A vector of indices corresponding to the palette colors.
-#[timed]
-fn map_pixels_to_indices(buffer: &[u32], color_to_index_map: &mut HashMap<u32, u8>, palette: &[(u8, u8, u8)]) -> Vec<u8> {
-    let mut logged_pixels = HashSet::new();
-    let next_index = color_to_index_map.len() as u8;
-
-    let mut color_to_index = |pixel: u32| {
-        logged_pixels.insert(pixel);
-
-        let index = *color_to_index_map.entry(pixel).or_insert_with(|| {
-            if next_index == u8::MAX {
-                eprintln!("Error: No color index available for pixel {}. Exiting to prevent overflow.", pixel);
-                exit(1); // GitHub Actions will detect this as a failure
-            }
-
-            let pixel_rgb = (
-                ((pixel >> 16) & 0xFF) as u8,
-                ((pixel >> 8) & 0xFF) as u8,
-                (pixel & 0xFF) as u8,
-            );
-
-            let closest_color_index = palette
-                .iter()
-                .enumerate()
-                .min_by(|(_, &color_a), (_, &color_b)| {
-                    let dist_a = color_distance(pixel_rgb, color_a);
-                    let dist_b = color_distance(pixel_rgb, color_b);
-                    dist_a.partial_cmp(&dist_b).unwrap_or(std::cmp::Ordering::Equal)
-                })
-                .map(|(index, _)| index as u8)
-                .unwrap_or(0); // Default to the first color in the palette if no unique closest color is found
-
-            // println!("Mapping pixel {} to color index {}", pixel, closest_color_index);
-
-            closest_color_index
-        });
+/// With dithering enabled each pixel is first offset by its ordered-dither threshold, and the
+/// offset color is what gets looked up and cached.
+///
+/// # Arguments
+/// * `pixels` - A slice of pixel values covering whole rows.
+/// * `indices` - The output slice receiving one palette index per pixel.
+/// * `first_row` - The frame row the slice starts at, used to position the dither pattern.
+/// * `format` - The format of the frame, providing its width and whether to dither.
+/// * `palette` - The palette to map against.
+/// * `pixel_index_cache` - The cache of pixel-to-index mappings shared across chunks and frames.
+fn map_pixels_to_indices(
+    pixels: &[u32],
+    indices: &mut [u8],
+    first_row: usize,
+    format: FrameFormat,
+    palette: &GifPalette,
+    pixel_index_cache: &PixelIndexCache,
+) {
+    let row_len = format.width as usize;
+    let mut misses: HashMap<u32, u8> = HashMap::new();
 
-        index
-    };
+    {
+        let cache = pixel_index_cache.read().unwrap_or_else(|poisoned| poisoned.into_inner());
 
-    buffer.iter().map(|&pixel| color_to_index(pixel)).collect()
+        for (offset, (index, &pixel)) in indices.iter_mut().zip(pixels).enumerate() {
+            // The window buffer is always opaque, so only the RGB channels identify a color
+            let packed_rgb = match format.dither {
+                true => palette.dither(pixel & 0x00FFFFFF, offset % row_len, first_row + offset / row_len),
+                false => pixel & 0x00FFFFFF,
+            };
+
+            *index = match palette.index_map.get(&packed_rgb).or_else(|| cache.get(&packed_rgb)) {
+                Some(&cached) => cached,
+                None => *misses.entry(packed_rgb).or_insert_with(|| palette.nearest_index(packed_rgb)),
+            };
+        }
+    }
+
+    if !misses.is_empty() {
+        let mut cache = pixel_index_cache.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        cache.extend(misses);
+    }
 }
 
 /// Calculates the Euclidean distance between two colors.
@@ -139,41 +317,88 @@ fn color_distance(color1: (u8, u8, u8), color2: (u8, u8, u8)) -> f64 {
     let (r1, g1, b1) = color1;
     let (r2, g2, b2) = color2;
 
-    let dr = ((r1 as i32 - r2 as i32).pow(2)) as i32;
-    let dg = ((g1 as i32 - g2 as i32).pow(2)) as i32;
-    let db = ((b1 as i32 - b2 as i32).pow(2)) as i32;
+    let dr = (r1 as i32 - r2 as i32).pow(2);
+    let dg = (g1 as i32 - g2 as i32).pow(2);
+    let db = (b1 as i32 - b2 as i32).pow(2);
 
     ((dr + dg + db) as f64).sqrt()
 }
 
-/// Writes a single frame to the GIF file.
-///
-/// This function takes the pixel buffer and palette, and writes the frame to
-/// the GIF encoder. The frame is configured with a delay to control playback speed.
+/// Writes a single pre-encoded frame to the GIF file.
 ///
 /// # Arguments
 /// * `encoder` - The GIF encoder instance.
-/// * `width` - The width of the frame in pixels.
-/// * `height` - The height of the frame in pixels.
-/// * `color_map` - The palette of colors used in the GIF.
-/// * `buffer` - The pixel buffer containing palette indices.
+/// * `frame` - The frame produced by `encode_frame`.
 /// * `frame_count` - The current frame count.
 #[timed]
-fn write_frame_to_gif(
-    encoder: &mut Encoder<&mut File>,
-    width: u16,
-    height: u16,
-    color_map: &[u8],
-    buffer: &[u8],
+pub fn write_frame_to_gif<W: Write>(
+    encoder: &mut Encoder<W>,
+    frame: &Frame<'_>,
     frame_count: usize,
-) {
-    let mut frame = Frame::default();
-    frame.width = width;
-    frame.height = height;
-    frame.palette = Some(color_map.to_vec());
-    frame.buffer = Cow::Borrowed(buffer);
-    frame.delay = 10;
-
-    encoder.write_frame(&frame).expect("Failed to write frame to GIF");
+) -> Result<(), gif::EncodingError> {
+    encoder.write_lzw_pre_encoded_frame(frame)?;
     println!("Frame {} written to GIF file.", frame_count);
-}
\ No newline at end of file
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grayscale_palette(size: usize) -> GifPalette {
+        let color_map: Vec<u8> = (0..size).flat_map(|i| [i as u8; 3]).collect();
+        let index_map: HashMap<u32, u8> = (0..size)
+            .map(|i| (((i as u32) << 16) | ((i as u32) << 8) | i as u32, i as u8))
+            .collect();
+        GifPalette::new(Some(&color_map), Some(&index_map)).unwrap()
+    }
+
+    #[test]
+    fn test_full_palette_maps_every_index() {
+        let palette = grayscale_palette(256);
+        let cache = PixelIndexCache::default();
+        let pixels: Vec<u32> = (0..256u32).map(|i| 0xFF000000 | (i << 16) | (i << 8) | i).collect();
+        let mut indices = vec![0u8; pixels.len()];
+
+        map_pixels_to_indices(&pixels, &mut indices, 0, FrameFormat::new(pixels.len() as u16, 1), &palette, &cache);
+
+        assert_eq!(indices, (0..=255u8).collect::<Vec<_>>());
+        assert!(cache.read().unwrap().is_empty(), "palette colors should not be copied into the pixel cache");
+    }
+
+    #[test]
+    fn test_unknown_pixels_are_cached_separately() {
+        let palette = grayscale_palette(255);
+        let cache = PixelIndexCache::default();
+        let pixels = [0xFFFF0000, 0xFFFF0000, 0xFF0A0A0B];
+        let mut indices = [0u8; 3];
+
+        map_pixels_to_indices(&pixels, &mut indices, 0, FrameFormat::new(pixels.len() as u16, 1), &palette, &cache);
+
+        assert_eq!(indices[0], indices[1]);
+        assert_eq!(indices[2], 10);
+        assert_eq!(cache.read().unwrap().len(), 2);
+        assert_eq!(palette.index_map.len(), 255);
+    }
+
+    #[test]
+    fn test_transparent_index_is_reserved_only_when_palette_has_room() {
+        let partial = grayscale_palette(255);
+        let full = grayscale_palette(256);
+
+        assert_eq!(partial.transparent_index(), Some(255));
+        assert_eq!(partial.color_map.len(), 256 * 3);
+        assert_eq!(full.transparent_index(), None);
+        assert_eq!(full.color_map.len(), 256 * 3);
+    }
+
+    #[test]
+    fn test_oversized_missing_and_empty_palettes_are_rejected() {
+        let oversized: Vec<u8> = vec![0; (MAX_PALETTE_SIZE + 1) * 3];
+        let index_map = HashMap::new();
+
+        assert!(GifPalette::new(Some(&oversized), Some(&index_map)).is_err());
+        assert!(GifPalette::new(Some(&[]), Some(&index_map)).is_err());
+        assert!(GifPalette::new(None, None).is_err());
+    }
+}
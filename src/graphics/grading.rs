@@ -0,0 +1,385 @@
+use crate::graphics::color::{reduce_palette, Color, PaletteMaps};
+use crate::state::constants::graphics::{MAX_LUT_SIZE, MAX_PALETTE_SIZE};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// A 3D lookup table mapping input colors to graded colors, as stored in `.cube` files.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lut3d {
+    /// The number of entries along each axis.
+    size: usize,
+    /// The output colors, with red changing fastest and blue slowest.
+    table: Vec<[f32; 3]>,
+    /// The input value mapped to the first entry of each axis.
+    domain_min: [f32; 3],
+    /// The input value mapped to the last entry of each axis.
+    domain_max: [f32; 3],
+}
+
+impl Lut3d {
+    /// Loads a 3D LUT from a `.cube` file.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the `.cube` file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parses the contents of a `.cube` file, which lists `LUT_3D_SIZE` cubed RGB triples.
+    ///
+    /// # Returns
+    /// The `Lut3d`, or an error if the size is missing or does not match the number of entries.
+    pub fn parse(contents: &str) -> Result<Self, Box<dyn Error>> {
+        let mut size = None;
+        let (mut domain_min, mut domain_max) = ([0.0; 3], [1.0; 3]);
+        let mut table = Vec::new();
+
+        let triple = |values: &[&str]| -> Result<[f32; 3], Box<dyn Error>> {
+            match values {
+                [r, g, b] => Ok([r.parse()?, g.parse()?, b.parse()?]),
+                _ => Err(format!("Expected three values in a LUT line, got {}.", values.len()).into()),
+            }
+        };
+
+        for line in contents.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[0] {
+                "TITLE" | "LUT_1D_INPUT_RANGE" | "LUT_3D_INPUT_RANGE" => {}
+                "LUT_1D_SIZE" => return Err("1D LUTs are not supported, expected LUT_3D_SIZE.".into()),
+                "LUT_3D_SIZE" => size = Some(fields.get(1).ok_or("LUT_3D_SIZE has no value.")?.parse::<usize>()?),
+                "DOMAIN_MIN" => domain_min = triple(&fields[1..])?,
+                "DOMAIN_MAX" => domain_max = triple(&fields[1..])?,
+                _ => table.push(triple(&fields)?),
+            }
+        }
+
+        let size = size.ok_or("The LUT has no LUT_3D_SIZE.")?;
+        let entries = size
+            .checked_pow(3)
+            .filter(|_| (2..=MAX_LUT_SIZE).contains(&size))
+            .ok_or_else(|| format!("LUT_3D_SIZE must be between 2 and {}, got {}.", MAX_LUT_SIZE, size))?;
+        if table.len() != entries {
+            return Err(format!("A LUT of size {} needs {} entries, got {}.", size, entries, table.len()).into());
+        }
+
+        Ok(Self { size, table, domain_min, domain_max })
+    }
+
+    /// Looks up a color with trilinear interpolation between the surrounding entries.
+    ///
+    /// # Arguments
+    /// * `rgb` - The input color, from 0.0 to 1.0 per channel.
+    pub fn apply(&self, rgb: [f32; 3]) -> [f32; 3] {
+        let last = (self.size - 1) as f32;
+        let mut lower = [0usize; 3];
+        let mut weight = [0.0f32; 3];
+        for channel in 0..3 {
+            let range = (self.domain_max[channel] - self.domain_min[channel]).max(f32::EPSILON);
+            let position = ((rgb[channel] - self.domain_min[channel]) / range).clamp(0.0, 1.0) * last;
+            lower[channel] = (position.floor() as usize).min(self.size - 2);
+            weight[channel] = position - lower[channel] as f32;
+        }
+
+        let entry = |r: usize, g: usize, b: usize| self.table[r + g * self.size + b * self.size * self.size];
+        let mut result = [0.0f32; 3];
+        for corner in 0..8 {
+            let (dr, dg, db) = (corner & 1, (corner >> 1) & 1, (corner >> 2) & 1);
+            let corner_weight = [dr, dg, db]
+                .iter()
+                .zip(weight)
+                .map(|(&step, weight)| if step == 1 { weight } else { 1.0 - weight })
+                .product::<f32>();
+            let value = entry(lower[0] + dr, lower[1] + dg, lower[2] + db);
+            for channel in 0..3 {
+                result[channel] += value[channel] * corner_weight;
+            }
+        }
+        result
+    }
+}
+
+/// A color grade made of parametric adjustments and an optional LUT.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ColorGrade {
+    /// The factor all channels are multiplied by, where values below 1.0 darken the frame.
+    #[serde(default = "neutral")]
+    pub exposure: f32,
+    /// The factor each RGB channel is multiplied by, for warm sunsets or cold nights.
+    #[serde(default = "neutral_gain")]
+    pub gain: [f32; 3],
+    /// How far colors are pushed away from mid grey, where 1.0 leaves them unchanged.
+    #[serde(default = "neutral")]
+    pub contrast: f32,
+    /// How far colors are pushed away from their luma, where 0.0 is greyscale.
+    #[serde(default = "neutral")]
+    pub saturation: f32,
+    /// The path of a `.cube` LUT applied after the parametric adjustments.
+    #[serde(default)]
+    pub lut: Option<String>,
+    /// How much of the LUT's result is mixed in, from 0.0 to 1.0.
+    #[serde(default = "neutral")]
+    pub lut_strength: f32,
+}
+
+fn neutral() -> f32 {
+    1.0
+}
+
+fn neutral_gain() -> [f32; 3] {
+    [1.0; 3]
+}
+
+impl Default for ColorGrade {
+    fn default() -> Self {
+        Self { exposure: 1.0, gain: neutral_gain(), contrast: 1.0, saturation: 1.0, lut: None, lut_strength: 1.0 }
+    }
+}
+
+impl ColorGrade {
+    /// Grades a color.
+    ///
+    /// # Arguments
+    /// * `rgb` - The color to grade, from 0.0 to 1.0 per channel.
+    /// * `lut` - The loaded LUT of this grade, if it has one.
+    fn apply(&self, rgb: [f32; 3], lut: Option<&Lut3d>) -> [f32; 3] {
+        let mut graded = [0.0f32; 3];
+        for channel in 0..3 {
+            let exposed = rgb[channel] * self.exposure * self.gain[channel];
+            graded[channel] = (exposed - 0.5) * self.contrast + 0.5;
+        }
+
+        let luma = 0.2126 * graded[0] + 0.7152 * graded[1] + 0.0722 * graded[2];
+        let graded = graded.map(|value| (luma + (value - luma) * self.saturation).clamp(0.0, 1.0));
+
+        match lut {
+            Some(lut) => {
+                let looked_up = lut.apply(graded);
+                let strength = self.lut_strength.clamp(0.0, 1.0);
+                [0, 1, 2].map(|channel| (graded[channel] + (looked_up[channel] - graded[channel]) * strength).clamp(0.0, 1.0))
+            }
+            None => graded,
+        }
+    }
+}
+
+/// A color grade reached at a time.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GradeKeyframe {
+    /// The time on the timeline the grade is reached at, in seconds.
+    pub time: f32,
+    /// The grade at that time.
+    #[serde(flatten)]
+    pub grade: ColorGrade,
+}
+
+/// A short burst of light, such as lightning, that fades out over a fraction of a second.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Flash {
+    /// The time on the timeline the flash is brightest at, in seconds.
+    pub time: f32,
+    /// How far colors are pushed toward the flash color at the start, from 0.0 to 1.0.
+    pub intensity: f32,
+    /// How long the flash takes to fade out, in seconds.
+    #[serde(default = "default_flash_duration")]
+    pub duration: f32,
+    /// The RGB color of the light.
+    #[serde(default = "default_flash_color")]
+    pub color: [u8; 3],
+}
+
+fn default_flash_duration() -> f32 {
+    0.3
+}
+
+fn default_flash_color() -> [u8; 3] {
+    [235, 240, 255]
+}
+
+impl Flash {
+    /// How strong the flash is at a time, fading linearly to nothing over its duration.
+    fn strength_at(&self, time: f32) -> f32 {
+        let elapsed = time - self.time;
+        match elapsed >= 0.0 && elapsed < self.duration {
+            true => self.intensity * (1.0 - elapsed / self.duration),
+            false => 0.0,
+        }
+    }
+}
+
+/// Color grading that changes over the timeline of an animation, such as a sunset or a storm.
+///
+/// Keyframes and flashes are placed in seconds, so the grade changes at the same pace at any frame rate.
+///
+/// Between two keyframes both grades are applied and their results mixed, so LUTs cross-fade
+/// as smoothly as the parametric adjustments. Flashes are added on top of the grade.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ColorTimeline {
+    /// The grades, sorted by time. The first is held before it and the last after it.
+    #[serde(default)]
+    pub keyframes: Vec<GradeKeyframe>,
+    /// The flashes, in any order.
+    #[serde(default)]
+    pub flashes: Vec<Flash>,
+    /// The LUT of each keyframe, loaded once.
+    #[serde(skip)]
+    luts: Vec<Option<Lut3d>>,
+}
+
+impl ColorTimeline {
+    /// Creates a color timeline, sorting the keyframes and loading their LUTs.
+    ///
+    /// # Returns
+    /// The `ColorTimeline`, or an error if a time is invalid, two keyframes share a time or a LUT cannot be loaded.
+    pub fn new(mut keyframes: Vec<GradeKeyframe>, flashes: Vec<Flash>) -> Result<Self, Box<dyn Error>> {
+        keyframes.sort_by(|first, second| first.time.total_cmp(&second.time));
+        let times = keyframes.iter().map(|keyframe| keyframe.time).chain(flashes.iter().map(|flash| flash.time));
+        if let Some(time) = times.into_iter().find(|time| *time < 0.0 || !time.is_finite()) {
+            return Err(format!("Invalid grading time {}, expected seconds from the start.", time).into());
+        }
+        if let Some(flash) = flashes.iter().find(|flash| flash.duration <= 0.0 || !flash.duration.is_finite()) {
+            return Err(format!("Invalid flash duration {}, expected a positive number of seconds.", flash.duration).into());
+        }
+        if let Some(pair) = keyframes.windows(2).find(|pair| pair[0].time == pair[1].time) {
+            return Err(format!("Two grade keyframes share the time {} seconds.", pair[0].time).into());
+        }
+
+        let luts = keyframes
+            .iter()
+            .map(|keyframe| keyframe.grade.lut.as_ref().map(|path| Lut3d::load(path).map_err(|e| format!("Cannot load LUT '{}': {}", path, e))).transpose())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self { keyframes, flashes, luts })
+    }
+
+    /// Loads a color timeline from a JSON file with `keyframes` and `flashes` arrays.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the JSON file.
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let ColorTimeline { keyframes, flashes, .. } = serde_json::from_str(&fs::read_to_string(path)?)?;
+        Self::new(keyframes, flashes)
+    }
+
+    /// Grades a single color as it looks at a time.
+    ///
+    /// # Arguments
+    /// * `rgb` - The color to grade, from 0.0 to 1.0 per channel.
+    /// * `time` - The time on the timeline to grade the color for, in seconds.
+    fn grade_at(&self, rgb: [f32; 3], time: f32) -> [f32; 3] {
+        let next = self.keyframes.partition_point(|keyframe| keyframe.time < time);
+        let graded_by = |index: usize| self.keyframes[index].grade.apply(rgb, self.luts[index].as_ref());
+
+        let mut graded = match (next.checked_sub(1), self.keyframes.get(next)) {
+            (_, None) if self.keyframes.is_empty() => rgb,
+            (_, None) => graded_by(self.keyframes.len() - 1),
+            (None, Some(_)) => graded_by(next),
+            (Some(_), Some(to)) if to.time == time => graded_by(next),
+            (Some(previous), Some(to)) => {
+                let from = &self.keyframes[previous];
+                let t = (time - from.time) / (to.time - from.time);
+                let (start, end) = (graded_by(previous), graded_by(next));
+                [0, 1, 2].map(|channel| start[channel] + (end[channel] - start[channel]) * t)
+            }
+        };
+
+        for flash in &self.flashes {
+            let strength = flash.strength_at(time);
+            if strength > 0.0 {
+                for (value, light) in graded.iter_mut().zip(flash.color) {
+                    *value += (light as f32 / 255.0 - *value) * strength.min(1.0);
+                }
+            }
+        }
+        graded
+    }
+
+    /// Grades a composited frame in place.
+    ///
+    /// # Arguments
+    /// * `pixels` - The ARGB pixels of the frame.
+    /// * `time` - The time of the frame on the timeline, in seconds.
+    pub fn apply(&self, pixels: &mut [u32], time: f32) {
+        pixels.par_iter_mut().for_each(|pixel| {
+            let rgb = [16, 8, 0].map(|shift| ((*pixel >> shift) & 0xFF) as f32 / 255.0);
+            let graded = self.grade_at(rgb, time).map(|value| (value.clamp(0.0, 1.0) * 255.0).round() as u32);
+            *pixel = (*pixel & 0xFF000000) | (graded[0] << 16) | (graded[1] << 8) | graded[2];
+        });
+    }
+
+    /// Grades a palette the way the frames will be graded, so quantizing graded frames keeps their colors.
+    ///
+    /// The palette colors are graded at every keyframe and at the peak of every flash, and the
+    /// results are reduced back to a GIF palette with K-means if there are too many of them.
+    ///
+    /// # Arguments
+    /// * `color_map` - The flat RGB color map extracted from the source image.
+    ///
+    /// # Returns
+    /// The graded color map and its mapping of packed RGB values to palette indices.
+    pub fn grade_palette(&self, color_map: &[u8]) -> Result<PaletteMaps, Box<dyn Error>> {
+        let times = self.keyframes.iter().map(|keyframe| keyframe.time).chain(self.flashes.iter().map(|flash| flash.time));
+        let mut times: Vec<f32> = times.collect();
+        if times.is_empty() {
+            times.push(0.0);
+        }
+
+        let colors: Vec<Color> = times
+            .iter()
+            .flat_map(|&time| {
+                color_map.chunks(3).map(move |rgb| {
+                    let graded = self.grade_at([rgb[0], rgb[1], rgb[2]].map(|value| value as f32 / 255.0), time);
+                    let [r, g, b] = graded.map(|value| (value.clamp(0.0, 1.0) * 255.0).round() as u8);
+                    Color::new(r, g, b)
+                })
+            })
+            .collect();
+
+        // Leave one index free for the transparent pixels of frame deltas, like the source palette
+        reduce_palette(colors, MAX_PALETTE_SIZE - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A LUT that swaps the red and blue channels.
+    const SWAP_LUT: &str = "TITLE \"swap\"\n# red and blue swapped\nLUT_3D_SIZE 2\n\
+        0 0 0\n0 0 1\n0 1 0\n0 1 1\n1 0 0\n1 0 1\n1 1 0\n1 1 1\n";
+
+    #[test]
+    fn test_cube_luts_are_parsed_and_interpolated() {
+        let lut = Lut3d::parse(SWAP_LUT).unwrap();
+
+        assert_eq!(lut.apply([1.0, 0.0, 0.0]), [0.0, 0.0, 1.0]);
+        assert_eq!(lut.apply([0.25, 0.5, 0.75]), [0.75, 0.5, 0.25]);
+        assert!(Lut3d::parse("LUT_3D_SIZE 2\n0 0 0\n").is_err());
+        assert!(Lut3d::parse("LUT_3D_SIZE 1\n0 0 0\n").is_err());
+        assert!(Lut3d::parse(&format!("LUT_3D_SIZE {}\n0 0 0\n", usize::MAX)).is_err());
+        assert!(Lut3d::parse("LUT_3D_SIZE 257\n0 0 0\n").is_err());
+    }
+
+    #[test]
+    fn test_grades_are_interpolated_and_flashes_fade() {
+        let darkening = GradeKeyframe { time: 1.0, grade: ColorGrade { exposure: 0.0, ..ColorGrade::default() } };
+        let flash = Flash { time: 0.4, intensity: 1.0, duration: 0.4, color: [255, 255, 255] };
+        let timeline = ColorTimeline::new(vec![darkening, GradeKeyframe { time: 0.0, grade: ColorGrade::default() }], vec![flash]).unwrap();
+
+        let mut pixels = vec![0xFF808080];
+        timeline.apply(&mut pixels, 0.5);
+        // Halfway to black, then three quarters of the way to the white of the fading flash
+        assert_eq!(pixels, vec![0xFFCFCFCF]);
+
+        let mut pixels = vec![0xFF808080];
+        timeline.apply(&mut pixels, 1.25);
+        assert_eq!(pixels, vec![0xFF000000]);
+        assert!(ColorTimeline::new(Vec::new(), vec![Flash { duration: 0.0, ..flash }]).is_err());
+
+        let (color_map, _) = timeline.grade_palette(&[128, 128, 128, 255, 0, 0]).unwrap();
+        assert!(color_map.chunks(3).any(|rgb| rgb == [255, 255, 255]));
+        assert!(color_map.chunks(3).any(|rgb| rgb == [0, 0, 0]));
+    }
+}
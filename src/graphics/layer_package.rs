@@ -0,0 +1,265 @@
+use crate::graphics::parallax::{ParallaxLayer, RepeatMode};
+use crate::state::constants::file_paths::PACKAGES_DIRECTORY;
+use chrono::NaiveDate;
+use image::{DynamicImage, GenericImageView};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt::Write;
+use std::fs;
+use timing_macro::timed;
+
+/// Describes a set of parallax layers in a form game engines can import.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct LayerManifest {
+    /// The date of the background the layers were cut from.
+    pub date: String,
+    /// The width of the full scene in pixels.
+    pub width: u32,
+    /// The height of the full scene in pixels.
+    pub height: u32,
+    /// The layers, back to front.
+    pub layers: Vec<LayerEntry>,
+}
+
+/// A single cropped layer and how it moves relative to the camera.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct LayerEntry {
+    /// The layer's name, also used for its nodes in the engine scenes.
+    pub name: String,
+    /// The file name of the cropped layer image.
+    pub image: String,
+    /// Where the cropped image sits within the scene, in pixels.
+    pub offset_x: f32,
+    pub offset_y: f32,
+    /// The size of the cropped image in pixels.
+    pub width: u32,
+    pub height: u32,
+    /// The fraction of the camera movement the layer follows, horizontally and vertically.
+    pub scroll_factor_x: f32,
+    pub scroll_factor_y: f32,
+    /// Whether the layer repeats horizontally to fill the scene as it scrolls.
+    pub repeat_x: bool,
+    /// Whether the layer repeats vertically.
+    pub repeat_y: bool,
+    /// The opacity of the layer, from 0.0 to 1.0.
+    pub opacity: f32,
+    /// The RGB color the layer is multiplied by.
+    pub tint: [u8; 3],
+}
+
+/// Exports the layers of a date as a parallax package for game engines.
+///
+/// The layers in `layers/<n>/layer_<date>.png` are cropped to their visible pixels and written
+/// to `packages/parallax_<date>/` together with a JSON manifest, a Godot scene with a
+/// `ParallaxBackground` and a Tiled map with one image layer per parallax layer. Each layer
+/// scrolls, repeats and is tinted the same way as in the rendered GIF.
+///
+/// # Arguments
+/// * `date` - The date of the layers to export.
+/// * `layers` - The parallax layers to export, back to front.
+///
+/// # Returns
+/// The directory the package was written to, or an error if a layer could not be read or written.
+#[timed]
+pub fn export_layer_package(date: NaiveDate, layers: &[ParallaxLayer]) -> Result<String, Box<dyn Error>> {
+    let directory = format!("{}/parallax_{}", PACKAGES_DIRECTORY, date);
+    fs::create_dir_all(&directory)?;
+
+    let images = layers
+        .iter()
+        .map(|layer| image::open(format!("layers/{}/layer_{}.png", layer.sprite + 1, date)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let (manifest, cropped_layers) = build_layer_package(&images, layers, date);
+
+    for (entry, cropped) in manifest.layers.iter().zip(&cropped_layers) {
+        cropped.save(format!("{}/{}", directory, entry.image))?;
+        println!("Layer '{}' exported with scroll factor {}", entry.name, entry.scroll_factor_x);
+    }
+
+    fs::write(format!("{}/parallax.json", directory), serde_json::to_string_pretty(&manifest)?)?;
+    fs::write(format!("{}/parallax.tscn", directory), to_godot_scene(&manifest))?;
+    fs::write(format!("{}/parallax.tmx", directory), to_tiled_map(&manifest))?;
+
+    println!("Parallax package written to '{}'", directory);
+    Ok(directory)
+}
+
+/// Crops each layer to its visible pixels and describes it in a manifest.
+///
+/// # Arguments
+/// * `images` - The full-size image of each layer.
+/// * `layers` - The parallax layers, back to front.
+/// * `date` - The date of the layers.
+///
+/// # Returns
+/// The manifest and the cropped layer images, in the same order.
+fn build_layer_package(images: &[DynamicImage], layers: &[ParallaxLayer], date: NaiveDate) -> (LayerManifest, Vec<DynamicImage>) {
+    let (width, height) = images.first().map(|image| image.dimensions()).unwrap_or_default();
+    let mut entries = Vec::with_capacity(layers.len());
+    let mut cropped_layers = Vec::with_capacity(layers.len());
+
+    for (image, layer) in images.iter().zip(layers) {
+        let (crop_x, crop_y, crop_width, crop_height) = visible_bounds(image);
+
+        entries.push(LayerEntry {
+            name: layer.name.clone(),
+            image: format!("{}.png", layer.name),
+            offset_x: crop_x as f32,
+            offset_y: crop_y as f32 + layer.offset_y,
+            width: crop_width,
+            height: crop_height,
+            scroll_factor_x: layer.scroll_factor,
+            scroll_factor_y: layer.scroll_factor,
+            repeat_x: layer.repeat != RepeatMode::None,
+            repeat_y: layer.repeat == RepeatMode::Both,
+            opacity: layer.opacity,
+            tint: layer.tint,
+        });
+        cropped_layers.push(image.crop_imm(crop_x, crop_y, crop_width, crop_height));
+    }
+
+    let manifest = LayerManifest { date: date.to_string(), width, height, layers: entries };
+    (manifest, cropped_layers)
+}
+
+/// Finds the smallest rectangle containing every pixel that is not fully transparent.
+///
+/// # Returns
+/// The rectangle as `(x, y, width, height)`, or the whole image if it is fully transparent.
+fn visible_bounds(layer: &DynamicImage) -> (u32, u32, u32, u32) {
+    let (width, height) = layer.dimensions();
+    let (mut left, mut top, mut right, mut bottom) = (width, height, 0, 0);
+
+    for (x, y, pixel) in layer.pixels() {
+        if pixel.0[3] > 0 {
+            left = left.min(x);
+            top = top.min(y);
+            right = right.max(x + 1);
+            bottom = bottom.max(y + 1);
+        }
+    }
+
+    if left >= right || top >= bottom {
+        return (0, 0, width, height);
+    }
+
+    (left, top, right - left, bottom - top)
+}
+
+/// Writes the manifest as a Godot 4 scene with a `ParallaxBackground` and one `ParallaxLayer` per layer.
+fn to_godot_scene(manifest: &LayerManifest) -> String {
+    let mut scene = format!("[gd_scene load_steps={} format=3]\n\n", manifest.layers.len() + 1);
+
+    for (index, layer) in manifest.layers.iter().enumerate() {
+        let _ = writeln!(scene, "[ext_resource type=\"Texture2D\" path=\"res://{}\" id=\"{}\"]", layer.image, index + 1);
+    }
+
+    let _ = write!(scene, "\n[node name=\"Parallax_{}\" type=\"ParallaxBackground\"]\n", manifest.date);
+
+    for (index, layer) in manifest.layers.iter().enumerate() {
+        let mirroring_x = if layer.repeat_x { manifest.width } else { 0 };
+        let mirroring_y = if layer.repeat_y { manifest.height } else { 0 };
+
+        let _ = write!(
+            scene,
+            "\n[node name=\"{name}\" type=\"ParallaxLayer\" parent=\".\"]\n\
+             motion_scale = Vector2({}, {})\n\
+             motion_mirroring = Vector2({}, {})\n\
+             modulate = Color({}, {}, {}, {})\n\
+             \n[node name=\"Sprite\" type=\"Sprite2D\" parent=\"{name}\"]\n\
+             texture = ExtResource(\"{}\")\n\
+             centered = false\n\
+             position = Vector2({}, {})\n",
+            layer.scroll_factor_x,
+            layer.scroll_factor_y,
+            mirroring_x,
+            mirroring_y,
+            layer.tint[0] as f32 / 255.0,
+            layer.tint[1] as f32 / 255.0,
+            layer.tint[2] as f32 / 255.0,
+            layer.opacity,
+            index + 1,
+            layer.offset_x,
+            layer.offset_y,
+            name = layer.name,
+        );
+    }
+
+    scene
+}
+
+/// Writes the manifest as a Tiled map with one parallax image layer per layer.
+fn to_tiled_map(manifest: &LayerManifest) -> String {
+    let mut map = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <map version=\"1.10\" orientation=\"orthogonal\" renderorder=\"right-down\" width=\"1\" height=\"1\" \
+         tilewidth=\"{}\" tileheight=\"{}\" infinite=\"0\" nextlayerid=\"{}\" nextobjectid=\"1\">\n",
+        manifest.width,
+        manifest.height,
+        manifest.layers.len() + 1,
+    );
+
+    for (index, layer) in manifest.layers.iter().enumerate() {
+        let _ = write!(
+            map,
+            " <imagelayer id=\"{}\" name=\"{}\" offsetx=\"{}\" offsety=\"{}\" parallaxx=\"{}\" parallaxy=\"{}\" repeatx=\"{}\" repeaty=\"{}\" \
+             opacity=\"{}\" tintcolor=\"#{:02x}{:02x}{:02x}\">\n\
+             \x20 <image source=\"{}\" width=\"{}\" height=\"{}\"/>\n\
+             \x20</imagelayer>\n",
+            index + 1,
+            layer.name,
+            layer.offset_x,
+            layer.offset_y,
+            layer.scroll_factor_x,
+            layer.scroll_factor_y,
+            layer.repeat_x as u8,
+            layer.repeat_y as u8,
+            layer.opacity,
+            layer.tint[0],
+            layer.tint[1],
+            layer.tint[2],
+            layer.image,
+            layer.width,
+            layer.height,
+        );
+    }
+
+    map.push_str("</map>\n");
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphics::parallax::default_parallax_layers;
+    use image::{Rgba, RgbaImage};
+
+    #[test]
+    fn test_layers_are_cropped_and_keep_their_scroll_factors() {
+        let images: Vec<DynamicImage> = (0..4u32)
+            .map(|band| {
+                DynamicImage::ImageRgba8(RgbaImage::from_fn(8, 8, |_, y| match y / 2 == band {
+                    true => Rgba([255, 0, 0, 255]),
+                    false => Rgba([0, 0, 0, 0]),
+                }))
+            })
+            .collect();
+        let date = NaiveDate::from_ymd_opt(2025, 8, 2).unwrap();
+
+        let (manifest, cropped) = build_layer_package(&images, &default_parallax_layers(), date);
+
+        assert_eq!(manifest.layers.len(), 4);
+        let third = &manifest.layers[2];
+        assert_eq!((third.offset_x, third.offset_y, third.width, third.height), (0.0, 4.0, 8, 2));
+        assert_eq!(third.scroll_factor_x, 0.25);
+        assert_eq!(cropped[2].dimensions(), (8, 2));
+
+        let scene = to_godot_scene(&manifest);
+        assert!(scene.contains("[node name=\"layer_3\" type=\"ParallaxLayer\" parent=\".\"]\nmotion_scale = Vector2(0.25, 0.25)"));
+        assert!(scene.contains("position = Vector2(0, 4)"));
+
+        let map = to_tiled_map(&manifest);
+        assert!(map.contains("<imagelayer id=\"3\" name=\"layer_3\" offsetx=\"0\" offsety=\"4\" parallaxx=\"0.25\""));
+    }
+}
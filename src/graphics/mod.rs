@@ -1,6 +1,19 @@
 pub mod sprites;
 pub mod render_graphics;
+pub mod render_target;
 pub mod update_graphics;
 pub mod gif;
+pub mod pipeline;
+pub mod frame_delta;
+pub mod size_budget;
+pub mod output;
+pub mod sprite_sheet;
 pub mod parallax;
+pub mod layer_package;
 pub mod color;
+pub mod compositing;
+pub mod atmosphere;
+pub mod particles;
+pub mod actor;
+pub mod grading;
+pub mod text;
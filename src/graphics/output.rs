@@ -0,0 +1,500 @@
+use crate::graphics::sprite_sheet::{FrameSequenceWriter, SpriteSheetWriter};
+use crate::state::constants::file_paths::ANIMATIONS_DIRECTORY;
+use crate::state::constants::graphics::VIDEO_ENCODER;
+use crate::state::timeline::Timeline;
+use chrono::NaiveDate;
+use image_webp::{ColorType, WebPEncoder};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::str::FromStr;
+
+/// The animation formats that can be written alongside the GIF.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Animated PNG, lossless with full color and alpha.
+    Apng,
+    /// Animated WebP, lossless with full color and alpha.
+    WebP,
+    /// H.264 video, encoded by the local video encoder.
+    Mp4,
+    /// VP9 video with alpha, encoded by the local video encoder.
+    WebM,
+    /// A directory of numbered PNGs, one per frame.
+    Frames,
+    /// A single PNG with every frame in a grid, described by a JSON atlas.
+    SpriteSheet,
+}
+
+impl OutputFormat {
+    /// The name of the file, or directory for `Frames`, the format is written to.
+    ///
+    /// # Arguments
+    /// * `date` - The date of the recording.
+    pub fn file_name(self, date: NaiveDate) -> String {
+        match self {
+            OutputFormat::Apng => format!("animation_{}.apng", date),
+            OutputFormat::WebP => format!("animation_{}.webp", date),
+            OutputFormat::Mp4 => format!("animation_{}.mp4", date),
+            OutputFormat::WebM => format!("animation_{}.webm", date),
+            OutputFormat::Frames => format!("frames_{}", date),
+            OutputFormat::SpriteSheet => format!("sprite_sheet_{}.png", date),
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "apng" | "png" => Ok(OutputFormat::Apng),
+            "webp" => Ok(OutputFormat::WebP),
+            "mp4" => Ok(OutputFormat::Mp4),
+            "webm" => Ok(OutputFormat::WebM),
+            "frames" => Ok(OutputFormat::Frames),
+            "spritesheet" | "sprite-sheet" => Ok(OutputFormat::SpriteSheet),
+            _ => Err(format!("Unknown output format '{}', expected one of apng, webp, mp4, webm, frames or spritesheet.", name)),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    /// Writes the name the format is parsed from.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            OutputFormat::Apng => "apng",
+            OutputFormat::WebP => "webp",
+            OutputFormat::Mp4 => "mp4",
+            OutputFormat::WebM => "webm",
+            OutputFormat::Frames => "frames",
+            OutputFormat::SpriteSheet => "spritesheet",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Receives the rendered frames of a recording, in order, and writes them to an animation file.
+pub trait AnimationWriter {
+    /// Adds the next rendered ARGB frame to the animation.
+    fn write_frame(&mut self, pixels: &[u32]) -> Result<(), Box<dyn Error>>;
+
+    /// Completes the animation file.
+    ///
+    /// # Returns
+    /// The path of the written file.
+    fn finish(self: Box<Self>) -> Result<String, Box<dyn Error>>;
+}
+
+/// Creates a writer for each requested format, writing to the `animations` directory.
+///
+/// # Arguments
+/// * `formats` - The formats to write.
+/// * `date` - The date of the recording, used for naming the files.
+/// * `width` - The width of the frames in pixels.
+/// * `height` - The height of the frames in pixels.
+/// * `timeline` - The clock the frames are rendered on, which sets how long each is shown.
+///
+/// # Returns
+/// One `AnimationWriter` per format, or an error if one of them could not be created.
+pub fn create_animation_writers(
+    formats: &[OutputFormat],
+    date: NaiveDate,
+    width: u32,
+    height: u32,
+    timeline: &Timeline,
+) -> Result<Vec<Box<dyn AnimationWriter>>, Box<dyn Error>> {
+    if !formats.is_empty() {
+        fs::create_dir_all(ANIMATIONS_DIRECTORY)?;
+    }
+
+    formats
+        .iter()
+        .map(|&format| -> Result<Box<dyn AnimationWriter>, Box<dyn Error>> {
+            let path = format!("{}/{}", ANIMATIONS_DIRECTORY, format.file_name(date));
+            Ok(match format {
+                OutputFormat::Apng => Box::new(ApngWriter::new(path, width, height, timeline.clone())),
+                OutputFormat::WebP => Box::new(WebPWriter::new(path, width, height, timeline.clone())),
+                OutputFormat::Mp4 | OutputFormat::WebM => Box::new(VideoWriter::spawn(path, format, width, height, timeline.clone())?),
+                OutputFormat::Frames => Box::new(FrameSequenceWriter::new(path, width, height)?),
+                OutputFormat::SpriteSheet => Box::new(SpriteSheetWriter::new(path, width, height, timeline.clone())),
+            })
+        })
+        .collect()
+}
+
+/// Buffers frames and writes them as an APNG once the frame count is known.
+struct ApngWriter {
+    path: String,
+    width: u32,
+    height: u32,
+    timeline: Timeline,
+    frames: Vec<Vec<u8>>,
+}
+
+impl ApngWriter {
+    fn new(path: String, width: u32, height: u32, timeline: Timeline) -> Self {
+        Self { path, width, height, timeline, frames: Vec::new() }
+    }
+}
+
+impl AnimationWriter for ApngWriter {
+    fn write_frame(&mut self, pixels: &[u32]) -> Result<(), Box<dyn Error>> {
+        self.frames.push(argb_to_rgba(pixels));
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<String, Box<dyn Error>> {
+        let file = BufWriter::new(File::create(&self.path)?);
+        let delays = frame_durations(&self.timeline, self.frames.len());
+        encode_apng(file, &self.frames, self.width, self.height, &delays)?;
+        Ok(self.path)
+    }
+}
+
+/// Compresses each frame to a lossless WebP bitstream as it arrives and assembles the animation at the end.
+struct WebPWriter {
+    path: String,
+    width: u32,
+    height: u32,
+    timeline: Timeline,
+    bitstreams: Vec<Vec<u8>>,
+}
+
+impl WebPWriter {
+    fn new(path: String, width: u32, height: u32, timeline: Timeline) -> Self {
+        Self { path, width, height, timeline, bitstreams: Vec::new() }
+    }
+}
+
+impl AnimationWriter for WebPWriter {
+    fn write_frame(&mut self, pixels: &[u32]) -> Result<(), Box<dyn Error>> {
+        self.bitstreams.push(encode_webp_bitstream(&argb_to_rgba(pixels), self.width, self.height)?);
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<String, Box<dyn Error>> {
+        let durations = frame_durations(&self.timeline, self.bitstreams.len());
+        fs::write(&self.path, assemble_animated_webp(&self.bitstreams, self.width, self.height, &durations))?;
+        Ok(self.path)
+    }
+}
+
+/// Pipes raw RGBA frames to the local video encoder as they arrive.
+///
+/// Videos play at a constant frame rate, so frames with longer delays are piped repeatedly.
+struct VideoWriter {
+    path: String,
+    encoder: Child,
+    /// The encoder's standard input, taken once the video is finished.
+    input: Option<ChildStdin>,
+    timeline: Timeline,
+    written: usize,
+    /// The number of video frames the written frames last on the timeline.
+    due: usize,
+    /// The number of video frames piped to the encoder.
+    piped: usize,
+}
+
+impl VideoWriter {
+    /// Starts the video encoder, reading raw frames from its standard input.
+    fn spawn(path: String, format: OutputFormat, width: u32, height: u32, timeline: Timeline) -> Result<Self, Box<dyn Error>> {
+        let codec_arguments: &[&str] = match format {
+            OutputFormat::WebM => &["-c:v", "libvpx-vp9", "-pix_fmt", "yuva420p", "-lossless", "1"],
+            _ => &["-c:v", "libx264", "-pix_fmt", "yuv420p", "-crf", "18"],
+        };
+
+        let mut encoder = Command::new(VIDEO_ENCODER)
+            .args(["-loglevel", "error", "-y", "-f", "rawvideo", "-pixel_format", "rgba"])
+            .args(["-video_size", &format!("{}x{}", width, height)])
+            .args(["-framerate", &timeline.frames_per_second.to_string()])
+            .args(["-i", "-"])
+            .args(codec_arguments)
+            .arg(&path)
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Could not start the video encoder '{}' for '{}': {}", VIDEO_ENCODER, path, e))?;
+
+        let input = encoder.stdin.take().ok_or("The video encoder has no standard input.")?;
+        Ok(Self { path, encoder, input: Some(input), timeline, written: 0, due: 0, piped: 0 })
+    }
+}
+
+impl AnimationWriter for VideoWriter {
+    fn write_frame(&mut self, pixels: &[u32]) -> Result<(), Box<dyn Error>> {
+        let input = self.input.as_mut().ok_or("The video encoder's input is already closed.")?;
+        let rgba = argb_to_rgba(pixels);
+        self.due += self.timeline.delay_at(self.written, self.timeline.frames_per_second as f64) as usize;
+        let copies = video_copies(self.due, self.piped);
+        for _ in 0..copies {
+            input.write_all(&rgba)?;
+        }
+        self.written += 1;
+        self.piped += copies;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<String, Box<dyn Error>> {
+        // Closing the input signals the end of the video to the encoder
+        drop(self.input.take());
+
+        let status = self.encoder.wait()?;
+        if !status.success() {
+            return Err(format!("The video encoder failed to write '{}' ({}).", self.path, status).into());
+        }
+
+        Ok(std::mem::take(&mut self.path))
+    }
+}
+
+impl Drop for VideoWriter {
+    /// Stops the encoder of a video abandoned before `finish`, when another writer or the GIF
+    /// failed, so it leaves neither a zombie process nor a truncated video behind.
+    fn drop(&mut self) {
+        if self.input.take().is_some() {
+            let _ = self.encoder.kill();
+            let _ = self.encoder.wait();
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// How many times a frame is piped to the video encoder, so the video keeps to the timeline.
+///
+/// Every frame is piped at least once, even when it is shown for less than a video frame, and
+/// the time it overruns is taken from the frames after it, so eased segments keep every frame.
+///
+/// # Arguments
+/// * `due` - The number of video frames the frames up to and including this one last on the timeline.
+/// * `piped` - The number of video frames already piped.
+fn video_copies(due: usize, piped: usize) -> usize {
+    due.saturating_sub(piped).max(1)
+}
+
+/// How long each of a number of frames is shown, in milliseconds, as the timeline rounds them.
+pub fn frame_durations(timeline: &Timeline, frame_count: usize) -> Vec<u32> {
+    (0..frame_count).map(|frame| timeline.delay_at(frame, 1000.0)).collect()
+}
+
+/// Splits a delay evenly over as few frame copies as can show it with 16-bit frame delays.
+///
+/// # Returns
+/// The delay of each copy, in the unit of `delay`.
+pub fn split_delay(delay: u32) -> impl Iterator<Item = u16> {
+    let copies = delay.div_ceil(u16::MAX as u32).max(1);
+    (0..copies).map(move |copy| (delay * (copy + 1) / copies - delay * copy / copies) as u16)
+}
+
+/// Converts packed ARGB pixels to interleaved RGBA bytes.
+pub fn argb_to_rgba(pixels: &[u32]) -> Vec<u8> {
+    pixels
+        .iter()
+        .flat_map(|&pixel| [(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8, (pixel >> 24) as u8])
+        .collect()
+}
+
+/// Writes RGBA frames as an endlessly looping APNG.
+///
+/// # Arguments
+/// * `writer` - The destination of the APNG file.
+/// * `frames` - The RGBA frames, in order.
+/// * `width` - The width of the frames in pixels.
+/// * `height` - The height of the frames in pixels.
+/// * `delays` - How long each frame is shown, in milliseconds.
+fn encode_apng<W: Write>(writer: W, frames: &[Vec<u8>], width: u32, height: u32, delays: &[u32]) -> Result<(), Box<dyn Error>> {
+    if frames.is_empty() {
+        return Err("An APNG needs at least one frame.".into());
+    }
+
+    let mut encoder = png::Encoder::new(writer, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    if frames.len() != delays.len() {
+        return Err(format!("An APNG of {} frames was given {} delays.", frames.len(), delays.len()).into());
+    }
+
+    // Frames shown for longer than a delay can hold are written again for the rest of the hold
+    let frame_count: usize = delays.iter().map(|&delay| split_delay(delay).count()).sum();
+    encoder.set_animated(frame_count as u32, 0)?;
+
+    let mut writer = encoder.write_header()?;
+    for (frame, &delay) in frames.iter().zip(delays) {
+        for copy_delay in split_delay(delay) {
+            writer.set_frame_delay(copy_delay, 1000)?;
+            writer.write_image_data(frame)?;
+        }
+    }
+    writer.finish()?;
+
+    Ok(())
+}
+
+/// Compresses an RGBA frame to a lossless WebP (VP8L) bitstream.
+///
+/// The encoder wraps the bitstream in a still image container, which is stripped so the
+/// bitstream can be embedded in an animation frame instead.
+fn encode_webp_bitstream(rgba: &[u8], width: u32, height: u32) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut still_image = Vec::new();
+    WebPEncoder::new(&mut still_image).encode(rgba, width, height, ColorType::Rgba8)?;
+
+    // RIFF header (12 bytes) followed by the VP8L chunk header (8 bytes)
+    let length = u32::from_le_bytes(still_image[16..20].try_into()?) as usize;
+    if &still_image[12..16] != b"VP8L" || still_image.len() < 20 + length {
+        return Err("The WebP encoder produced an unexpected container.".into());
+    }
+
+    Ok(still_image[20..20 + length].to_vec())
+}
+
+/// Assembles lossless WebP bitstreams into an endlessly looping animated WebP file.
+///
+/// Every frame covers the whole canvas and replaces the previous one without blending.
+///
+/// # Arguments
+/// * `bitstreams` - The VP8L bitstream of each frame, in order.
+/// * `width` - The width of the canvas in pixels.
+/// * `height` - The height of the canvas in pixels.
+/// * `durations` - How long each frame is shown, in milliseconds.
+fn assemble_animated_webp(bitstreams: &[Vec<u8>], width: u32, height: u32, durations: &[u32]) -> Vec<u8> {
+    let mut chunks = Vec::new();
+
+    // Animation and alpha flags, followed by the canvas size
+    let mut vp8x = vec![0x02 | 0x10, 0, 0, 0];
+    vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+    vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+    write_webp_chunk(&mut chunks, b"VP8X", &vp8x);
+
+    // Transparent background and infinite looping
+    write_webp_chunk(&mut chunks, b"ANIM", &[0, 0, 0, 0, 0, 0]);
+
+    for (bitstream, duration) in bitstreams.iter().zip(durations) {
+        let mut anmf = Vec::with_capacity(bitstream.len() + 24);
+        anmf.extend_from_slice(&[0; 6]); // Frame offset
+        anmf.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        anmf.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+        anmf.extend_from_slice(&duration.to_le_bytes()[..3]);
+        anmf.push(0x02); // Do not blend with the previous frame, do not dispose
+        write_webp_chunk(&mut anmf, b"VP8L", bitstream);
+        write_webp_chunk(&mut chunks, b"ANMF", &anmf);
+    }
+
+    let mut file = Vec::with_capacity(chunks.len() + 12);
+    file.extend_from_slice(b"RIFF");
+    file.extend_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+    file.extend_from_slice(b"WEBP");
+    file.extend_from_slice(&chunks);
+    file
+}
+
+/// Appends a RIFF chunk, padded to an even length.
+fn write_webp_chunk(output: &mut Vec<u8>, name: &[u8; 4], data: &[u8]) {
+    output.extend_from_slice(name);
+    output.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    output.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        output.push(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_frames(width: u32, height: u32) -> Vec<Vec<u32>> {
+        let pixel_count = (width * height) as usize;
+        vec![vec![0xFFFF0000; pixel_count], vec![0x8000FF00; pixel_count], vec![0xFF0000FF; pixel_count]]
+    }
+
+    #[test]
+    fn test_output_formats_print_as_they_are_parsed() {
+        for name in ["apng", "webp", "mp4", "webm", "frames", "spritesheet"] {
+            assert_eq!(name.parse::<OutputFormat>().unwrap().to_string(), name);
+        }
+    }
+
+    #[test]
+    fn test_animated_webp_decodes_to_the_original_frames() {
+        let (width, height) = (5, 3);
+        let frames = test_frames(width, height);
+        let bitstreams: Vec<Vec<u8>> = frames
+            .iter()
+            .map(|frame| encode_webp_bitstream(&argb_to_rgba(frame), width, height).unwrap())
+            .collect();
+
+        let webp = assemble_animated_webp(&bitstreams, width, height, &[100; 3]);
+
+        let mut decoder = image_webp::WebPDecoder::new(std::io::Cursor::new(webp)).unwrap();
+        assert!(decoder.is_animated());
+        assert_eq!(decoder.num_frames(), 3);
+        assert_eq!(decoder.dimensions(), (width, height));
+
+        let mut buffer = vec![0; (width * height * 4) as usize];
+        for frame in &frames {
+            assert_eq!(decoder.read_frame(&mut buffer).unwrap(), 100);
+            assert_eq!(buffer, argb_to_rgba(frame));
+        }
+    }
+
+    #[test]
+    fn test_apng_decodes_to_the_original_frames() {
+        let (width, height) = (5, 3);
+        let frames: Vec<Vec<u8>> = test_frames(width, height).iter().map(|frame| argb_to_rgba(frame)).collect();
+        let mut apng = Vec::new();
+
+        encode_apng(&mut apng, &frames, width, height, &[100; 3]).unwrap();
+
+        let mut reader = png::Decoder::new(apng.as_slice()).read_info().unwrap();
+        let animation = reader.info().animation_control().unwrap();
+        assert_eq!(animation.num_frames, 3);
+        assert_eq!(animation.num_plays, 0);
+
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        for frame in &frames {
+            reader.next_frame(&mut buffer).unwrap();
+            assert_eq!(&buffer, frame);
+        }
+    }
+
+    #[test]
+    fn test_apng_splits_holds_longer_than_a_frame_delay() {
+        let (width, height) = (5, 3);
+        let frames: Vec<Vec<u8>> = test_frames(width, height).iter().map(|frame| argb_to_rgba(frame)).collect();
+        let mut apng = Vec::new();
+
+        encode_apng(&mut apng, &frames, width, height, &[100, 600_000, 100]).unwrap();
+
+        let mut reader = png::Decoder::new(apng.as_slice()).read_info().unwrap();
+        assert_eq!(reader.info().animation_control().unwrap().num_frames, 12);
+
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let mut shown = vec![0; frames.len()];
+        for _ in 0..12 {
+            reader.next_frame(&mut buffer).unwrap();
+            let control = reader.info().frame_control().unwrap();
+            let index = frames.iter().position(|frame| *frame == buffer).unwrap();
+            shown[index] += control.delay_num as u32 * 1000 / control.delay_den as u32;
+        }
+        assert_eq!(shown, vec![100, 600_000, 100]);
+    }
+
+    #[test]
+    fn test_video_keeps_frames_shorter_than_a_video_frame() {
+        let segment = "0-4:0.4~0.02".parse().unwrap();
+        let timeline = Timeline::default().with_delays(vec![segment]).unwrap();
+
+        let (mut due, mut piped) = (0, 0);
+        let copies: Vec<usize> = (0..8)
+            .map(|frame| {
+                due += timeline.delay_at(frame, timeline.frames_per_second as f64) as usize;
+                let copies = video_copies(due, piped);
+                piped += copies;
+                copies
+            })
+            .collect();
+
+        assert_eq!(copies, vec![4, 3, 2, 1, 1, 1, 1, 1]);
+        assert_eq!(piped, due);
+    }
+}
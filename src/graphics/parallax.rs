@@ -1,7 +1,14 @@
 use chrono::NaiveDate;
 use image::{GenericImageView, ImageBuffer, Rgba};
-use crate::graphics::sprites::draw_sprite;
+use crate::graphics::atmosphere::LayerEffects;
+use crate::graphics::compositing::{composite_layer, BlendMode, PremultipliedColor};
+use crate::graphics::sprites::{animation_frame, draw_sprite_resampled, SpriteFrame, SpriteMaps, SpriteTransform};
+use crate::state::constants::graphics::{LAYER_FRAMES_PER_SECOND, PARALLAX_DIVISORS};
+use rayon::prelude::*;
+use std::f32::consts::PI;
+use std::str::FromStr;
 use crate::state::structs::State;
+use serde::{Deserialize, Serialize};
 
 /// Creates parallax layers from an input image and saves them as separate files.
 ///
@@ -13,12 +20,12 @@ use crate::state::structs::State;
 /// - `Result<(), Box<dyn std::error::Error>>`: Returns `Ok(())` if successful, or an error if something goes wrong.
 ///
 /// # Functionality
-/// - This function loads an input image and splits it into multiple layers based on predefined heights.
-/// - Each layer corresponds to a specific section of the image and is saved as a separate file.
+/// - This function loads an input image and splits it into four horizontal bands of equal height.
+/// - Each layer keeps the full size of the image, with only its own band visible, and is saved as a separate file.
 /// - The layers are saved in the `layers/` directory, with subdirectories named after the layer index.
 ///
 /// # Constraints
-/// - The input image must be 1024x1024 pixels. If the dimensions are incorrect, the function returns an error.
+/// - The input image may have any size, but must be at least four pixels tall so every layer has a band.
 ///
 /// # Example
 /// ```
@@ -30,19 +37,20 @@ pub fn create_parallax_layers(input_path: &str, current_date: NaiveDate) -> Resu
     let img = image::open(input_path)?;
     let (width, height) = img.dimensions();
 
-    // Ensure the input image has the correct dimensions
-    if width != 1024 || height != 1024 {
-        return Err("Input image must be 1024x1024.".into());
+    // Ensure every layer receives at least one row
+    let layer_count = PARALLAX_DIVISORS.len() as u32;
+    if width == 0 || height < layer_count {
+        return Err(format!("Input image must be at least {} pixels tall, but is {}x{}.", layer_count, width, height).into());
     }
 
-    // Define the layer heights (each layer is 256 pixels tall)
-    let layer_heights = [256, 512, 768, 1024];
+    for i in 0..layer_count {
+        // Each band ends where the next begins, so the bands cover the image even if its height is not divisible
+        let (top, bottom) = (i * height / layer_count, (i + 1) * height / layer_count);
 
-    for (i, _) in layer_heights.iter().enumerate() {
         // Create a new image buffer for the layer
         let layer = ImageBuffer::from_fn(width, height, |x, y| {
-            // Include pixels within the current layer's height range
-            if y >= (i * 256) as u32 && y < ((i + 1) * 256) as u32 {
+            // Include pixels within the current layer's band
+            if y >= top && y < bottom {
                 img.get_pixel(x, y)
             } else {
                 Rgba([0, 0, 0, 0]) // Transparent pixel for areas outside the layer
@@ -63,45 +71,388 @@ pub fn create_parallax_layers(input_path: &str, current_date: NaiveDate) -> Resu
     Ok(())
 }
 
+/// How a layer continues beyond the edges of its sprite.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RepeatMode {
+    /// The layer is drawn once.
+    None,
+    /// The layer wraps around horizontally, filling the width as it scrolls.
+    #[default]
+    Horizontal,
+    /// The layer wraps around horizontally with every other repeat mirrored, hiding the seams
+    /// of images whose left and right edges do not match.
+    Mirrored,
+    /// The layer wraps around both horizontally and vertically.
+    Both,
+}
+
+/// A viewport several widths of the scene wide, showing the horizontally repeating layers as a
+/// cinematic strip.
+///
+/// Written as `<tiles>[:mirrored]`, for example `3:mirrored` for a viewport three scene widths
+/// wide in which every other repeat is mirrored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Panorama {
+    /// How many widths of the scene fit side by side in the viewport.
+    pub tiles: u32,
+    /// Whether the horizontally repeating layers mirror every other repeat.
+    pub mirrored: bool,
+}
+
+impl FromStr for Panorama {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (tiles, mirrored) = match spec.split_once(':') {
+            Some((tiles, "mirrored")) => (tiles, true),
+            Some((_, option)) => return Err(format!("Unknown panorama option '{}', expected 'mirrored'.", option)),
+            None => (spec, false),
+        };
+        match tiles.parse::<u32>() {
+            Ok(tiles) if tiles > 0 => Ok(Self { tiles, mirrored }),
+            _ => Err(format!("Invalid panorama '{}', expected <tiles>[:mirrored] with at least one tile.", spec)),
+        }
+    }
+}
+
+impl Panorama {
+    /// The size of a viewport of a given height that fits the panorama's tiles side by side.
+    ///
+    /// # Arguments
+    /// * `height` - The height of the viewport, which the scene is scaled to.
+    /// * `scene_size` - The width and height of the scene as loaded.
+    ///
+    /// # Returns
+    /// The width and height of the viewport, or an error if it is wider than a GIF can be.
+    pub fn viewport_size(self, height: usize, scene_size: (u32, u32)) -> Result<(usize, usize), Box<dyn std::error::Error>> {
+        let (scene_width, scene_height) = scene_size;
+        let width = (self.tiles as f32 * scene_width as f32 * height as f32 / scene_height.max(1) as f32).round() as usize;
+        if width == 0 || width > u16::MAX as usize {
+            return Err(format!("A panorama of {} tiles is {} pixels wide, but a GIF is at most {}.", self.tiles, width, u16::MAX).into());
+        }
+        Ok((width, height))
+    }
+
+    /// Switches the horizontally repeating layers to mirrored repeats if the panorama is mirrored.
+    ///
+    /// # Arguments
+    /// * `layers` - The layers to update. Layers drawn once or repeating both ways are left unchanged.
+    pub fn apply(self, layers: &mut [ParallaxLayer]) {
+        if !self.mirrored {
+            return;
+        }
+        for layer in layers.iter_mut().filter(|layer| layer.repeat == RepeatMode::Horizontal) {
+            layer.repeat = RepeatMode::Mirrored;
+        }
+    }
+}
+
+/// Generates animation frames for a layer by displacing its rows along a sine wave.
+///
+/// The wave moves through one full period over the frames, so the animation loops seamlessly.
+/// Displacing only the rows below `top` turns the lower part of a layer into shimmering water.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Ripple {
+    /// The number of frames to generate.
+    pub frames: usize,
+    /// How far rows are moved sideways at most, in pixels.
+    pub amplitude: f32,
+    /// The vertical distance between two crests of the wave, in pixels.
+    #[serde(default = "default_wavelength")]
+    pub wavelength: f32,
+    /// The first row that is displaced, leaving the rows above it still.
+    #[serde(default)]
+    pub top: u32,
+}
+
+fn default_wavelength() -> f32 {
+    24.0
+}
+
+/// How the frames of a layer are played.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LayerAnimation {
+    /// How many of the layer's frames are played per second.
+    #[serde(default = "default_layer_frames_per_second")]
+    pub frames_per_second: f32,
+    /// The ripple to generate the layer's frames with, replacing the frames of its sprite.
+    #[serde(default)]
+    pub ripple: Option<Ripple>,
+}
+
+fn default_layer_frames_per_second() -> f32 {
+    LAYER_FRAMES_PER_SECOND
+}
+
+impl Default for LayerAnimation {
+    fn default() -> Self {
+        Self { frames_per_second: LAYER_FRAMES_PER_SECOND, ripple: None }
+    }
+}
+
+/// Describes how a parallax layer is drawn.
+///
+/// The layers in `State` are drawn back to front, so their order and depth are data that can
+/// be loaded from a JSON file rather than calls in the renderer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParallaxLayer {
+    /// A name for the layer, used in logs and exports.
+    pub name: String,
+    /// The index of the layer's frames in `SpriteMaps`, from 0 for `layer_1` to 3 for `layer_4`.
+    pub sprite: usize,
+    /// The fraction of the camera movement the layer follows, where 1.0 moves with the camera and 0.0 stands still.
+    pub scroll_factor: f32,
+    /// How far the layer is moved down, in pixels, on top of the camera movement.
+    #[serde(default)]
+    pub offset_y: f32,
+    /// The factor the layer's alpha is multiplied by, from 0.0 to 1.0.
+    #[serde(default = "full_opacity")]
+    pub opacity: f32,
+    /// The RGB color the layer's colors are multiplied by, where white leaves them unchanged.
+    #[serde(default = "no_tint")]
+    pub tint: [u8; 3],
+    /// How the layer continues beyond the edges of its sprite.
+    #[serde(default)]
+    pub repeat: RepeatMode,
+    /// How the layer is combined with the layers behind it.
+    #[serde(default)]
+    pub blend: BlendMode,
+    /// The haze, fog and blur applied to the layer before it is combined with the layers behind it.
+    #[serde(default)]
+    pub effects: LayerEffects,
+    /// How the layer's frames are played, if its sprite has more than one.
+    #[serde(default)]
+    pub animation: LayerAnimation,
+}
+
+fn full_opacity() -> f32 {
+    1.0
+}
+
+fn no_tint() -> [u8; 3] {
+    [255, 255, 255]
+}
+
+impl ParallaxLayer {
+    /// Creates an opaque, untinted and horizontally repeating layer.
+    ///
+    /// # Arguments
+    /// * `name` - A name for the layer.
+    /// * `sprite` - The index of the layer's frames in `SpriteMaps`.
+    /// * `scroll_factor` - The fraction of the camera movement the layer follows.
+    pub fn new(name: &str, sprite: usize, scroll_factor: f32) -> Self {
+        Self {
+            name: name.to_string(),
+            sprite,
+            scroll_factor,
+            offset_y: 0.0,
+            opacity: full_opacity(),
+            tint: no_tint(),
+            repeat: RepeatMode::default(),
+            blend: BlendMode::default(),
+            effects: LayerEffects::default(),
+            animation: LayerAnimation::default(),
+        }
+    }
+}
+
+/// The default layers, one per sprite layer, scrolling at `1 / PARALLAX_DIVISORS` of the camera speed.
+pub fn default_parallax_layers() -> Vec<ParallaxLayer> {
+    PARALLAX_DIVISORS
+        .into_iter()
+        .enumerate()
+        .map(|(index, divisor)| ParallaxLayer::new(&format!("layer_{}", index + 1), index, 1.0 / divisor as f32))
+        .collect()
+}
+
+/// Loads parallax layers from a JSON array of layer descriptions.
+///
+/// # Arguments
+/// * `path` - The path of the JSON file.
+///
+/// # Returns
+/// The layers, back to front, or an error if the file is invalid or a layer refers to a missing sprite.
+pub fn load_parallax_layers(path: &str) -> Result<Vec<ParallaxLayer>, Box<dyn std::error::Error>> {
+    let layers: Vec<ParallaxLayer> = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+
+    if let Some(layer) = layers.iter().find(|layer| layer.sprite >= PARALLAX_DIVISORS.len()) {
+        return Err(format!("Layer '{}' refers to sprite {}, but only {} exist.", layer.name, layer.sprite, PARALLAX_DIVISORS.len()).into());
+    }
+    if let Some(layer) = layers.iter().find(|layer| !(0.0..=1.0).contains(&layer.opacity)) {
+        return Err(format!("Layer '{}' has opacity {}, which is not between 0 and 1.", layer.name, layer.opacity).into());
+    }
+    if let Some(layer) = layers.iter().find(|layer| layer.animation.frames_per_second <= 0.0 || layer.animation.ripple.is_some_and(|ripple| ripple.frames == 0)) {
+        return Err(format!("Layer '{}' needs a positive frame rate and at least one ripple frame.", layer.name).into());
+    }
+
+    Ok(layers)
+}
+
+/// Replaces the frames of the sprites of rippling layers with generated ripple frames.
+///
+/// A ripple applies to the sprite rather than the layer, so layers sharing a sprite share its
+/// frames, and the last of them with a ripple decides how they look.
+///
+/// # Arguments
+/// * `sprites` - The loaded sprites, whose frames are replaced.
+/// * `layers` - The parallax layers to generate frames for.
+pub fn animate_layer_sprites(sprites: &mut SpriteMaps, layers: &[ParallaxLayer]) {
+    for layer in layers {
+        let Some(ripple) = layer.animation.ripple else { continue };
+        let Some(frames) = sprites.layer_mut(layer.sprite) else { continue };
+        let Some(base) = frames.first() else { continue };
+
+        *frames = ripple_frames(base, ripple);
+        println!("Layer '{}' ripples over {} frames", layer.name, frames.len());
+    }
+}
+
+/// Generates the frames of a ripple from a still frame.
+///
+/// Each row below `top` is moved sideways along a sine wave whose phase advances with the frame,
+/// sampling between pixels and wrapping around horizontally like the layer does when scrolling.
+fn ripple_frames(base: &SpriteFrame, ripple: Ripple) -> Vec<SpriteFrame> {
+    let (width, height) = (base.width as usize, base.height as usize);
+    let wavelength = ripple.wavelength.max(1.0);
+
+    (0..ripple.frames)
+        .map(|frame| {
+            let phase = 2.0 * PI * frame as f32 / ripple.frames as f32;
+            let mut pixels = base.pixels().to_vec();
+
+            pixels.par_chunks_mut(width.max(1)).enumerate().skip(ripple.top as usize).for_each(|(y, row)| {
+                let shift = ripple.amplitude * (2.0 * PI * y as f32 / wavelength + phase).sin();
+                let source = &base.pixels()[y * width..][..width];
+                let (whole, fraction) = (shift.floor(), shift - shift.floor());
+
+                for (x, pixel) in row.iter_mut().enumerate() {
+                    let left = source[(x as isize + whole as isize).rem_euclid(width as isize) as usize];
+                    let right = source[(x as isize + whole as isize + 1).rem_euclid(width as isize) as usize];
+                    *pixel = mix_argb(left, right, fraction);
+                }
+            });
+
+            SpriteFrame::new(width as u32, height as u32, pixels)
+        })
+        .collect()
+}
+
+/// Mixes two ARGB pixels channel by channel.
+fn mix_argb(first: u32, second: u32, weight: f32) -> u32 {
+    (0..4).fold(0, |mixed, channel| {
+        let shift = channel * 8;
+        let (a, b) = (((first >> shift) & 0xFF) as f32, ((second >> shift) & 0xFF) as f32);
+        mixed | (((a + (b - a) * weight).round() as u32) << shift)
+    })
+}
+
 /// Draws a parallax layer onto the window buffer based on the game state.
 ///
 /// # Parameters
 /// - `game_state`: A mutable reference to the current game state, containing camera position, window buffer, and sprite layers.
-/// - `layer_index`: The index of the parallax layer to draw (0 to 3).
-/// - `divisor`: A divisor used to calculate the horizontal offset for the parallax effect.
+/// - `layer_index`: The index of the layer in `state.layers` to draw.
 ///
 /// # Parallax Effect
 /// The parallax effect is a visual technique used in 2D games to create a sense of depth and immersion.
 /// It simulates the way objects at different distances appear to move at different speeds relative to the viewer.
-/// This function calculates the offset for the layer based on the camera position and divisor, selects the appropriate layer from the game state,
-/// and uses the `draw_sprite` function to render the layer onto the window buffer.
+/// This function calculates the offset for the layer based on the camera position and the layer's scroll factor,
+/// and uses the `draw_sprite_resampled` function to render the layer's sprite onto the window buffer.
 ///
 /// Layers closer to the camera move faster, while layers farther away move slower, creating the illusion of depth.
 ///
 /// # Implementation Details
-/// - The `offset_x` is calculated using the camera's horizontal position divided by the divisor and wrapped around the texture width.
-/// - The `offset_y` is calculated using the camera's vertical position divided by a fixed value.
-/// - The appropriate layer is selected based on the `layer_index`.
-/// - The `draw_sprite` function is used to render the layer onto the window buffer.
-pub fn draw_parallax_layer(state: &mut State, layer_index: usize, divisor: usize) {
-    let texture_width = state.window_width;
-
-    let offset_x = state.camera.x as usize / divisor % texture_width;
-    let offset_y = state.camera.y as usize / 666;
-
-    let layer = match layer_index {
-        0 => &state.sprites.layer_1[0],
-        1 => &state.sprites.layer_2[0],
-        2 => &state.sprites.layer_3[0],
-        3 => &state.sprites.layer_4[0],
-        _ => unreachable!(),
+/// - The `offset_x` is the camera's horizontal position times the scroll factor, wrapped around the texture width,
+///   or twice the width for a mirrored layer, whose pattern only repeats after a mirrored copy.
+/// - The `offset_y` is the camera's vertical position times the scroll factor, plus the layer's own vertical offset.
+/// - The camera and the offsets are in pixels of the scene, and are scaled to the viewport like the layer's frames.
+/// - Both offsets keep their fractions, which are resampled so slow layers glide instead of jumping a pixel at a time.
+/// - The camera zoom is scaled by the scroll factor as well, so distant layers grow less than close ones when zooming in.
+/// - A layer with several frames shows the one its frame rate reaches at the current frame, looping.
+/// - A layer with atmospheric effects is first drawn into a buffer of its own, where the effects are applied with
+///   `1 - scroll_factor` as its depth, and then composited onto the window with its opacity and blend mode.
+pub fn draw_parallax_layer(state: &mut State, layer_index: usize) {
+    let layer = &state.layers[layer_index];
+    let Some(frames) = state.sprites.layer(layer.sprite).filter(|frames| !frames.is_empty()) else {
+        return;
     };
+    let sprite = animation_frame(frames, layer.animation.frames_per_second, state.time);
+    let scale = state.render_scale;
+    let mirrored = layer.repeat == RepeatMode::Mirrored;
+    let period = if mirrored { 2 * sprite.width } else { sprite.width };
 
-    draw_sprite(
-        (state.window_width).saturating_sub(offset_x),
-        offset_y,
-        layer,
-        state.window_buffer,
-        state.window_width,
-    );
-}
\ No newline at end of file
+    let transform = SpriteTransform {
+        offset_x: (state.camera.x * layer.scroll_factor * scale).rem_euclid(period as f32),
+        offset_y: (state.camera.y * layer.scroll_factor + layer.offset_y) * scale,
+        scale: 1.0 + (state.camera.zoom - 1.0) * layer.scroll_factor,
+        focus_x: state.camera.focus_x,
+        focus_y: state.camera.focus_y,
+        repeat_x: layer.repeat != RepeatMode::None,
+        mirror_x: mirrored,
+        repeat_y: layer.repeat == RepeatMode::Both,
+    };
+
+    if layer.effects.is_empty() {
+        draw_sprite_resampled(sprite, transform, layer.opacity, layer.tint, layer.blend, state.window_buffer, state.window_width);
+        return;
+    }
+
+    let mut layer_buffer = vec![0u32; state.window_buffer.len()];
+    draw_sprite_resampled(sprite, transform, 1.0, layer.tint, BlendMode::Normal, &mut layer_buffer, state.window_width);
+
+    let mut layer_pixels: Vec<PremultipliedColor> = layer_buffer.into_iter().map(PremultipliedColor::from_argb).collect();
+    let depth = (1.0 - layer.scroll_factor).clamp(0.0, 1.0);
+    layer.effects.apply(&mut layer_pixels, state.window_width, depth, state.time);
+
+    composite_layer(state.window_buffer, &layer_pixels, layer.opacity, layer.blend);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layer_descriptions_fill_in_defaults() {
+        let json = r#"[{"name": "far", "sprite": 0, "scroll_factor": 0.1}, {"name": "fog", "sprite": 3, "scroll_factor": 1.5, "opacity": 0.4, "repeat": "both", "blend": "screen"}]"#;
+
+        let layers: Vec<ParallaxLayer> = serde_json::from_str(json).unwrap();
+
+        assert_eq!(layers[0], ParallaxLayer::new("far", 0, 0.1));
+        assert_eq!((layers[1].opacity, layers[1].repeat, layers[1].tint), (0.4, RepeatMode::Both, [255, 255, 255]));
+        assert_eq!(layers[1].blend, BlendMode::Screen);
+        assert_eq!(default_parallax_layers()[1].scroll_factor, 1.0 / 6.0);
+    }
+
+    #[test]
+    fn test_ripple_frames_displace_rows_below_the_top_and_loop() {
+        let row: Vec<u32> = (0..8).map(|x| 0xFF000000 | (x * 32)).collect();
+        let base = SpriteFrame::new(8, 4, row.repeat(4));
+        let ripple = Ripple { frames: 4, amplitude: 1.0, wavelength: 4.0, top: 1 };
+
+        let frames = ripple_frames(&base, ripple);
+
+        assert_eq!(frames.len(), 4);
+        assert_eq!(frames[0].pixels()[..8], row[..]);
+        // Row 1 of the first frame is shifted by sin(pi / 2), a whole pixel to the left
+        assert_eq!(frames[0].pixels()[8..11], [0xFF000020, 0xFF000040, 0xFF000060]);
+        assert_ne!(frames[0].pixels(), frames[1].pixels());
+        // The wave is a quarter period further along in each frame and each row, so the last frame
+        // is one step behind the first and the ripple loops without a jump
+        assert_eq!(frames[3].pixels()[16..24], frames[0].pixels()[8..16]);
+        assert_ne!(frames[3].pixels()[8..16], frames[0].pixels()[8..16]);
+    }
+
+    #[test]
+    fn test_panorama_sizes_the_viewport_and_mirrors_horizontal_repeats() {
+        let panorama: Panorama = "3:mirrored".parse().unwrap();
+        let mut layers = vec![ParallaxLayer::new("sky", 0, 0.1), ParallaxLayer { repeat: RepeatMode::Both, ..ParallaxLayer::new("fog", 3, 1.0) }];
+
+        panorama.apply(&mut layers);
+
+        assert_eq!(panorama, Panorama { tiles: 3, mirrored: true });
+        assert_eq!((layers[0].repeat, layers[1].repeat), (RepeatMode::Mirrored, RepeatMode::Both));
+        assert_eq!(panorama.viewport_size(480, (1024, 1024)).unwrap(), (1440, 480));
+        assert!("40".parse::<Panorama>().unwrap().viewport_size(1024, (2048, 1024)).is_err());
+        assert!("0".parse::<Panorama>().is_err() && "2:flipped".parse::<Panorama>().is_err());
+    }
+}
@@ -0,0 +1,348 @@
+use crate::graphics::compositing::{composite, BlendMode, PremultipliedColor};
+use crate::state::structs::State;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::f32::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+/// How far particles may travel outside the window before wrapping around, so they enter and leave smoothly.
+const PARTICLE_MARGIN: f32 = 128.0;
+
+/// The kind of procedurally animated elements in an overlay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParticleKind {
+    /// Fast, slanted streaks falling in front of the scene.
+    Rain,
+    /// Flakes drifting down while swaying from side to side.
+    Snow,
+    /// Pulsing points of light wandering between the closest layers.
+    Fireflies,
+    /// Large, soft clouds drifting slowly behind the hills.
+    Clouds,
+    /// Autumn leaves tumbling down in front of the scene.
+    Leaves,
+}
+
+impl ParticleKind {
+    /// Guesses the overlay that fits a prompt, by looking for words about the weather or season.
+    ///
+    /// Keywords are matched against whole words of the prompt, so "terrain" does not mean rain,
+    /// and a keyword ending in `*` matches every word starting with it.
+    ///
+    /// # Returns
+    /// The first matching kind, or `None` if the prompt does not mention any.
+    pub fn from_prompt(prompt: &str) -> Option<Self> {
+        let prompt = prompt.to_lowercase();
+        let words: Vec<&str> = prompt.split(|c: char| !c.is_alphanumeric()).filter(|word| !word.is_empty()).collect();
+        let keywords: [(ParticleKind, &[&str]); 5] = [
+            (ParticleKind::Rain, &["rain", "rainy", "raining", "storm", "stormy", "drizzle", "monsoon"]),
+            (ParticleKind::Snow, &["snow", "snowy", "snowfall", "winter", "blizzard", "frost", "frosty"]),
+            (ParticleKind::Leaves, &["autumn", "fall foliage", "leaves", "harvest"]),
+            (ParticleKind::Fireflies, &["firefl*", "night", "dusk", "twilight"]),
+            (ParticleKind::Clouds, &["cloud", "clouds", "cloudy", "breeze", "windy", "overcast"]),
+        ];
+
+        let mentions = |keyword: &str| match keyword.strip_suffix('*') {
+            Some(prefix) => words.iter().any(|word| word.starts_with(prefix)),
+            None => {
+                let phrase: Vec<&str> = keyword.split(' ').collect();
+                words.windows(phrase.len()).any(|window| window == phrase.as_slice())
+            }
+        };
+
+        keywords
+            .into_iter()
+            .find(|(_, keywords)| keywords.iter().any(|keyword| mentions(keyword)))
+            .map(|(kind, _)| kind)
+    }
+
+    /// The number of particles drawn unless another count is given.
+    fn default_count(self) -> usize {
+        match self {
+            ParticleKind::Rain => 400,
+            ParticleKind::Snow => 300,
+            ParticleKind::Fireflies => 40,
+            ParticleKind::Clouds => 8,
+            ParticleKind::Leaves => 40,
+        }
+    }
+
+    /// The number of parallax layers drawn beneath the overlay unless another depth is given.
+    fn default_depth(self) -> usize {
+        match self {
+            ParticleKind::Clouds => 1,
+            ParticleKind::Fireflies => 3,
+            ParticleKind::Rain | ParticleKind::Snow | ParticleKind::Leaves => usize::MAX,
+        }
+    }
+
+    /// The fraction of the camera movement the overlay follows, matching the layers around it.
+    fn scroll_factor(self) -> f32 {
+        match self {
+            ParticleKind::Clouds => 1.0 / 6.0,
+            ParticleKind::Fireflies => 0.5,
+            ParticleKind::Rain | ParticleKind::Snow | ParticleKind::Leaves => 1.0,
+        }
+    }
+}
+
+impl FromStr for ParticleKind {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.trim().to_lowercase().as_str() {
+            "rain" => Ok(ParticleKind::Rain),
+            "snow" => Ok(ParticleKind::Snow),
+            "fireflies" => Ok(ParticleKind::Fireflies),
+            "clouds" => Ok(ParticleKind::Clouds),
+            "leaves" => Ok(ParticleKind::Leaves),
+            _ => Err(format!("Unknown particle overlay '{}', expected rain, snow, fireflies, clouds or leaves.", name)),
+        }
+    }
+}
+
+impl fmt::Display for ParticleKind {
+    /// Writes the name the kind is parsed from.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ParticleKind::Rain => "rain",
+            ParticleKind::Snow => "snow",
+            ParticleKind::Fireflies => "fireflies",
+            ParticleKind::Clouds => "clouds",
+            ParticleKind::Leaves => "leaves",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A procedurally animated overlay composited between or above the parallax layers.
+///
+/// The particles are generated from the seed and moved as a function of the timeline's time rather
+/// than simulated step by step, so every frame, and therefore the GIF, is reproducible.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParticleOverlay {
+    /// The kind of particles.
+    pub kind: ParticleKind,
+    /// The number of particles.
+    pub count: usize,
+    /// The seed the particles are generated from.
+    pub seed: u64,
+    /// The number of parallax layers drawn beneath the overlay, where anything past the last layer draws it on top.
+    pub depth: usize,
+}
+
+impl ParticleOverlay {
+    /// Creates an overlay with the default count and depth of its kind.
+    ///
+    /// # Arguments
+    /// * `kind` - The kind of particles.
+    /// * `seed` - The seed the particles are generated from.
+    pub fn new(kind: ParticleKind, seed: u64) -> Self {
+        Self { kind, count: kind.default_count(), seed, depth: kind.default_depth() }
+    }
+
+    /// Parses overlays written as `kind[:count][@depth]`, separated by commas.
+    ///
+    /// For example `clouds@1,rain:600` draws clouds right above the farthest layer and six hundred
+    /// rain streaks above all layers. Each overlay gets its own seed derived from `seed`.
+    ///
+    /// # Arguments
+    /// * `spec` - The overlays to parse.
+    /// * `seed` - The seed of the first overlay.
+    pub fn parse_list(spec: &str, seed: u64) -> Result<Vec<Self>, String> {
+        spec.split(',')
+            .filter(|overlay| !overlay.trim().is_empty())
+            .enumerate()
+            .map(|(index, overlay)| {
+                let (rest, depth) = match overlay.split_once('@') {
+                    Some((rest, depth)) => (rest, Some(depth.trim().parse().map_err(|_| format!("Invalid overlay depth '{}'.", depth))?)),
+                    None => (overlay, None),
+                };
+                let (kind, count) = match rest.split_once(':') {
+                    Some((kind, count)) => (kind, Some(count.trim().parse().map_err(|_| format!("Invalid particle count '{}'.", count))?)),
+                    None => (rest, None),
+                };
+
+                let mut overlay = Self::new(kind.parse()?, seed.wrapping_add(index as u64));
+                overlay.count = count.unwrap_or(overlay.count);
+                overlay.depth = depth.unwrap_or(overlay.depth);
+                Ok(overlay)
+            })
+            .collect()
+    }
+
+    /// Draws the overlay as it is at a point in time.
+    ///
+    /// # Arguments
+    /// * `window_buffer` - The pixels of the window in ARGB format.
+    /// * `window_width` - The width of the window in pixels.
+    /// * `camera` - The camera position, which the overlay follows by the scroll factor of its kind.
+    /// * `time` - The time on the timeline to draw, in seconds.
+    pub fn draw(&self, window_buffer: &mut [u32], window_width: usize, camera: (f32, f32), time: f32) {
+        let height = window_buffer.len() / window_width.max(1);
+        let mut canvas = Canvas { buffer: window_buffer, width: window_width, height };
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let scroll = self.kind.scroll_factor();
+        let (area_width, area_height) = (canvas.width as f32 + 2.0 * PARTICLE_MARGIN, canvas.height as f32 + 2.0 * PARTICLE_MARGIN);
+
+        for _ in 0..self.count {
+            // Every particle draws the same random values, so adding one does not move the others
+            let (x, y) = (rng.gen::<f32>() * area_width, rng.gen::<f32>() * area_height);
+            let speed = rng.gen_range(0.6..1.4);
+            let phase = rng.gen_range(0.0..2.0 * PI);
+            let size = rng.gen_range(0.6..1.4);
+            let shade = rng.gen::<f32>();
+
+            let (dx, dy) = match self.kind {
+                ParticleKind::Rain => (-30.0 * speed * time, 280.0 * speed * time),
+                ParticleKind::Snow => (12.0 * (1.5 * speed * time + phase).sin(), 25.0 * speed * time),
+                ParticleKind::Fireflies => (25.0 * (0.7 * speed * time + phase).sin(), 15.0 * (1.1 * speed * time + 2.0 * phase).sin()),
+                ParticleKind::Clouds => (15.0 * speed * time, 0.0),
+                ParticleKind::Leaves => (15.0 * speed * time + 20.0 * (speed * time + phase).sin(), 30.0 * speed * time),
+            };
+            let screen_x = (x + dx - camera.0 * scroll).rem_euclid(area_width) - PARTICLE_MARGIN;
+            let screen_y = (y + dy + camera.1 * scroll).rem_euclid(area_height) - PARTICLE_MARGIN;
+
+            match self.kind {
+                ParticleKind::Rain => canvas.streak(screen_x, screen_y, (-3.0, 28.0), 0.6 * size, [200, 210, 230], 0.6),
+                ParticleKind::Snow => canvas.disc((screen_x, screen_y), (1.5 * size + 0.5, 1.5 * size + 0.5), [255, 255, 255], 0.9, false, BlendMode::Normal),
+                ParticleKind::Fireflies => {
+                    let pulse = 0.4 + 0.6 * (0.5 + 0.5 * (5.0 * speed * time + phase).sin());
+                    canvas.disc((screen_x, screen_y), (5.0 * size, 5.0 * size), [210, 255, 120], pulse, true, BlendMode::Additive);
+                }
+                ParticleKind::Clouds => {
+                    // A cloud is a few overlapping puffs, the middle one the largest
+                    for (offset, scale) in [(-0.6, 0.7), (0.0, 1.0), (0.6, 0.75)] {
+                        let (radius_x, radius_y) = (60.0 * size * scale, 25.0 * size * scale);
+                        canvas.disc((screen_x + offset * 60.0 * size, screen_y - (scale - 0.7) * 30.0 * size), (radius_x, radius_y), [255, 255, 255], 0.35, true, BlendMode::Normal);
+                    }
+                }
+                ParticleKind::Leaves => {
+                    let colors = [[196, 98, 45], [222, 150, 50], [150, 60, 35], [180, 130, 40]];
+                    let color = colors[(shade * colors.len() as f32) as usize % colors.len()];
+                    // The leaf flattens and widens as it tumbles
+                    let tumble = (2.0 * speed * time + phase).cos().abs();
+                    canvas.disc((screen_x, screen_y), (4.0 * size, (1.0 + 2.0 * tumble) * size), color, 1.0, false, BlendMode::Normal);
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for ParticleOverlay {
+    /// Writes the overlay the way it is parsed, leaving out the depth of an overlay above all layers.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.kind, self.count)?;
+        if self.depth != usize::MAX {
+            write!(f, "@{}", self.depth)?;
+        }
+        Ok(())
+    }
+}
+
+/// Draws the overlays that sit above a number of parallax layers.
+///
+/// # Arguments
+/// * `state` - The application state, with the overlays, camera and window buffer.
+/// * `depth` - The number of layers drawn so far. Overlays deeper than the last layer are drawn with it.
+pub fn draw_particle_overlays(state: &mut State, depth: usize) {
+    let layer_count = state.layers.len();
+    let camera = (state.camera.x * state.render_scale, state.camera.y * state.render_scale);
+
+    for overlay in state.particles.iter().filter(|overlay| overlay.depth.min(layer_count) == depth) {
+        overlay.draw(state.window_buffer, state.window_width, camera, state.time);
+    }
+}
+
+/// The window buffer with its size, for drawing clipped shapes.
+struct Canvas<'a> {
+    buffer: &'a mut [u32],
+    width: usize,
+    height: usize,
+}
+
+impl Canvas<'_> {
+    /// Composites a color at a pixel, skipping pixels outside the window.
+    fn blend(&mut self, x: i32, y: i32, color: [u8; 3], alpha: f32, mode: BlendMode) {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height || alpha <= 0.0 {
+            return;
+        }
+        let pixel = &mut self.buffer[y as usize * self.width + x as usize];
+        let source = PremultipliedColor::from_argb(0xFF000000 | (color[0] as u32) << 16 | (color[1] as u32) << 8 | color[2] as u32);
+        *pixel = composite(*pixel, source.scale(alpha.min(1.0)), mode);
+    }
+
+    /// Draws a filled ellipse, either with a soft falloff toward its edge or with a one-pixel anti-aliased edge.
+    fn disc(&mut self, center: (f32, f32), radius: (f32, f32), color: [u8; 3], alpha: f32, soft: bool, mode: BlendMode) {
+        let ((center_x, center_y), (radius_x, radius_y)) = (center, radius);
+        let (left, right) = ((center_x - radius_x).floor() as i32, (center_x + radius_x).ceil() as i32);
+        let (top, bottom) = ((center_y - radius_y).floor() as i32, (center_y + radius_y).ceil() as i32);
+
+        for y in top..=bottom {
+            for x in left..=right {
+                let (dx, dy) = ((x as f32 + 0.5 - center_x) / radius_x, (y as f32 + 0.5 - center_y) / radius_y);
+                let distance = (dx * dx + dy * dy).sqrt();
+                if distance >= 1.0 {
+                    continue;
+                }
+                let coverage = match soft {
+                    true => (1.0 - distance * distance).powi(2),
+                    false => ((1.0 - distance) * radius_x.min(radius_y)).min(1.0),
+                };
+                self.blend(x, y, color, alpha * coverage, mode);
+            }
+        }
+    }
+
+    /// Draws a streak trailing behind a point along a direction, fading toward its tail.
+    fn streak(&mut self, head_x: f32, head_y: f32, direction: (f32, f32), length: f32, color: [u8; 3], alpha: f32) {
+        let steps = ((direction.0.hypot(direction.1)) * length).ceil().max(1.0) as usize;
+        for step in 0..steps {
+            let progress = step as f32 / steps as f32;
+            let x = head_x - direction.0 * length * progress;
+            let y = head_y - direction.1 * length * progress;
+            self.blend(x.floor() as i32, y.floor() as i32, color, alpha * (1.0 - progress), BlendMode::Normal);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(overlay: &ParticleOverlay, time: f32) -> Vec<u32> {
+        let mut buffer = vec![0xFF000000; 64 * 64];
+        overlay.draw(&mut buffer, 64, (0.0, 0.0), time);
+        buffer
+    }
+
+    #[test]
+    fn test_overlays_are_reproducible_and_animated() {
+        for kind in ["rain", "snow", "fireflies", "clouds", "leaves"] {
+            let overlay = ParticleOverlay { count: 50, ..ParticleOverlay::new(kind.parse().unwrap(), 7) };
+            let first = render(&overlay, 0.3);
+
+            assert_eq!(first, render(&overlay, 0.3), "{} is not reproducible", kind);
+            assert_ne!(first, render(&overlay, 0.4), "{} does not move", kind);
+            assert_ne!(first, render(&ParticleOverlay { seed: 8, ..overlay }, 0.3), "{} ignores the seed", kind);
+        }
+    }
+
+    #[test]
+    fn test_overlays_are_parsed_and_follow_the_prompt() {
+        let overlays = ParticleOverlay::parse_list("clouds@1,rain:600", 10).unwrap();
+
+        assert_eq!(overlays[0], ParticleOverlay { kind: ParticleKind::Clouds, count: 8, seed: 10, depth: 1 });
+        assert_eq!((overlays[1].count, overlays[1].seed, overlays[1].depth), (600, 11, usize::MAX));
+        assert!(ParticleOverlay::parse_list("hail", 0).is_err());
+        assert_eq!(overlays.iter().map(ToString::to_string).collect::<Vec<_>>(), ["clouds:8@1", "rain:600"]);
+
+        assert_eq!(ParticleKind::from_prompt("A pixel art valley during a summer storm"), Some(ParticleKind::Rain));
+        assert_eq!(ParticleKind::from_prompt("Fireflies over a quiet pond"), Some(ParticleKind::Fireflies));
+        assert_eq!(ParticleKind::from_prompt("A sunny beach"), None);
+        assert_eq!(ParticleKind::from_prompt("a knight on mountainous terrain"), None);
+        assert_eq!(ParticleKind::from_prompt("Frostpunk city"), None);
+        assert_eq!(ParticleKind::from_prompt("Hills in fall foliage"), Some(ParticleKind::Leaves));
+    }
+}
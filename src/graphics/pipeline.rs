@@ -0,0 +1,369 @@
+use crate::graphics::frame_delta::{diff_frames, FrameDelta};
+use crate::graphics::gif::{encode_smallest_frame, quantize_frame, write_frame_to_gif, FrameFormat, GifPalette, PixelIndexCache};
+use gif::{Encoder, Frame};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::io::Write;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, Scope, ScopedJoinHandle};
+
+/// A composited frame waiting to be quantized, tagged with its position in the GIF.
+struct PendingFrame {
+    index: usize,
+    pixels: Vec<u32>,
+    delay: u16,
+}
+
+/// A quantized and LZW-compressed frame, tagged with its position in the GIF.
+struct EncodedFrame {
+    index: usize,
+    frame: Frame<'static>,
+    /// Whether the frame quantized to the same indices as the one before it.
+    unchanged: bool,
+}
+
+/// Quantized index buffers published by the workers, each waiting for its successor to diff against.
+///
+/// Every worker publishes its own frame before waiting for the previous one, and frames leave
+/// the shared receiver in order, so the frame being waited for is always already published or
+/// held by a worker that will publish it without waiting first. A worker that panics before
+/// publishing publishes `None` instead, so the worker waiting for its frame stops too.
+#[derive(Default)]
+struct QuantizedFrames {
+    frames: Mutex<HashMap<usize, Option<Arc<Vec<u8>>>>>,
+    published: Condvar,
+}
+
+impl QuantizedFrames {
+    /// Makes a frame's palette indices available to the worker handling the next frame.
+    ///
+    /// # Arguments
+    /// * `index` - The position of the frame in the GIF.
+    /// * `indices` - The frame's palette indices, or `None` if quantizing it failed.
+    fn publish(&self, index: usize, indices: Option<Arc<Vec<u8>>>) {
+        let mut frames = self.frames.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        frames.insert(index, indices);
+        self.published.notify_all();
+    }
+
+    /// Waits for a frame's palette indices and removes them, as only the next frame diffs against them.
+    ///
+    /// # Returns
+    /// The palette indices, or `None` if the worker quantizing the frame panicked.
+    fn take(&self, index: usize) -> Option<Arc<Vec<u8>>> {
+        let mut frames = self.frames.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        loop {
+            if let Some(indices) = frames.remove(&index) {
+                return indices;
+            }
+            frames = self.published.wait(frames).unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+}
+
+/// Publishes a frame as failed if its worker panics while quantizing it.
+struct FailOnPanic<'a> {
+    quantized: &'a QuantizedFrames,
+    index: usize,
+}
+
+impl Drop for FailOnPanic<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.quantized.publish(self.index, None);
+        }
+    }
+}
+
+/// A pipelined GIF encoder.
+///
+/// Frames are composited by the caller and submitted in order. A pool of workers picks them up
+/// from a bounded channel, maps their pixels to palette indices in parallel row chunks, reduces
+/// them to the rectangle that changed since the previous frame and LZW-compresses them. A single
+/// writer thread receives the encoded frames, which may arrive out of order, and writes them to
+/// the GIF in submission order. A frame identical to the one before it is not written, and its
+/// delay is added to that frame instead, so holds and paused stretches cost no space. Frames are
+/// only merged while their delays fit a GIF delay of at most 655.35 seconds.
+///
+/// Both channels are bounded by the number of workers, so composition blocks instead of
+/// buffering an unbounded number of 4 MB frames when encoding falls behind.
+pub struct FramePipeline<'scope> {
+    sender: Option<SyncSender<PendingFrame>>,
+    workers: Vec<ScopedJoinHandle<'scope, ()>>,
+    writer: ScopedJoinHandle<'scope, Result<WrittenFrames, gif::EncodingError>>,
+    submitted: usize,
+}
+
+impl<'scope> FramePipeline<'scope> {
+    /// Spawns the worker pool and writer thread inside the given scope.
+    ///
+    /// # Arguments
+    /// * `scope` - The thread scope the pipeline's threads are bound to.
+    /// * `encoder` - The GIF encoder the writer thread takes ownership of.
+    /// * `format` - The dimensions and quantization options of each frame.
+    /// * `palette` - The palette frames are quantized against.
+    /// * `pixel_index_cache` - The cache of pixel values to palette indices shared by the workers.
+    ///
+    /// # Returns
+    /// A `FramePipeline` ready to accept frames.
+    pub fn spawn<W: Write + Send + 'scope>(
+        scope: &'scope Scope<'scope, '_>,
+        encoder: Encoder<W>,
+        format: FrameFormat,
+        palette: &'scope GifPalette,
+        pixel_index_cache: &'scope PixelIndexCache,
+    ) -> Self {
+        let worker_count = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        println!("Encoding frames with {} workers", worker_count);
+
+        let (sender, receiver) = sync_channel::<PendingFrame>(worker_count);
+        let (encoded_sender, encoded_receiver) = sync_channel::<EncodedFrame>(worker_count);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let quantized = Arc::new(QuantizedFrames::default());
+
+        let workers = (0..worker_count)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                let quantized = Arc::clone(&quantized);
+                let encoded_sender = encoded_sender.clone();
+                scope.spawn(move || run_worker(&receiver, &quantized, &encoded_sender, format, palette, pixel_index_cache))
+            })
+            .collect();
+
+        // Drop the original sender so the writer stops once every worker has finished
+        drop(encoded_sender);
+
+        let writer = scope.spawn(move || run_writer(encoder, encoded_receiver));
+
+        Self {
+            sender: Some(sender),
+            workers,
+            writer,
+            submitted: 0,
+        }
+    }
+
+    /// Submits the next composited frame, blocking while the pipeline is full.
+    ///
+    /// # Arguments
+    /// * `pixels` - A copy of the composited window buffer.
+    /// * `delay` - How long the frame is shown, in hundredths of a second.
+    ///
+    /// # Returns
+    /// `false` if the pipeline has shut down, in which case `finish` reports the cause.
+    pub fn submit(&mut self, pixels: Vec<u32>, delay: u16) -> bool {
+        let frame = PendingFrame { index: self.submitted, pixels, delay };
+
+        match self.sender.as_ref().map(|sender| sender.send(frame)) {
+            Some(Ok(())) => {
+                self.submitted += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Closes the pipeline and waits for every submitted frame to be written.
+    ///
+    /// # Returns
+    /// The number of frames written to the GIF, after merging identical frames, or the first error
+    /// raised by a worker or the writer.
+    pub fn finish(mut self) -> Result<usize, Box<dyn Error>> {
+        drop(self.sender.take());
+
+        for worker in self.workers {
+            worker.join().map_err(|_| "A GIF encoding worker panicked.")?;
+        }
+
+        let WrittenFrames { written, merged } = self.writer.join().map_err(|_| "The GIF writer panicked.")??;
+        if written + merged != self.submitted {
+            return Err(format!("Only {} of {} frames were written to the GIF.", written + merged, self.submitted).into());
+        }
+        if merged > 0 {
+            println!("Merged {} identical frames into the frames before them", merged);
+        }
+
+        Ok(written)
+    }
+}
+
+/// Pulls composited frames off the shared receiver, encodes them and forwards them to the writer.
+fn run_worker(
+    receiver: &Mutex<Receiver<PendingFrame>>,
+    quantized: &QuantizedFrames,
+    encoded_sender: &SyncSender<EncodedFrame>,
+    format: FrameFormat,
+    palette: &GifPalette,
+    pixel_index_cache: &PixelIndexCache,
+) {
+    loop {
+        // Hold the lock only while receiving so other workers can pick up the next frame
+        let pending = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+
+        let Ok(PendingFrame { index, pixels, delay }) = pending else {
+            return;
+        };
+
+        let (width, height) = (format.width, format.height);
+        let indices = {
+            let _fail_on_panic = FailOnPanic { quantized, index };
+            Arc::new(quantize_frame(&pixels, format, palette, pixel_index_cache))
+        };
+        quantized.publish(index, Some(Arc::clone(&indices)));
+
+        let mut unchanged = false;
+        let candidates = match index {
+            0 => vec![FrameDelta::full(indices.to_vec(), width, height)],
+            _ => {
+                // The previous frame's worker panicked, which `finish` reports
+                let Some(previous) = quantized.take(index - 1) else {
+                    return;
+                };
+                unchanged = previous == indices;
+                let mut candidates = vec![diff_frames(&previous, &indices, width, height, None)];
+                if let Some(transparent_index) = palette.transparent_index() {
+                    candidates.push(diff_frames(&previous, &indices, width, height, Some(transparent_index)));
+                }
+                candidates
+            }
+        };
+
+        let frame = encode_smallest_frame(candidates, palette, delay);
+        if encoded_sender.send(EncodedFrame { index, frame, unchanged }).is_err() {
+            return;
+        }
+    }
+}
+
+/// The number of frames the writer wrote to the GIF and merged into the frames before them.
+struct WrittenFrames {
+    written: usize,
+    merged: usize,
+}
+
+/// Writes encoded frames to the GIF in submission order, holding back frames that arrive early.
+///
+/// The last frame in order is only written once the next one is known to differ, so the delays
+/// of the unchanged frames after it can still be added to its own.
+fn run_writer<W: Write>(mut encoder: Encoder<W>, encoded_receiver: Receiver<EncodedFrame>) -> Result<WrittenFrames, gif::EncodingError> {
+    let mut held_back: BTreeMap<usize, EncodedFrame> = BTreeMap::new();
+    let mut next_index = 0;
+    let mut last: Option<Frame<'static>> = None;
+    let mut counts = WrittenFrames { written: 0, merged: 0 };
+
+    for encoded in encoded_receiver {
+        held_back.insert(encoded.index, encoded);
+
+        while let Some(EncodedFrame { frame, unchanged, .. }) = held_back.remove(&next_index) {
+            next_index += 1;
+            // A hold too long for a single GIF frame continues in the next one instead of being cut short
+            let merged_delay = last.as_ref().filter(|_| unchanged).and_then(|last| last.delay.checked_add(frame.delay));
+            match (last.as_mut(), merged_delay) {
+                (Some(last), Some(delay)) => {
+                    last.delay = delay;
+                    counts.merged += 1;
+                }
+                _ => {
+                    if let Some(last) = last.replace(frame) {
+                        counts.written += 1;
+                        write_frame_to_gif(&mut encoder, &last, counts.written)?;
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(last) = last {
+        counts.written += 1;
+        write_frame_to_gif(&mut encoder, &last, counts.written)?;
+    }
+
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphics::gif::initialize_gif_encoder;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_frames_are_written_in_submission_order() {
+        let color_map: Vec<u8> = (0..16u8).flat_map(|i| [i * 16; 3]).collect();
+        let index_map: HashMap<u32, u8> = (0..16u32).map(|i| ((i * 16) * 0x010101, i as u8)).collect();
+        let palette = GifPalette::new(Some(&color_map), Some(&index_map)).unwrap();
+        let cache = PixelIndexCache::default();
+        let (width, height) = (8u16, 8u16);
+        let mut output = Vec::new();
+
+        let written = thread::scope(|scope| {
+            let encoder = initialize_gif_encoder(&mut output, width, height);
+            let mut pipeline = FramePipeline::spawn(scope, encoder, FrameFormat::new(width, height), &palette, &cache);
+            for i in 0..16u32 {
+                let pixel = 0xFF000000 | ((i * 16) * 0x010101);
+                assert!(pipeline.submit(vec![pixel; width as usize * height as usize], 10));
+            }
+            pipeline.finish().unwrap()
+        });
+
+        assert_eq!(written, 16);
+
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(output.as_slice()).unwrap();
+        let mut decoded = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            decoded.push(frame.buffer[0]);
+        }
+
+        assert_eq!(decoded, (0..16u8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_identical_frames_are_merged_with_summed_delays() {
+        let color_map: Vec<u8> = (0..4u8).flat_map(|i| [i * 64; 3]).collect();
+        let index_map: HashMap<u32, u8> = (0..4u32).map(|i| ((i * 64) * 0x010101, i as u8)).collect();
+        let palette = GifPalette::new(Some(&color_map), Some(&index_map)).unwrap();
+        let cache = PixelIndexCache::default();
+        let mut output = Vec::new();
+
+        let written = thread::scope(|scope| {
+            let encoder = initialize_gif_encoder(&mut output, 4, 4);
+            let mut pipeline = FramePipeline::spawn(scope, encoder, FrameFormat::new(4, 4), &palette, &cache);
+            for (shade, delay) in [(0u32, 50), (0, 10), (0, 10), (1, 10), (1, 10), (2, 60000), (2, 10000)] {
+                assert!(pipeline.submit(vec![0xFF000000 | ((shade * 64) * 0x010101); 16], delay));
+            }
+            pipeline.finish().unwrap()
+        });
+
+        let mut decoder = gif::DecodeOptions::new().read_info(output.as_slice()).unwrap();
+        let mut delays = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            delays.push(frame.delay);
+        }
+
+        assert_eq!(written, 4);
+        assert_eq!(delays, vec![70, 20, 60000, 10000]);
+    }
+
+    #[test]
+    fn test_a_panicking_worker_releases_the_worker_waiting_for_its_frame() {
+        let quantized = QuantizedFrames::default();
+
+        let previous = thread::scope(|scope| {
+            let waiter = scope.spawn(|| quantized.take(0));
+            let panicked = scope.spawn(|| {
+                let _fail_on_panic = FailOnPanic { quantized: &quantized, index: 0 };
+                panic!("quantizing failed");
+            });
+            assert!(panicked.join().is_err());
+            waiter.join().unwrap()
+        });
+
+        assert_eq!(previous, None);
+    }
+}
@@ -1,19 +1,62 @@
 use crate::state::structs::State;
 
-/// Renders the pixel buffer to the screen or scales it for headless mode.
+/// Presents the pixel buffer on the state's render target.
 ///
 /// # Parameters
 /// - `game_state`: A mutable reference to the `State` struct containing the game state.
-/// - `headless`: A boolean indicating whether the rendering is done in headless mode.
 ///
-/// In headless mode, the buffer is scaled but not displayed. Otherwise, the scaled buffer is drawn onto the window.
+/// The buffer already holds the frame at the size of the viewport, with the layers scaled to it when
+/// the state was created, so it is presented without further scaling. A window draws it on screen,
+/// while an offscreen target keeps it in memory.
 pub fn render_pixel_buffer(state: &mut State) {
+    state
+        .target
+        .present(state.window_buffer, state.window_width, state.window_height)
+        .unwrap_or_else(|e| panic!("Failed to present the frame: {}", e));
+}
 
-    if !state.headless {
-        // Ensure the window is initialized
-        let window = state.window.as_mut().expect("Window should be initialized");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphics::parallax::ParallaxLayer;
+    use crate::graphics::render_target::OffscreenTarget;
+    use crate::graphics::sprites::{SpriteFrame, SpriteMaps};
+    use crate::graphics::update_graphics::update_pixel_buffer;
+    use crate::state::structs::Camera;
+    use crate::state::timeline::Timeline;
 
-        // Draw the scaled buffer onto the window
-        window.update_with_buffer(&state.window_buffer, state.window_width, state.window_height).unwrap();
+    #[test]
+    fn test_frames_render_and_present_without_a_display() {
+        let frames = |color: u32| vec![SpriteFrame::new(4, 4, vec![color; 16])];
+        let mut window_buffer = vec![0; 16];
+        let mut target = OffscreenTarget::default();
+        let mut state = State {
+            target_date: chrono::NaiveDate::default(),
+            camera: Camera::new(0.0, 0.0),
+            camera_path: None,
+            frame_index: 0,
+            timeline: Timeline::default(),
+            time: 0.0,
+            sprites: SpriteMaps { layer_1: frames(0xFF336699), layer_2: frames(0), layer_3: frames(0), layer_4: frames(0), scene_width: 4, scene_height: 4 },
+            layers: vec![ParallaxLayer::new("sky", 0, 0.5)],
+            particles: Vec::new(),
+            actor: None,
+            color_grade: None,
+            text: Vec::new(),
+            window_buffer: &mut window_buffer,
+            window_width: 4,
+            window_height: 4,
+            render_scale: 1.0,
+            target: &mut target,
+            prompt: "",
+            color_map: None,
+            color_to_index_map: None,
+        };
+
+        update_pixel_buffer(&mut state);
+        render_pixel_buffer(&mut state);
+
+        assert!(state.window_buffer.iter().all(|&pixel| pixel == 0xFF336699));
+        assert_eq!(target.presented(), 1);
     }
-}
\ No newline at end of file
+}
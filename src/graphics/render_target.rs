@@ -0,0 +1,160 @@
+use std::error::Error;
+
+/// A request from the keyboard while previewing a scene.
+#[cfg(any(feature = "window", test))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreviewInput {
+    /// Pauses or resumes playback, with Space.
+    TogglePause,
+    /// Moves the playback back by a frame, with the left arrow.
+    ScrubBackward,
+    /// Moves the playback forward by a frame, with the right arrow.
+    ScrubForward,
+    /// Doubles the playback speed, with plus.
+    Faster,
+    /// Halves the playback speed, with minus.
+    Slower,
+    /// Hides or shows a layer by its index in `State::layers`, with the number keys from 1.
+    ToggleLayer(usize),
+    /// Switches the scroll factors to the next set of divisors, with D.
+    CycleDivisors,
+    /// Saves the current layers as a JSON file for `--layers`, with S.
+    SaveSettings,
+    /// Records a GIF from the current settings, with G.
+    ExportGif,
+}
+
+/// Where the rendered frames are shown while they are recorded.
+///
+/// The renderer only draws into the window buffer, so a target decides whether a frame is put
+/// on screen or kept in memory, which keeps windowing libraries out of headless builds.
+pub trait RenderTarget {
+    /// Whether the target still accepts frames, which ends the recording early once it does not.
+    fn is_open(&self) -> bool;
+
+    /// Shows a rendered frame.
+    ///
+    /// # Arguments
+    /// * `buffer` - The pixels of the frame in ARGB format, row by row.
+    /// * `width` - The width of the frame in pixels.
+    /// * `height` - The height of the frame in pixels.
+    fn present(&mut self, buffer: &[u32], width: usize, height: usize) -> Result<(), Box<dyn Error>>;
+
+    /// The preview requests made since the last presented frame. Targets without a keyboard make none.
+    #[cfg(feature = "window")]
+    fn poll_input(&mut self) -> Vec<PreviewInput> {
+        Vec::new()
+    }
+}
+
+/// A target without a display, which checks and counts the presented frames.
+///
+/// Used in headless mode and by tests, so rendering works on machines without a display. The
+/// frames themselves stay in the window buffer, where the recording reads them.
+#[derive(Debug, Default)]
+pub struct OffscreenTarget {
+    presented: usize,
+}
+
+impl OffscreenTarget {
+    /// The number of frames presented so far.
+    pub fn presented(&self) -> usize {
+        self.presented
+    }
+}
+
+impl RenderTarget for OffscreenTarget {
+    fn is_open(&self) -> bool {
+        true
+    }
+
+    fn present(&mut self, buffer: &[u32], width: usize, height: usize) -> Result<(), Box<dyn Error>> {
+        if buffer.len() != width * height {
+            return Err(format!("A {}x{} frame needs {} pixels, but {} were presented.", width, height, width * height, buffer.len()).into());
+        }
+        self.presented += 1;
+        Ok(())
+    }
+}
+
+/// A minifb window showing the frames as they are rendered, closed with Escape.
+#[cfg(feature = "window")]
+pub struct WindowTarget {
+    window: minifb::Window,
+}
+
+#[cfg(feature = "window")]
+impl WindowTarget {
+    /// Opens a window the size of the frames.
+    ///
+    /// # Arguments
+    /// * `title` - The title of the window.
+    /// * `width` - The width of the window in pixels.
+    /// * `height` - The height of the window in pixels.
+    ///
+    /// # Returns
+    /// The `WindowTarget`, or an error if the window cannot be opened.
+    pub fn new(title: &str, width: usize, height: usize) -> Result<Self, Box<dyn Error>> {
+        let window = minifb::Window::new(title, width, height, minifb::WindowOptions::default())?;
+        Ok(Self { window })
+    }
+}
+
+#[cfg(feature = "window")]
+impl RenderTarget for WindowTarget {
+    fn is_open(&self) -> bool {
+        self.window.is_open() && !self.window.is_key_down(minifb::Key::Escape)
+    }
+
+    fn present(&mut self, buffer: &[u32], width: usize, height: usize) -> Result<(), Box<dyn Error>> {
+        Ok(self.window.update_with_buffer(buffer, width, height)?)
+    }
+
+    fn poll_input(&mut self) -> Vec<PreviewInput> {
+        use minifb::{Key, KeyRepeat};
+
+        // The arrows repeat while held to scrub continuously, the other keys act once per press
+        self.window
+            .get_keys_pressed(KeyRepeat::Yes)
+            .into_iter()
+            .filter_map(|key| match key {
+                Key::Left => Some(PreviewInput::ScrubBackward),
+                Key::Right => Some(PreviewInput::ScrubForward),
+                _ if !self.window.is_key_pressed(key, KeyRepeat::No) => None,
+                Key::Space => Some(PreviewInput::TogglePause),
+                Key::Equal | Key::NumPadPlus => Some(PreviewInput::Faster),
+                Key::Minus | Key::NumPadMinus => Some(PreviewInput::Slower),
+                Key::D => Some(PreviewInput::CycleDivisors),
+                Key::S => Some(PreviewInput::SaveSettings),
+                Key::G => Some(PreviewInput::ExportGif),
+                Key::Key1 => Some(PreviewInput::ToggleLayer(0)),
+                Key::Key2 => Some(PreviewInput::ToggleLayer(1)),
+                Key::Key3 => Some(PreviewInput::ToggleLayer(2)),
+                Key::Key4 => Some(PreviewInput::ToggleLayer(3)),
+                Key::Key5 => Some(PreviewInput::ToggleLayer(4)),
+                Key::Key6 => Some(PreviewInput::ToggleLayer(5)),
+                Key::Key7 => Some(PreviewInput::ToggleLayer(6)),
+                Key::Key8 => Some(PreviewInput::ToggleLayer(7)),
+                Key::Key9 => Some(PreviewInput::ToggleLayer(8)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offscreen_target_counts_frames_of_the_right_size() {
+        let mut target = OffscreenTarget::default();
+
+        target.present(&[1, 2, 3, 4], 2, 2).unwrap();
+        target.present(&[5, 6, 7, 8], 2, 2).unwrap();
+
+        assert!(target.is_open());
+        assert_eq!(target.presented(), 2);
+        assert!(target.present(&[1, 2, 3], 2, 2).is_err());
+    }
+}
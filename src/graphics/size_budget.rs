@@ -0,0 +1,252 @@
+use crate::graphics::color::extract_palette_from_pixels;
+use crate::graphics::gif::{initialize_gif_encoder, FrameFormat, GifPalette, PixelIndexCache};
+use crate::graphics::output::split_delay;
+use crate::graphics::pipeline::FramePipeline;
+use crate::state::timeline::Timeline;
+use image::imageops::{resize, FilterType};
+use image::{ImageBuffer, Rgba};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::error::Error;
+use std::thread;
+use timing_macro::timed;
+
+/// One combination of the settings a GIF can give up in order to shrink.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EncodingSettings {
+    /// The maximum number of palette colors.
+    pub palette_size: usize,
+    /// Whether ordered dithering is applied, which softens banding from small palettes at the cost of size.
+    pub dither: bool,
+    /// The factor the rendered frames are scaled by.
+    pub scale: f32,
+    /// Only every `frame_step`-th frame is kept, and the delays grow to keep the duration.
+    pub frame_step: usize,
+}
+
+impl EncodingSettings {
+    const fn new(palette_size: usize, dither: bool, scale: f32, frame_step: usize) -> Self {
+        Self { palette_size, dither, scale, frame_step }
+    }
+}
+
+/// The settings tried when fitting a GIF into a size budget, from best to worst quality.
+///
+/// Each step gives up a little more than the one before it. The palette goes first since a
+/// smaller palette is barely noticeable on the muted DALL-E backgrounds, dithering is tried
+/// before dropping it to cover the banding, and resolution and frame rate go last.
+const SIZE_BUDGET_LADDER: [EncodingSettings; 12] = [
+    EncodingSettings::new(256, false, 1.0, 1),
+    EncodingSettings::new(128, true, 1.0, 1),
+    EncodingSettings::new(128, false, 1.0, 1),
+    EncodingSettings::new(64, true, 1.0, 1),
+    EncodingSettings::new(64, false, 1.0, 1),
+    EncodingSettings::new(64, false, 0.75, 1),
+    EncodingSettings::new(64, false, 0.75, 2),
+    EncodingSettings::new(32, false, 0.75, 2),
+    EncodingSettings::new(32, false, 0.5, 2),
+    EncodingSettings::new(16, false, 0.5, 2),
+    EncodingSettings::new(16, false, 0.5, 4),
+    EncodingSettings::new(16, false, 0.25, 4),
+];
+
+/// A GIF encoded in memory along with what it took to get there.
+pub struct FittedGif {
+    /// The encoded GIF file.
+    pub bytes: Vec<u8>,
+    /// The number of frames in the GIF.
+    pub frame_count: usize,
+    /// Human-readable descriptions of everything given up compared to a regular recording.
+    pub trade_offs: Vec<String>,
+}
+
+/// Encodes the rendered frames into the best-looking GIF that fits within `max_bytes`.
+///
+/// The frames are re-encoded in memory with the settings of `SIZE_BUDGET_LADDER`, one step
+/// at a time, until the result fits.
+///
+/// # Arguments
+/// * `frames` - The rendered ARGB frames, in order.
+/// * `width` - The width of the rendered frames in pixels.
+/// * `height` - The height of the rendered frames in pixels.
+/// * `base_palette` - The palette extracted from the source image, used while no smaller one is needed.
+/// * `timeline` - The clock the frames were rendered on.
+/// * `max_bytes` - The maximum size of the GIF file in bytes.
+///
+/// # Returns
+/// The first `FittedGif` within budget, or an error if even the smallest settings exceed it.
+#[timed]
+pub fn fit_gif_to_size(
+    frames: &[Vec<u32>],
+    width: u16,
+    height: u16,
+    base_palette: &GifPalette,
+    timeline: &Timeline,
+    max_bytes: u64,
+) -> Result<FittedGif, Box<dyn Error>> {
+    if frames.is_empty() {
+        return Err("No frames were rendered to fit into the size budget.".into());
+    }
+
+    let mut reduced_palettes: HashMap<usize, GifPalette> = HashMap::new();
+    let mut smallest_attempt = u64::MAX;
+
+    for settings in SIZE_BUDGET_LADDER {
+        let palette = match reduced_palettes.entry(settings.palette_size) {
+            _ if settings.palette_size >= base_palette.color_count() => base_palette,
+            Entry::Occupied(entry) => &*entry.into_mut(),
+            Entry::Vacant(entry) => {
+                // Reduced palettes are clustered from the first frame, which shows every layer
+                let (color_map, index_map) = extract_palette_from_pixels(&frames[0], settings.palette_size)?;
+                &*entry.insert(GifPalette::new(Some(&color_map), Some(&index_map))?)
+            }
+        };
+
+        let (bytes, frame_count) = encode_gif_in_memory(frames, width, height, palette, timeline, settings)?;
+
+        if bytes.len() as u64 <= max_bytes {
+            let trade_offs = describe_trade_offs(settings, palette.color_count(), base_palette.color_count(), width, height, frames.len());
+            return Ok(FittedGif { bytes, frame_count, trade_offs });
+        }
+
+        smallest_attempt = smallest_attempt.min(bytes.len() as u64);
+    }
+
+    Err(format!("Could not fit the GIF into {} bytes, the smallest attempt was {} bytes.", max_bytes, smallest_attempt).into())
+}
+
+/// Encodes the frames into an in-memory GIF with the given settings.
+///
+/// # Returns
+/// The GIF file contents and the number of frames written.
+fn encode_gif_in_memory(
+    frames: &[Vec<u32>],
+    width: u16,
+    height: u16,
+    palette: &GifPalette,
+    timeline: &Timeline,
+    settings: EncodingSettings,
+) -> Result<(Vec<u8>, usize), Box<dyn Error>> {
+    let (scaled_width, scaled_height) = scaled_dimensions(width, height, settings.scale);
+    let format = FrameFormat {
+        width: scaled_width,
+        height: scaled_height,
+        dither: settings.dither,
+    };
+
+    let pixel_index_cache = PixelIndexCache::default();
+    let mut bytes = Vec::new();
+
+    let frame_count = thread::scope(|scope| {
+        let encoder = initialize_gif_encoder(&mut bytes, scaled_width, scaled_height);
+        let mut pipeline = FramePipeline::spawn(scope, encoder, format, palette, &pixel_index_cache);
+
+        'frames: for (index, frame) in frames.iter().enumerate().step_by(settings.frame_step) {
+            // A kept frame is shown for as long as the frames it stands in for together, split
+            // evenly over copies of it when that is longer than a single GIF frame can be shown
+            let delay: u32 = (index..(index + settings.frame_step).min(frames.len())).map(|skipped| timeline.gif_delay_at(skipped) as u32).sum();
+            let scaled = scale_frame(frame, width, height, scaled_width, scaled_height);
+            for copy_delay in split_delay(delay) {
+                if !pipeline.submit(scaled.clone(), copy_delay) {
+                    break 'frames;
+                }
+            }
+        }
+
+        pipeline.finish()
+    })?;
+
+    Ok((bytes, frame_count))
+}
+
+/// Computes the frame dimensions after scaling, never going below one pixel.
+fn scaled_dimensions(width: u16, height: u16, scale: f32) -> (u16, u16) {
+    let scale_dimension = |dimension: u16| ((dimension as f32 * scale).round() as u16).max(1);
+    (scale_dimension(width), scale_dimension(height))
+}
+
+/// Resizes an ARGB frame, returning a plain copy when the size is unchanged.
+fn scale_frame(pixels: &[u32], width: u16, height: u16, scaled_width: u16, scaled_height: u16) -> Vec<u32> {
+    if (width, height) == (scaled_width, scaled_height) {
+        return pixels.to_vec();
+    }
+
+    let image = ImageBuffer::from_fn(width as u32, height as u32, |x, y| {
+        let pixel = pixels[y as usize * width as usize + x as usize];
+        Rgba([(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8, (pixel >> 24) as u8])
+    });
+
+    resize(&image, scaled_width as u32, scaled_height as u32, FilterType::Triangle)
+        .pixels()
+        .map(|pixel| {
+            let [r, g, b, a] = pixel.0;
+            ((a as u32) << 24) | ((r as u32) << 16) | ((g as u32) << 8) | b as u32
+        })
+        .collect()
+}
+
+/// Lists what the chosen settings give up compared to a regular recording.
+fn describe_trade_offs(
+    settings: EncodingSettings,
+    palette_size: usize,
+    base_palette_size: usize,
+    width: u16,
+    height: u16,
+    frame_count: usize,
+) -> Vec<String> {
+    let mut trade_offs = Vec::new();
+
+    if palette_size < base_palette_size {
+        trade_offs.push(format!("palette reduced from {} to {} colors", base_palette_size, palette_size));
+    }
+
+    if settings.dither {
+        trade_offs.push("ordered dithering added to soften the smaller palette".to_string());
+    }
+
+    let (scaled_width, scaled_height) = scaled_dimensions(width, height, settings.scale);
+    if (scaled_width, scaled_height) != (width, height) {
+        trade_offs.push(format!("resolution reduced from {}x{} to {}x{}", width, height, scaled_width, scaled_height));
+    }
+
+    if settings.frame_step > 1 {
+        let kept_frames = frame_count.div_ceil(settings.frame_step);
+        trade_offs.push(format!("frames reduced from {} to {}, each shown {} times as long", frame_count, kept_frames, settings.frame_step));
+    }
+
+    trade_offs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ladder_only_ever_gives_up_quality() {
+        for pair in SIZE_BUDGET_LADDER.windows(2) {
+            let (better, worse) = (pair[0], pair[1]);
+            assert!(worse.palette_size <= better.palette_size);
+            assert!(worse.scale <= better.scale);
+            assert!(worse.frame_step >= better.frame_step);
+            assert_ne!(better, worse);
+        }
+    }
+
+    #[test]
+    fn test_trade_offs_describe_every_reduction() {
+        let settings = EncodingSettings::new(64, true, 0.5, 2);
+
+        let trade_offs = describe_trade_offs(settings, 64, 255, 1024, 1024, 10);
+
+        assert_eq!(
+            trade_offs,
+            vec![
+                "palette reduced from 255 to 64 colors",
+                "ordered dithering added to soften the smaller palette",
+                "resolution reduced from 1024x1024 to 512x512",
+                "frames reduced from 10 to 5, each shown 2 times as long",
+            ]
+        );
+        assert!(describe_trade_offs(SIZE_BUDGET_LADDER[0], 255, 255, 1024, 1024, 10).is_empty());
+    }
+}
@@ -0,0 +1,205 @@
+use crate::graphics::output::{argb_to_rgba, frame_durations, AnimationWriter};
+use crate::graphics::sprites::SpriteFrame;
+use crate::state::timeline::Timeline;
+use image::{ColorType, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// Describes where each frame of a sprite sheet is and how long it is shown.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct SpriteAtlas {
+    /// The file name of the sprite sheet image, relative to the atlas.
+    pub image: String,
+    /// The width of the sprite sheet in pixels.
+    pub width: u32,
+    /// The height of the sprite sheet in pixels.
+    pub height: u32,
+    /// The width of every frame in pixels.
+    pub frame_width: u32,
+    /// The height of every frame in pixels.
+    pub frame_height: u32,
+    /// The number of frames per row.
+    pub columns: u32,
+    /// The number of rows of frames.
+    pub rows: u32,
+    /// The number of frames, which leaves the cells after them in the last row empty.
+    pub frame_count: u32,
+    /// The frames in playback order.
+    pub frames: Vec<AtlasFrame>,
+}
+
+/// The position and duration of a single frame within a sprite sheet.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct AtlasFrame {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    /// How long the frame is shown, in milliseconds.
+    pub duration: u32,
+}
+
+/// Writes every frame as a numbered PNG as soon as it is rendered.
+pub struct FrameSequenceWriter {
+    directory: String,
+    width: u32,
+    height: u32,
+    written: usize,
+}
+
+impl FrameSequenceWriter {
+    /// Creates the directory the frames are written to.
+    pub fn new(directory: String, width: u32, height: u32) -> Result<Self, Box<dyn Error>> {
+        fs::create_dir_all(&directory)?;
+        Ok(Self { directory, width, height, written: 0 })
+    }
+}
+
+impl AnimationWriter for FrameSequenceWriter {
+    fn write_frame(&mut self, pixels: &[u32]) -> Result<(), Box<dyn Error>> {
+        self.written += 1;
+        let path = format!("{}/frame_{:04}.png", self.directory, self.written);
+        image::save_buffer(path, &argb_to_rgba(pixels), self.width, self.height, ColorType::Rgba8)?;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<String, Box<dyn Error>> {
+        Ok(self.directory)
+    }
+}
+
+/// Collects the frames and packs them into a sprite sheet with a JSON atlas next to it.
+///
+/// The frames are laid out the way `try_load_sprites_from_map` reads them, left to right and then
+/// top to bottom in cells of the frame size, so the sheet can be loaded back as a `Vec<SpriteFrame>`
+/// and truncated to the atlas' frame count.
+pub struct SpriteSheetWriter {
+    path: String,
+    width: u32,
+    height: u32,
+    timeline: Timeline,
+    frames: Vec<SpriteFrame>,
+}
+
+impl SpriteSheetWriter {
+    pub fn new(path: String, width: u32, height: u32, timeline: Timeline) -> Self {
+        Self { path, width, height, timeline, frames: Vec::new() }
+    }
+}
+
+impl AnimationWriter for SpriteSheetWriter {
+    fn write_frame(&mut self, pixels: &[u32]) -> Result<(), Box<dyn Error>> {
+        self.frames.push(SpriteFrame::new(self.width, self.height, pixels.to_vec()));
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<String, Box<dyn Error>> {
+        let image_name = Path::new(&self.path).file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let (sheet, atlas) = pack_sprite_sheet(&self.frames, image_name, &frame_durations(&self.timeline, self.frames.len()))?;
+
+        sheet.save(&self.path)?;
+        fs::write(Path::new(&self.path).with_extension("json"), serde_json::to_string_pretty(&atlas)?)?;
+
+        Ok(self.path)
+    }
+}
+
+/// Packs equally sized frames into a grid and describes them in an atlas.
+///
+/// The number of columns is the square root of the frame count, rounded up, which keeps the sheet
+/// close to square for any frame count. Cells after the last frame are left transparent.
+///
+/// # Arguments
+/// * `frames` - The frames in playback order, all the size of the first one.
+/// * `image_name` - The file name of the sprite sheet, recorded in the atlas.
+/// * `durations` - How long each frame is shown, in milliseconds.
+///
+/// # Returns
+/// The sprite sheet image and its atlas, or an error if there are no frames, their sizes differ or
+/// there is not one duration per frame.
+pub fn pack_sprite_sheet(frames: &[SpriteFrame], image_name: &str, durations: &[u32]) -> Result<(RgbaImage, SpriteAtlas), Box<dyn Error>> {
+    let first = frames.first().ok_or("A sprite sheet needs at least one frame.")?;
+    let (frame_width, frame_height) = (first.width, first.height);
+    if frames.iter().any(|frame| (frame.width, frame.height) != (frame_width, frame_height)) {
+        return Err("Every frame of a sprite sheet must have the same size.".into());
+    }
+    if durations.len() != frames.len() {
+        return Err(format!("A sprite sheet of {} frames was given {} durations.", frames.len(), durations.len()).into());
+    }
+
+    let count = frames.len() as u32;
+    let columns = count.isqrt() + u32::from(count.isqrt().pow(2) < count);
+    let rows = count.div_ceil(columns);
+
+    let mut sheet = RgbaImage::new(columns * frame_width, rows * frame_height);
+    let mut atlas_frames = Vec::with_capacity(frames.len());
+
+    for (index, (frame, &duration)) in frames.iter().zip(durations).enumerate() {
+        let (x, y) = ((index as u32 % columns) * frame_width, (index as u32 / columns) * frame_height);
+
+        for (offset, pixel) in argb_to_rgba(frame.pixels()).chunks_exact(4).enumerate() {
+            let offset = offset as u32;
+            sheet.put_pixel(x + offset % frame_width, y + offset / frame_width, image::Rgba([pixel[0], pixel[1], pixel[2], pixel[3]]));
+        }
+
+        atlas_frames.push(AtlasFrame { x, y, width: frame_width, height: frame_height, duration });
+    }
+
+    let atlas = SpriteAtlas {
+        image: image_name.to_string(),
+        width: sheet.width(),
+        height: sheet.height(),
+        frame_width,
+        frame_height,
+        columns,
+        rows,
+        frame_count: count,
+        frames: atlas_frames,
+    };
+
+    Ok((sheet, atlas))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphics::sprites::try_load_sprites_from_map;
+
+    #[test]
+    fn test_sprite_sheet_loads_back_with_try_load_sprites_from_map() {
+        let (width, height) = (3, 2);
+        let frames: Vec<SpriteFrame> = (0..6u32)
+            .map(|i| SpriteFrame::new(width, height, (0..width * height).map(|p| 0xFF000000 | (i << 16) | p).collect()))
+            .collect();
+
+        let (sheet, atlas) = pack_sprite_sheet(&frames, "sheet.png", &[100; 6]).unwrap();
+
+        assert_eq!((atlas.columns, atlas.rows), (3, 2));
+        assert_eq!((atlas.width, atlas.height), (9, 4));
+        assert_eq!(atlas.frames[4], AtlasFrame { x: 3, y: 2, width, height, duration: 100 });
+
+        let path = std::env::temp_dir().join(format!("sprite_sheet_test_{}.png", std::process::id()));
+        sheet.save(&path).unwrap();
+        let loaded = try_load_sprites_from_map(path.to_str().unwrap(), atlas.frame_width, atlas.frame_height).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.len(), frames.len());
+        for (loaded, original) in loaded.iter().zip(&frames) {
+            assert_eq!(loaded.pixels(), original.pixels());
+        }
+    }
+
+    #[test]
+    fn test_prime_frame_counts_stay_near_square() {
+        let frames: Vec<SpriteFrame> = (0..211).map(|_| SpriteFrame::new(2, 2, vec![0xFF000000; 4])).collect();
+
+        let (sheet, atlas) = pack_sprite_sheet(&frames, "sheet.png", &[100; 211]).unwrap();
+
+        assert_eq!((atlas.columns, atlas.rows, atlas.frame_count), (15, 15, 211));
+        assert_eq!((sheet.width(), sheet.height()), (30, 30));
+        assert_eq!(atlas.frames[210], AtlasFrame { x: 0, y: 28, width: 2, height: 2, duration: 100 });
+        assert!(pack_sprite_sheet(&frames, "sheet.png", &[100; 210]).is_err());
+    }
+}
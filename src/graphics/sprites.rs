@@ -1,5 +1,9 @@
 use chrono::NaiveDate;
-use image::GenericImageView;
+use image::imageops::{self, FilterType};
+use image::{GenericImageView, Rgba32FImage};
+use rayon::prelude::*;
+use std::error::Error;
+use crate::graphics::compositing::{composite, BlendMode, PremultipliedColor};
 
 pub struct SpriteFrame {
     pub width: u32,  // Width of the sprite in pixels
@@ -8,9 +12,14 @@ pub struct SpriteFrame {
 }
 
 impl SpriteFrame {
-    fn new(width: u32, height: u32, data: Vec<u32>) -> Self {
+    pub fn new(width: u32, height: u32, data: Vec<u32>) -> Self {
         Self { width, height, data }
     }
+
+    /// The pixel data of the sprite, row by row.
+    pub fn pixels(&self) -> &[u32] {
+        &self.data
+    }
 }
 
 pub struct SpriteMaps {
@@ -18,19 +27,141 @@ pub struct SpriteMaps {
     pub layer_2: Vec<SpriteFrame>,
     pub layer_3: Vec<SpriteFrame>,
     pub layer_4: Vec<SpriteFrame>,
+    pub scene_width: u32,  // Width of a layer frame as loaded, before any scaling to the viewport
+    pub scene_height: u32, // Height of a layer frame as loaded, before any scaling to the viewport
 }
 
 impl SpriteMaps {
+    /// Loads the four layer images of a date at whatever size they were created.
+    ///
+    /// The frame size is the smallest width and height among the layer images, so a layer image
+    /// holding several frames side by side or stacked is loaded as an animated layer with one
+    /// frame per tile, while the still layers give the size of the scene.
     pub fn new(target_date: NaiveDate) -> Self {
-        Self {
-            layer_1: load_sprites_from_map(format!("layers/1/layer_{}.png", target_date).as_str(), 1024, 1024),
-            layer_2: load_sprites_from_map(format!("layers/2/layer_{}.png", target_date).as_str(), 1024, 1024),
-            layer_3: load_sprites_from_map(format!("layers/3/layer_{}.png", target_date).as_str(), 1024, 1024),
-            layer_4: load_sprites_from_map(format!("layers/4/layer_{}.png", target_date).as_str(), 1024, 1024),
+        Self::load(target_date).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Loads the four layer images of a date like `new`, returning an error instead of panicking
+    /// when an image is missing or cannot be decoded, for example while it is still being written.
+    pub fn load(target_date: NaiveDate) -> Result<Self, Box<dyn Error>> {
+        let (scene_width, scene_height) = Self::scene_size(target_date)?;
+        let mut layers = Self::layer_paths(target_date)
+            .into_iter()
+            .map(|path| try_load_sprites_from_map(&path, scene_width, scene_height))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter();
+
+        Ok(Self {
+            layer_1: layers.next().unwrap_or_default(),
+            layer_2: layers.next().unwrap_or_default(),
+            layer_3: layers.next().unwrap_or_default(),
+            layer_4: layers.next().unwrap_or_default(),
+            scene_width,
+            scene_height,
+        })
+    }
+
+    /// The paths of the four layer images of a date, back to front.
+    pub fn layer_paths(target_date: NaiveDate) -> Vec<String> {
+        (1..=4).map(|layer| format!("layers/{}/layer_{}.png", layer, target_date)).collect()
+    }
+
+    /// The size of the scene, which is the smallest width and height among the layer images of a date.
+    ///
+    /// Only the headers of the images are read, so the size can be known before the layers are loaded.
+    ///
+    /// # Returns
+    /// The width and height, or an error if an image cannot be opened.
+    pub fn scene_size(target_date: NaiveDate) -> Result<(u32, u32), Box<dyn Error>> {
+        Self::layer_paths(target_date).iter().try_fold((u32::MAX, u32::MAX), |(width, height), path| {
+            let (w, h) = image::image_dimensions(path).map_err(|e| format!("Failed to open sprite map at {}: {}", path, e))?;
+            Ok((width.min(w), height.min(h)))
+        })
+    }
+
+    /// Resamples the frames of every layer by a factor, keeping `scene_width` and `scene_height`
+    /// at the size the layers were loaded with.
+    ///
+    /// # Parameters
+    /// - `scale`: The factor the frames are scaled by, where 1.0 leaves them unchanged.
+    pub fn scale(&mut self, scale: f32) {
+        for frames in [&mut self.layer_1, &mut self.layer_2, &mut self.layer_3, &mut self.layer_4] {
+            *frames = frames.iter().map(|frame| scale_frame(frame, scale)).collect();
+        }
+    }
+
+    /// The frames of a layer by its index, from 0 for `layer_1` to 3 for `layer_4`.
+    pub fn layer(&self, index: usize) -> Option<&[SpriteFrame]> {
+        match index {
+            0 => Some(&self.layer_1),
+            1 => Some(&self.layer_2),
+            2 => Some(&self.layer_3),
+            3 => Some(&self.layer_4),
+            _ => None,
+        }
+    }
+
+    /// The frames of a layer by its index, for replacing them with generated animation frames.
+    pub fn layer_mut(&mut self, index: usize) -> Option<&mut Vec<SpriteFrame>> {
+        match index {
+            0 => Some(&mut self.layer_1),
+            1 => Some(&mut self.layer_2),
+            2 => Some(&mut self.layer_3),
+            3 => Some(&mut self.layer_4),
+            _ => None,
         }
     }
 }
 
+/// Resamples a sprite frame by a factor with a Lanczos filter.
+///
+/// The pixels are premultiplied by their alpha while filtering, so the transparent pixels around
+/// a layer's band do not bleed dark fringes into its edges. The filter overshoots next to hard
+/// edges and the resize clamps every channel on its own, so the channels are filtered at half
+/// their value to keep the overshoot from being clipped. A factor of 1.0 copies the frame.
+///
+/// # Parameters
+/// - `frame`: The frame to resample.
+/// - `scale`: The factor the width and height are multiplied by.
+///
+/// # Returns
+/// The resampled frame, at least one pixel wide and tall.
+pub fn scale_frame(frame: &SpriteFrame, scale: f32) -> SpriteFrame {
+    if scale == 1.0 {
+        return SpriteFrame::new(frame.width, frame.height, frame.data.clone());
+    }
+
+    let premultiplied: Vec<f32> = frame.data.iter().flat_map(|&pixel| {
+        let color = PremultipliedColor::from_argb(pixel);
+        [color.red, color.green, color.blue, color.alpha].map(|channel| channel * 0.5)
+    }).collect();
+    let image = Rgba32FImage::from_raw(frame.width, frame.height, premultiplied).expect("Frame data should match its size");
+
+    let width = ((frame.width as f32 * scale).round() as u32).max(1);
+    let height = ((frame.height as f32 * scale).round() as u32).max(1);
+    let resized = imageops::resize(&image, width, height, FilterType::Lanczos3);
+
+    let data = resized.pixels().map(|pixel| {
+        let [red, green, blue, alpha] = pixel.0;
+        PremultipliedColor { alpha, red, green, blue }.scale(2.0).to_argb()
+    }).collect();
+    SpriteFrame::new(width, height, data)
+}
+
+/// Selects the frame of a looping animation shown at a point on the timeline.
+///
+/// # Parameters
+/// - `frames`: The animation frames, which must not be empty.
+/// - `frames_per_second`: How many animation frames are played per second.
+/// - `time`: The time of the rendered frame on the timeline, in seconds.
+///
+/// # Returns
+/// The animation frame to draw.
+pub fn animation_frame(frames: &[SpriteFrame], frames_per_second: f32, time: f32) -> &SpriteFrame {
+    let animation_frame = (time * frames_per_second).floor() as usize;
+    &frames[animation_frame % frames.len()]
+}
+
 /// Loads sprites from a sprite map image file into memory.
 ///
 /// Opens the image file specified by `sprite_map_path`, extracts individual
@@ -42,10 +173,10 @@ impl SpriteMaps {
 /// - `sprite_height`: The height of each individual sprite in pixels.
 ///
 /// # Returns
-/// A vector containing tuples of sprite dimensions and pixel data.
-pub fn load_sprites_from_map(sprite_map_path: &str, sprite_width: u32, sprite_height: u32) -> Vec<SpriteFrame> {
+/// A vector of the sprites, or an error naming the sprite map.
+pub fn try_load_sprites_from_map(sprite_map_path: &str, sprite_width: u32, sprite_height: u32) -> Result<Vec<SpriteFrame>, Box<dyn Error>> {
     // Load the sprite map image
-    let sprite_map = image::open(sprite_map_path).expect(&format!("Failed to open sprite map at {}", sprite_map_path));
+    let sprite_map = image::open(sprite_map_path).map_err(|e| format!("Failed to open sprite map at {}: {}", sprite_map_path, e))?;
     let (map_width, map_height) = sprite_map.dimensions();
 
     println!("Sprite map loaded from {}", sprite_map_path);
@@ -74,7 +205,7 @@ pub fn load_sprites_from_map(sprite_map_path: &str, sprite_width: u32, sprite_he
     println!("Total sprites extracted: {}\n", sprites.len());
 
     // Return the vector of sprites
-    sprites
+    Ok(sprites)
 }
 
 /// Converts an image to a buffer of u32 pixels in ARGB format.
@@ -96,75 +227,197 @@ pub fn img_to_buffer(img: &image::DynamicImage) -> Vec<u32> {
     }).collect()
 }
 
-/// Draws a sprite onto the window buffer at the specified coordinates, with alpha blending.
+/// Where and how large a sprite is drawn by `draw_sprite_resampled`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpriteTransform {
+    /// How far the sprite is scrolled to the left, in pixels. Fractions are resampled.
+    pub offset_x: f32,
+    /// How far the sprite is moved down, in pixels. Fractions are resampled.
+    pub offset_y: f32,
+    /// The factor the sprite is scaled by around the focal point.
+    pub scale: f32,
+    /// The x-coordinate in the window that scaling is centered on.
+    pub focus_x: f32,
+    /// The y-coordinate in the window that scaling is centered on.
+    pub focus_y: f32,
+    /// Whether the sprite wraps around horizontally instead of ending at its edges.
+    pub repeat_x: bool,
+    /// Whether every other horizontal repeat is mirrored, so neighbouring repeats meet without a seam.
+    pub mirror_x: bool,
+    /// Whether the sprite wraps around vertically instead of ending at its edges.
+    pub repeat_y: bool,
+}
+
+/// Draws a sprite at a sub-pixel offset and scale, with bilinear resampling and a blend mode.
+///
+/// Every window pixel is mapped back to a fractional position in the sprite, and the four sprite
+/// pixels around it are blended by their distance. Colors are weighted by alpha while blending
+/// so the transparent pixels around a layer do not darken its edges, and the premultiplied result
+/// is composited onto the window with `composite`. Rows are drawn in parallel.
 ///
 /// # Parameters
-/// - `x`: The x-coordinate where the sprite will be drawn.
-/// - `y`: The y-coordinate where the sprite will be drawn.
-/// - `sprite`: A tuple containing the sprite's width, height, and pixel data. The pixel data is a vector of `u32` values representing RGBA colors.
-/// - `window_buffer`: A mutable slice of `u32` representing the pixels of the window buffer. Each `u32` value represents an RGBA color.
+/// - `sprite`: The sprite to draw.
+/// - `transform`: The offset, scale, focal point and repetition to draw the sprite with.
+/// - `opacity`: The factor the sprite's alpha is multiplied by, from 0.0 to 1.0.
+/// - `tint`: The RGB color the sprite's colors are multiplied by, where white leaves them unchanged.
+/// - `blend`: How the sprite's colors are combined with the pixels already in the window.
+/// - `window_buffer`: A mutable slice of `u32` representing the pixels of the window buffer in ARGB format.
 /// - `window_width`: The width of the window in pixels.
-///
-/// This function uses alpha blending to combine the sprite's pixels with the corresponding pixels in the window buffer. Only non-transparent pixels in the sprite are drawn.
-///
-/// # Alpha Blending
-/// Alpha blending is a process used in computer graphics to combine a foreground image with a background image, resulting in a composite image.
-/// The alpha value determines the transparency level of the pixel:
-/// - An alpha value of 0 means the pixel is completely transparent.
-/// - An alpha value of 255 (0xFF) means the pixel is completely opaque.
-///
-/// The formula for alpha blending is:
-/// ```
-/// blended_color = (foreground_color * alpha + background_color * (255 - alpha)) / 255
-/// ```
-///
-/// # ARGB Color Palette
-/// Each `u32` value in the pixel data represents a color in ARGB format:
-/// - The highest 8 bits represent the alpha (transparency) channel.
-/// - The next 8 bits represent the red channel.
-/// - The next 8 bits represent the green channel.
-/// - The lowest 8 bits represent the blue channel.
-///
-/// For example, a color represented as `0x80FF00FF` means:
-/// - Alpha: 0x80 (128 in decimal, semi-transparent)
-/// - Red: 0xFF (255 in decimal, full intensity)
-/// - Green: 0x00 (0 in decimal, no intensity)
-/// - Blue: 0xFF (255 in decimal, full intensity)
-///
-/// # Example Usage
-/// ```rust
-/// let sprite = (16, 16, vec![0x80FF00FF; 256]); // A semi-transparent magenta 16x16 sprite
-/// let mut window_buffer = vec![0xFFFFFFFF; 800 * 600]; // A white 800x600 window buffer
-/// draw_sprite(10, 10, &sprite, &mut window_buffer, 800);
-/// ```
-pub fn draw_sprite(x: usize, y: usize, sprite: &SpriteFrame, window_buffer: &mut [u32], window_width: usize) {
-
-    for row in 0..sprite.height as usize {
-        for col in 0..sprite.width as usize {
-            let sprite_pixel_index = row * (sprite.width as usize) + col;
-            let window_pixel_index = (y + row) * window_width + (x + col);
-
-            if window_pixel_index < window_buffer.len() {
-                let sprite_pixel = sprite.data[sprite_pixel_index];
-                let sprite_alpha = (sprite_pixel >> 24) & 0xFF; // Extract alpha channel from sprite pixel
-                let sprite_rgb = sprite_pixel & 0x00FFFFFF; // Extract RGB channels from sprite pixel
-
-                if sprite_alpha > 0 { // Only blend if the pixel is not fully transparent
-                    let window_pixel = window_buffer[window_pixel_index];
-                    let window_rgb = window_pixel & 0x00FFFFFF; // Extract RGB channels from window buffer pixel
-
-                    // Calculate blended color using alpha blending formula
-                    let blended_r = ((sprite_rgb >> 16) & 0xFF) * sprite_alpha / 255 + ((window_rgb >> 16) & 0xFF) * (255 - sprite_alpha) / 255;
-                    let blended_g = ((sprite_rgb >> 8) & 0xFF) * sprite_alpha / 255 + ((window_rgb >> 8) & 0xFF) * (255 - sprite_alpha) / 255;
-                    let blended_b = (sprite_rgb & 0xFF) * sprite_alpha / 255 + (window_rgb & 0xFF) * (255 - sprite_alpha) / 255;
-
-                    // Combine blended color with full alpha
-                    let blended_pixel = 0xFF000000 | (blended_r & 0xFF) << 16 | (blended_g & 0xFF) << 8 | (blended_b & 0xFF);
-
-                    // Assign the blended pixel to the window buffer
-                    window_buffer[window_pixel_index] = blended_pixel;
+pub fn draw_sprite_resampled(
+    sprite: &SpriteFrame,
+    transform: SpriteTransform,
+    opacity: f32,
+    tint: [u8; 3],
+    blend: BlendMode,
+    window_buffer: &mut [u32],
+    window_width: usize,
+) {
+    let (sprite_width, sprite_height) = (sprite.width as usize, sprite.height as usize);
+    if sprite_width == 0 || sprite_height == 0 || transform.scale <= 0.0 || opacity <= 0.0 {
+        return;
+    }
+
+    // Maps a fractional sprite coordinate to its two neighbouring pixels and the weight of the second,
+    // leaving out neighbours that fall outside a sprite that does not repeat. A mirrored sprite repeats
+    // every two sprite sizes, with the second one running backwards.
+    let neighbours = |position: f32, size: usize, repeat: bool, mirror: bool| -> (Option<usize>, Option<usize>, f32) {
+        let period = if mirror { 2 * size } else { size };
+        let position = if repeat { position.rem_euclid(period as f32) } else { position };
+        let first = position.floor();
+        let index = |i: f32| match repeat {
+            true if mirror => Some(match i as usize % period {
+                i if i < size => i,
+                i => period - 1 - i,
+            }),
+            true => Some(i as usize % size),
+            false => (i >= 0.0 && i < size as f32).then_some(i as usize),
+        };
+        (index(first), index(first + 1.0), position - first)
+    };
+
+    // Every row samples the same columns, so the horizontal neighbours and weights are computed once
+    let columns: Vec<(Option<usize>, Option<usize>, f32)> = (0..window_width)
+        .map(|col| {
+            let u = transform.focus_x + (col as f32 + 0.5 - transform.focus_x) / transform.scale - 0.5 + transform.offset_x;
+            neighbours(u, sprite_width, transform.repeat_x, transform.mirror_x)
+        })
+        .collect();
+
+    // The tint and opacity are folded into one factor per channel, applied to the blended sample
+    let factors = [opacity.min(1.0), tint[0] as f32 / 255.0, tint[1] as f32 / 255.0, tint[2] as f32 / 255.0];
+
+    window_buffer.par_chunks_mut(window_width).enumerate().for_each(|(row, window_row)| {
+        let v = transform.focus_y + (row as f32 + 0.5 - transform.focus_y) / transform.scale - 0.5 - transform.offset_y;
+        let (upper, lower, weight_y) = neighbours(v, sprite_height, transform.repeat_y, false);
+        if upper.is_none() && lower.is_none() {
+            return;
+        }
+
+        let sprite_row = |y: Option<usize>| y.map(|y| &sprite.data[y * sprite_width..][..sprite_width]);
+        let rows = [(sprite_row(upper), 1.0 - weight_y), (sprite_row(lower), weight_y)];
+
+        for (window_pixel, &(left, right, weight_x)) in window_row.iter_mut().zip(&columns) {
+            let mut blended = [0.0f32; 4];
+            for (row_pixels, row_weight) in rows {
+                let Some(row_pixels) = row_pixels else { continue };
+                for (column, weight) in [(left, 1.0 - weight_x), (right, weight_x)] {
+                    let Some(column) = column else { continue };
+                    let pixel = row_pixels[column];
+                    let alpha = (pixel >> 24) as f32 * row_weight * weight;
+                    blended[0] += alpha;
+                    blended[1] += ((pixel >> 16) & 0xFF) as f32 * alpha;
+                    blended[2] += ((pixel >> 8) & 0xFF) as f32 * alpha;
+                    blended[3] += (pixel & 0xFF) as f32 * alpha;
                 }
             }
+
+            let alpha = blended[0] * factors[0];
+            if alpha < 0.5 {
+                continue;
+            }
+
+            // The blended colors are already premultiplied by alpha, from 0 to 255 squared
+            let source = PremultipliedColor {
+                alpha: blended[0] / 255.0,
+                red: blended[1] * factors[1] / (255.0 * 255.0),
+                green: blended[2] * factors[2] / (255.0 * 255.0),
+                blue: blended[3] * factors[3] / (255.0 * 255.0),
+            };
+            *window_pixel = composite(*window_pixel, source.scale(factors[0]), blend);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: [u8; 3] = [255, 255, 255];
+
+    fn transform(offset_x: f32, scale: f32) -> SpriteTransform {
+        SpriteTransform { offset_x, offset_y: 0.0, scale, focus_x: 2.0, focus_y: 0.5, repeat_x: true, mirror_x: false, repeat_y: false }
+    }
+
+    #[test]
+    fn test_resampled_sprite_blends_sub_pixel_offsets_and_wraps() {
+        let sprite = SpriteFrame::new(4, 1, vec![0xFF000000, 0xFFC8C8C8, 0xFF000000, 0xFF000000]);
+        let mut window = vec![0xFF000000; 4];
+
+        draw_sprite_resampled(&sprite, transform(0.0, 1.0), 1.0, WHITE, BlendMode::Normal, &mut window, 4);
+        assert_eq!(window, sprite.data);
+
+        draw_sprite_resampled(&sprite, transform(0.5, 1.0), 1.0, WHITE, BlendMode::Normal, &mut window, 4);
+        assert_eq!(window, vec![0xFF646464, 0xFF646464, 0xFF000000, 0xFF000000]);
+
+        draw_sprite_resampled(&sprite, transform(-1.0, 1.0), 1.0, WHITE, BlendMode::Normal, &mut window, 4);
+        assert_eq!(window, vec![0xFF000000, 0xFF000000, 0xFFC8C8C8, 0xFF000000]);
+    }
+
+    #[test]
+    fn test_mirrored_sprite_runs_backwards_on_every_other_repeat() {
+        let sprite = SpriteFrame::new(3, 1, vec![0xFF000001, 0xFF000002, 0xFF000003]);
+        let mut window = vec![0xFF000000; 8];
+        let mirrored = SpriteTransform { mirror_x: true, ..transform(0.0, 1.0) };
+
+        draw_sprite_resampled(&sprite, mirrored, 1.0, WHITE, BlendMode::Normal, &mut window, 8);
+
+        assert_eq!(window.iter().map(|pixel| pixel & 0xFF).collect::<Vec<_>>(), vec![1, 2, 3, 3, 2, 1, 1, 2]);
+    }
+
+    #[test]
+    fn test_resampled_sprite_scales_around_the_focal_point_and_keeps_transparency() {
+        let sprite = SpriteFrame::new(4, 1, vec![0x00000000, 0xFFFFFFFF, 0xFFFFFFFF, 0x00000000]);
+        let mut window = vec![0xFF000000; 4];
+
+        draw_sprite_resampled(&sprite, transform(0.0, 2.0), 1.0, WHITE, BlendMode::Normal, &mut window, 4);
+
+        assert_eq!(window[1..3], [0xFFFFFFFF, 0xFFFFFFFF]);
+        assert_eq!(window[0], 0xFFBFBFBF);
+    }
+
+    #[test]
+    fn test_resampled_sprite_applies_opacity_tint_and_edges() {
+        let sprite = SpriteFrame::new(2, 1, vec![0xFFC8C8C8, 0xFFC8C8C8]);
+        let mut window = vec![0xFF000000; 4];
+        let once = SpriteTransform { repeat_x: false, ..transform(-1.0, 1.0) };
+
+        draw_sprite_resampled(&sprite, once, 0.5, [255, 0, 128], BlendMode::Normal, &mut window, 4);
+
+        assert_eq!(window, vec![0xFF000000, 0xFF640032, 0xFF640032, 0xFF000000]);
+    }
+
+    #[test]
+    fn test_scaled_frames_keep_colors_without_dark_fringes() {
+        let band = SpriteFrame::new(4, 4, [vec![0x00000000; 8], vec![0xFFFF8000; 8]].concat());
+
+        let half = scale_frame(&band, 0.5);
+        let double = scale_frame(&band, 2.0);
+
+        assert_eq!((half.width, half.height, double.width, double.height), (2, 2, 8, 8));
+        assert_eq!(scale_frame(&SpriteFrame::new(4, 1, vec![0xFF336699; 4]), 0.5).pixels(), [0xFF336699, 0xFF336699]);
+        // The transparent rows above the band lower the alpha at its edge but never darken its color
+        for frame in [half, double] {
+            assert!(frame.pixels().iter().filter(|pixel| *pixel >> 24 > 0).all(|pixel| pixel & 0xFFFFFF == 0xFF8000));
         }
     }
 }
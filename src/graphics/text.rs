@@ -0,0 +1,423 @@
+use crate::graphics::compositing::{composite, BlendMode, PremultipliedColor};
+use crate::state::constants::graphics::{TEXT_MARGIN, TEXT_SIZE_DIVISOR};
+use crate::state::structs::State;
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+
+/// The width of a glyph in font pixels.
+const GLYPH_WIDTH: usize = 5;
+/// The height of a glyph in font pixels.
+const GLYPH_HEIGHT: usize = 7;
+/// The font pixels between two lines of text.
+const LINE_GAP: usize = 3;
+
+/// A 5x7 pixel font of the printable ASCII characters from space to tilde.
+///
+/// Each glyph is stored as five columns from left to right, with the top row in the lowest bit.
+/// It is embedded so text renders the same on every machine without any system fonts.
+const FONT: [[u8; GLYPH_WIDTH]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // space
+    [0x00, 0x00, 0x5F, 0x00, 0x00], // !
+    [0x00, 0x07, 0x00, 0x07, 0x00], // "
+    [0x14, 0x7F, 0x14, 0x7F, 0x14], // #
+    [0x24, 0x2A, 0x7F, 0x2A, 0x12], // $
+    [0x23, 0x13, 0x08, 0x64, 0x62], // %
+    [0x36, 0x49, 0x55, 0x22, 0x50], // &
+    [0x00, 0x05, 0x03, 0x00, 0x00], // '
+    [0x00, 0x1C, 0x22, 0x41, 0x00], // (
+    [0x00, 0x41, 0x22, 0x1C, 0x00], // )
+    [0x08, 0x2A, 0x1C, 0x2A, 0x08], // *
+    [0x08, 0x08, 0x3E, 0x08, 0x08], // +
+    [0x00, 0x50, 0x30, 0x00, 0x00], // ,
+    [0x08, 0x08, 0x08, 0x08, 0x08], // -
+    [0x00, 0x60, 0x60, 0x00, 0x00], // .
+    [0x20, 0x10, 0x08, 0x04, 0x02], // /
+    [0x3E, 0x51, 0x49, 0x45, 0x3E], // 0
+    [0x00, 0x42, 0x7F, 0x40, 0x00], // 1
+    [0x42, 0x61, 0x51, 0x49, 0x46], // 2
+    [0x21, 0x41, 0x45, 0x4B, 0x31], // 3
+    [0x18, 0x14, 0x12, 0x7F, 0x10], // 4
+    [0x27, 0x45, 0x45, 0x45, 0x39], // 5
+    [0x3C, 0x4A, 0x49, 0x49, 0x30], // 6
+    [0x01, 0x71, 0x09, 0x05, 0x03], // 7
+    [0x36, 0x49, 0x49, 0x49, 0x36], // 8
+    [0x06, 0x49, 0x49, 0x29, 0x1E], // 9
+    [0x00, 0x36, 0x36, 0x00, 0x00], // :
+    [0x00, 0x56, 0x36, 0x00, 0x00], // ;
+    [0x08, 0x14, 0x22, 0x41, 0x00], // <
+    [0x14, 0x14, 0x14, 0x14, 0x14], // =
+    [0x00, 0x41, 0x22, 0x14, 0x08], // >
+    [0x02, 0x01, 0x51, 0x09, 0x06], // ?
+    [0x32, 0x49, 0x79, 0x41, 0x3E], // @
+    [0x7E, 0x11, 0x11, 0x11, 0x7E], // A
+    [0x7F, 0x49, 0x49, 0x49, 0x36], // B
+    [0x3E, 0x41, 0x41, 0x41, 0x22], // C
+    [0x7F, 0x41, 0x41, 0x22, 0x1C], // D
+    [0x7F, 0x49, 0x49, 0x49, 0x41], // E
+    [0x7F, 0x09, 0x09, 0x09, 0x01], // F
+    [0x3E, 0x41, 0x49, 0x49, 0x7A], // G
+    [0x7F, 0x08, 0x08, 0x08, 0x7F], // H
+    [0x00, 0x41, 0x7F, 0x41, 0x00], // I
+    [0x20, 0x40, 0x41, 0x3F, 0x01], // J
+    [0x7F, 0x08, 0x14, 0x22, 0x41], // K
+    [0x7F, 0x40, 0x40, 0x40, 0x40], // L
+    [0x7F, 0x02, 0x0C, 0x02, 0x7F], // M
+    [0x7F, 0x04, 0x08, 0x10, 0x7F], // N
+    [0x3E, 0x41, 0x41, 0x41, 0x3E], // O
+    [0x7F, 0x09, 0x09, 0x09, 0x06], // P
+    [0x3E, 0x41, 0x51, 0x21, 0x5E], // Q
+    [0x7F, 0x09, 0x19, 0x29, 0x46], // R
+    [0x46, 0x49, 0x49, 0x49, 0x31], // S
+    [0x01, 0x01, 0x7F, 0x01, 0x01], // T
+    [0x3F, 0x40, 0x40, 0x40, 0x3F], // U
+    [0x1F, 0x20, 0x40, 0x20, 0x1F], // V
+    [0x3F, 0x40, 0x38, 0x40, 0x3F], // W
+    [0x63, 0x14, 0x08, 0x14, 0x63], // X
+    [0x07, 0x08, 0x70, 0x08, 0x07], // Y
+    [0x61, 0x51, 0x49, 0x45, 0x43], // Z
+    [0x00, 0x7F, 0x41, 0x41, 0x00], // [
+    [0x02, 0x04, 0x08, 0x10, 0x20], // backslash
+    [0x00, 0x41, 0x41, 0x7F, 0x00], // ]
+    [0x04, 0x02, 0x01, 0x02, 0x04], // ^
+    [0x40, 0x40, 0x40, 0x40, 0x40], // _
+    [0x00, 0x01, 0x02, 0x04, 0x00], // `
+    [0x20, 0x54, 0x54, 0x54, 0x78], // a
+    [0x7F, 0x48, 0x44, 0x44, 0x38], // b
+    [0x38, 0x44, 0x44, 0x44, 0x20], // c
+    [0x38, 0x44, 0x44, 0x48, 0x7F], // d
+    [0x38, 0x54, 0x54, 0x54, 0x18], // e
+    [0x08, 0x7E, 0x09, 0x01, 0x02], // f
+    [0x0C, 0x52, 0x52, 0x52, 0x3E], // g
+    [0x7F, 0x08, 0x04, 0x04, 0x78], // h
+    [0x00, 0x44, 0x7D, 0x40, 0x00], // i
+    [0x20, 0x40, 0x44, 0x3D, 0x00], // j
+    [0x7F, 0x10, 0x28, 0x44, 0x00], // k
+    [0x00, 0x41, 0x7F, 0x40, 0x00], // l
+    [0x7C, 0x04, 0x18, 0x04, 0x78], // m
+    [0x7C, 0x08, 0x04, 0x04, 0x78], // n
+    [0x38, 0x44, 0x44, 0x44, 0x38], // o
+    [0x7C, 0x14, 0x14, 0x14, 0x08], // p
+    [0x08, 0x14, 0x14, 0x18, 0x7C], // q
+    [0x7C, 0x08, 0x04, 0x04, 0x08], // r
+    [0x48, 0x54, 0x54, 0x54, 0x20], // s
+    [0x04, 0x3F, 0x44, 0x40, 0x20], // t
+    [0x3C, 0x40, 0x40, 0x20, 0x7C], // u
+    [0x1C, 0x20, 0x40, 0x20, 0x1C], // v
+    [0x3C, 0x40, 0x30, 0x40, 0x3C], // w
+    [0x44, 0x28, 0x10, 0x28, 0x44], // x
+    [0x0C, 0x50, 0x50, 0x50, 0x3C], // y
+    [0x44, 0x64, 0x54, 0x4C, 0x44], // z
+    [0x00, 0x08, 0x36, 0x41, 0x00], // {
+    [0x00, 0x00, 0x7F, 0x00, 0x00], // |
+    [0x00, 0x41, 0x36, 0x08, 0x00], // }
+    [0x08, 0x04, 0x08, 0x10, 0x08], // ~
+];
+
+/// The glyph of a character, with characters outside the font drawn as a question mark.
+fn glyph(character: char) -> [u8; GLYPH_WIDTH] {
+    match character {
+        ' '..='~' => FONT[character as usize - ' ' as usize],
+        _ => FONT['?' as usize - ' ' as usize],
+    }
+}
+
+/// Where a text block is placed in the viewport, which also aligns its lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TextAnchor {
+    TopLeft,
+    #[default]
+    Top,
+    TopRight,
+    Center,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl TextAnchor {
+    /// Where the anchor sits along each axis, from 0.0 at the left or top to 1.0 at the right or bottom.
+    fn alignment(self) -> (f32, f32) {
+        match self {
+            TextAnchor::TopLeft => (0.0, 0.0),
+            TextAnchor::Top => (0.5, 0.0),
+            TextAnchor::TopRight => (1.0, 0.0),
+            TextAnchor::Center => (0.5, 0.5),
+            TextAnchor::BottomLeft => (0.0, 1.0),
+            TextAnchor::Bottom => (0.5, 1.0),
+            TextAnchor::BottomRight => (1.0, 1.0),
+        }
+    }
+}
+
+/// A title, date or caption drawn over the composited frames, so GIFs describe themselves.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TextOverlay {
+    /// The text to draw, where `{prompt}` and `{date}` stand for the prompt and date of the image.
+    /// Lines are wrapped at spaces to fit the viewport.
+    pub text: String,
+    /// Where the text is placed in the viewport.
+    #[serde(default)]
+    pub anchor: TextAnchor,
+    /// The window pixels per font pixel, or `None` to scale the text with the viewport height.
+    #[serde(default)]
+    pub size: Option<usize>,
+    /// The RGB color of the text.
+    #[serde(default = "default_text_color")]
+    pub color: [u8; 3],
+    /// The RGB color of an outline one font pixel wide, which keeps the text legible on any background.
+    #[serde(default = "default_outline")]
+    pub outline: Option<[u8; 3]>,
+    /// When the text appears on the timeline, in seconds.
+    #[serde(default)]
+    pub start: f32,
+    /// When the text disappears on the timeline, in seconds, or `None` to keep it to the end.
+    #[serde(default)]
+    pub end: Option<f32>,
+    /// How long the text takes to fade in after it appears, in seconds.
+    #[serde(default)]
+    pub fade_in: f32,
+    /// How long the text takes to fade out before it disappears, in seconds.
+    #[serde(default)]
+    pub fade_out: f32,
+}
+
+fn default_text_color() -> [u8; 3] {
+    [255, 255, 255]
+}
+
+fn default_outline() -> Option<[u8; 3]> {
+    Some([0, 0, 0])
+}
+
+impl TextOverlay {
+    /// Creates white, outlined text shown over the whole animation.
+    ///
+    /// # Arguments
+    /// * `text` - The text to draw.
+    /// * `anchor` - Where the text is placed in the viewport.
+    pub fn new(text: &str, anchor: TextAnchor) -> Self {
+        Self {
+            text: text.to_string(),
+            anchor,
+            size: None,
+            color: default_text_color(),
+            outline: default_outline(),
+            start: 0.0,
+            end: None,
+            fade_in: 0.0,
+            fade_out: 0.0,
+        }
+    }
+
+    /// The prompt as a title at the top and the date in the bottom right corner.
+    pub fn auto() -> Vec<Self> {
+        vec![Self::new("{prompt}", TextAnchor::Top), Self::new("{date}", TextAnchor::BottomRight)]
+    }
+
+    /// Loads text overlays from a JSON array.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the JSON file.
+    pub fn load(path: &str) -> Result<Vec<Self>, Box<dyn Error>> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Fills in the `{prompt}` and `{date}` placeholders of the text.
+    ///
+    /// # Arguments
+    /// * `prompt` - The prompt the image was generated from.
+    /// * `date` - The date of the image.
+    pub fn with_placeholders(mut self, prompt: &str, date: NaiveDate) -> Self {
+        self.text = self.text.replace("{prompt}", prompt.trim()).replace("{date}", &date.to_string());
+        self
+    }
+
+    /// How opaque the text is at a time on the timeline, fading in after `start` and out before `end`.
+    fn opacity_at(&self, time: f32) -> f32 {
+        let end = self.end.unwrap_or(f32::INFINITY);
+        if time < self.start || time >= end {
+            return 0.0;
+        }
+        let fade = |elapsed: f32, duration: f32| if duration > 0.0 { (elapsed / duration).min(1.0) } else { 1.0 };
+        fade(time - self.start, self.fade_in).min(fade(end - time, self.fade_out))
+    }
+
+    /// Draws the text as it is at a time on the timeline.
+    ///
+    /// # Arguments
+    /// * `window_buffer` - The pixels of the window in ARGB format.
+    /// * `window_width` - The width of the window in pixels.
+    /// * `time` - The time of the frame on the timeline, in seconds.
+    pub fn draw(&self, window_buffer: &mut [u32], window_width: usize, time: f32) {
+        let opacity = self.opacity_at(time);
+        if opacity <= 0.0 || self.text.trim().is_empty() || window_width == 0 {
+            return;
+        }
+
+        let window_height = window_buffer.len() / window_width;
+        let scale = self.size.unwrap_or(window_height / TEXT_SIZE_DIVISOR).max(1);
+        let margin = TEXT_MARGIN * scale;
+        let columns = (window_width.saturating_sub(2 * margin) / ((GLYPH_WIDTH + 1) * scale)).max(1);
+        let (mask, mask_width) = self.rasterize(&wrap(&self.text, columns));
+        let mask_height = mask.len() / mask_width;
+
+        let (align_x, align_y) = self.anchor.alignment();
+        // The block keeps its margin from the edges it is anchored to and is centered between the others
+        let place = |window: usize, block: usize, alignment: f32| {
+            (margin as f32 + (window as f32 - block as f32 - 2.0 * margin as f32) * alignment).round() as i64
+        };
+        let left = place(window_width, mask_width * scale, align_x);
+        let top = place(window_height, mask_height * scale, align_y);
+
+        let paint = |color: [u8; 3]| {
+            let pixel = 0xFF000000 | (color[0] as u32) << 16 | (color[1] as u32) << 8 | color[2] as u32;
+            PremultipliedColor::from_argb(pixel).scale(opacity)
+        };
+        let (text_color, outline_color) = (paint(self.color), paint(self.outline.unwrap_or_default()));
+
+        for (index, &cell) in mask.iter().enumerate() {
+            let source = match cell {
+                Cell::Empty => continue,
+                Cell::Outline => outline_color,
+                Cell::Glyph => text_color,
+            };
+            let (cell_x, cell_y) = ((index % mask_width * scale) as i64 + left, (index / mask_width * scale) as i64 + top);
+            for y in cell_y.max(0)..(cell_y + scale as i64).min(window_height as i64) {
+                for x in cell_x.max(0)..(cell_x + scale as i64).min(window_width as i64) {
+                    let pixel = &mut window_buffer[y as usize * window_width + x as usize];
+                    *pixel = composite(*pixel, source, BlendMode::Normal);
+                }
+            }
+        }
+    }
+
+    /// Lays out the lines in font pixels, aligned by the anchor and surrounded by the outline.
+    ///
+    /// # Returns
+    /// The cells row by row and the width of a row.
+    fn rasterize(&self, lines: &[String]) -> (Vec<Cell>, usize) {
+        let advance = GLYPH_WIDTH + 1;
+        let longest = lines.iter().map(|line| line.chars().count()).max().unwrap_or(0);
+        let width = longest * advance + 1;
+        let height = lines.len() * (GLYPH_HEIGHT + LINE_GAP) - LINE_GAP + 2;
+        let mut mask = vec![Cell::Empty; width * height];
+
+        for (row, line) in lines.iter().enumerate() {
+            let indent = ((longest - line.chars().count()) as f32 * advance as f32 * self.anchor.alignment().0) as usize;
+            for (column, character) in line.chars().enumerate() {
+                for (glyph_x, bits) in glyph(character).into_iter().enumerate() {
+                    for glyph_y in (0..GLYPH_HEIGHT).filter(|glyph_y| bits >> glyph_y & 1 == 1) {
+                        let (x, y) = (1 + indent + column * advance + glyph_x, 1 + row * (GLYPH_HEIGHT + LINE_GAP) + glyph_y);
+                        mask[y * width + x] = Cell::Glyph;
+                    }
+                }
+            }
+        }
+
+        if self.outline.is_some() {
+            let glyph_cells: Vec<usize> = (0..mask.len()).filter(|&index| mask[index] == Cell::Glyph).collect();
+            for index in glyph_cells {
+                let (x, y) = (index % width, index / width);
+                for neighbour_y in y - 1..=y + 1 {
+                    for neighbour_x in x - 1..=x + 1 {
+                        let neighbour = &mut mask[neighbour_y * width + neighbour_x];
+                        if *neighbour == Cell::Empty {
+                            *neighbour = Cell::Outline;
+                        }
+                    }
+                }
+            }
+        }
+
+        (mask, width)
+    }
+}
+
+/// What a font pixel of a text block is covered by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cell {
+    Empty,
+    Outline,
+    Glyph,
+}
+
+/// Breaks text into lines of at most `columns` characters, at spaces where possible.
+fn wrap(text: &str, columns: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for paragraph in text.lines() {
+        let mut line = String::new();
+        for word in paragraph.split_whitespace() {
+            let mut word: Vec<char> = word.chars().collect();
+            // Words longer than a line are split over several
+            while word.len() > columns {
+                if !line.is_empty() {
+                    lines.push(std::mem::take(&mut line));
+                }
+                lines.push(word.drain(..columns).collect());
+            }
+            if !line.is_empty() && line.chars().count() + 1 + word.len() > columns {
+                lines.push(std::mem::take(&mut line));
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.extend(word);
+        }
+        if !line.is_empty() {
+            lines.push(line);
+        }
+    }
+    lines
+}
+
+/// Draws the text overlays over the composited and graded frame.
+///
+/// # Arguments
+/// * `state` - The application state, with the overlays, the time and the window buffer.
+pub fn draw_text_overlays(state: &mut State) {
+    for overlay in &state.text {
+        overlay.draw(state.window_buffer, state.window_width, state.time);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glyphs_are_drawn_with_an_outline_at_the_anchor() {
+        let mut buffer = vec![0xFF0000FF; 20 * 20];
+        let overlay = TextOverlay { size: Some(1), ..TextOverlay::new("I", TextAnchor::TopLeft) };
+
+        overlay.draw(&mut buffer, 20, 0.0);
+
+        // The block starts at the margin of four font pixels, with the outline around the glyph's middle three columns
+        let row = |y: usize| buffer[y * 20..y * 20 + 10].iter().map(|&pixel| match pixel {
+            0xFFFFFFFF => '#',
+            0xFF000000 => 'o',
+            _ => '.',
+        }).collect::<String>();
+        assert_eq!(row(4), ".....ooooo");
+        assert_eq!(row(5), ".....o###o");
+        assert_eq!(row(6), ".....oo#oo");
+        assert_eq!(row(11), ".....o###o");
+        assert_eq!(row(12), ".....ooooo");
+    }
+
+    #[test]
+    fn test_text_wraps_fades_and_fills_in_placeholders() {
+        assert_eq!(wrap("a pixel art valley\nat dusk", 8), vec!["a pixel", "art", "valley", "at dusk"]);
+        assert_eq!(wrap("mountains", 4), vec!["moun", "tain", "s"]);
+
+        let overlay = TextOverlay { start: 1.0, end: Some(3.0), fade_in: 0.5, fade_out: 1.0, ..TextOverlay::new("{date}: {prompt}", TextAnchor::Bottom) };
+        let opacities: Vec<f32> = [0.5, 1.25, 1.5, 2.5, 3.0].map(|time| overlay.opacity_at(time)).to_vec();
+        assert_eq!(opacities, vec![0.0, 0.5, 1.0, 0.5, 0.0]);
+        assert_eq!(overlay.with_placeholders(" Dunes ", NaiveDate::from_ymd_opt(2025, 8, 2).unwrap()).text, "2025-08-02: Dunes");
+
+        let loaded: Vec<TextOverlay> = serde_json::from_str(r#"[{"text": "Hi", "anchor": "bottom-right", "outline": null}]"#).unwrap();
+        assert_eq!((loaded[0].anchor, loaded[0].outline, loaded[0].color), (TextAnchor::BottomRight, None, [255, 255, 255]));
+    }
+}
@@ -1,9 +1,10 @@
+use crate::graphics::actor::draw_actor;
 use crate::graphics::parallax::draw_parallax_layer;
-use crate::graphics::sprites::draw_sprite;
+use crate::graphics::particles::draw_particle_overlays;
 
 use crate::state::structs::State;
 
-/// Updates the pixel buffer by drawing the background and parallax layers.
+/// Updates the pixel buffer by drawing the parallax layers, and the actor and particle overlays between them.
 ///
 /// # Parallax Effect
 /// The function draws layers at different speeds to achieve the parallax effect,
@@ -14,12 +15,15 @@ use crate::state::structs::State;
 /// - `game_state`: A mutable reference to the current game state, containing camera position, window buffer, and sprite layers.
 pub fn update_pixel_buffer(state: &mut State) {
 
-    // Always draw the static background layer first in order to fill all pixels as the parallax effect can result in empty pixels
-    draw_sprite(0, 0, &state.sprites.layer_1[0], state.window_buffer, state.window_width);
+    // Clear the previous frame, as vertical camera movement can leave parts of the window uncovered by any layer
+    state.window_buffer.fill(0xFF000000);
 
-    // Draw each parallax layer
-    draw_parallax_layer(state, 0, 16);
-    draw_parallax_layer(state, 1, 6);
-    draw_parallax_layer(state, 2, 4);
-    draw_parallax_layer(state, 3, 1);
-}
\ No newline at end of file
+    // Draw each parallax layer, back to front, followed by the actor and overlays that sit on top of it
+    draw_actor(state, 0);
+    draw_particle_overlays(state, 0);
+    for layer_index in 0..state.layers.len() {
+        draw_parallax_layer(state, layer_index);
+        draw_actor(state, layer_index + 1);
+        draw_particle_overlays(state, layer_index + 1);
+    }
+}
@@ -1,10 +1,19 @@
 use crate::state::constants::file_paths::INPUT_IMAGE_PATH;
-use crate::state::constants::graphics::{WINDOW_HEIGHT, WINDOW_WIDTH};
+use crate::graphics::layer_package::export_layer_package;
+use crate::graphics::parallax::default_parallax_layers;
+use crate::graphics::render_target::OffscreenTarget;
+#[cfg(feature = "window")]
+use crate::graphics::render_target::WindowTarget;
 use crate::state::event_loop::record_gif;
+#[cfg(feature = "window")]
+use crate::state::live_reload::LiveReload;
+#[cfg(feature = "window")]
+use crate::state::preview::run_preview;
+#[cfg(feature = "window")]
+use crate::utils::misc::parse_layer_config_path;
 use crate::state::structs::State;
-use crate::utils::misc::{create_parallax_layers_for_date, extract_palette_or_exit, generate_and_save_image, initialize_generators, parse_headless_mode, prepare_python_interpreter};
+use crate::utils::misc::{create_parallax_layers_for_date, extract_palette_or_exit, generate_and_save_image, initialize_generators, outpaint_input_image, parse_actor, parse_color_grade, parse_export_layers, parse_camera_path, parse_headless_mode, parse_layer_config, parse_max_gif_bytes, parse_outpainting, parse_output_formats, parse_panorama, parse_particle_overlays, parse_text_overlays, parse_timeline, parse_viewport_size, prepare_python_interpreter, resolve_viewport_size};
 use chrono::NaiveDate;
-use minifb::{Window, WindowOptions};
 use std::fs;
 use std::io::stdin;
 use std::process::exit;
@@ -15,6 +24,20 @@ mod graphics; mod state; mod utils; mod generators;
 fn main() {
     prepare_python_interpreter();
     let headless = parse_headless_mode();
+    let max_gif_bytes = parse_max_gif_bytes();
+    let output_formats = parse_output_formats();
+    let export_layers = parse_export_layers();
+    let camera_path = parse_camera_path();
+    let timeline = parse_timeline();
+    let mut layers = parse_layer_config().unwrap_or_else(default_parallax_layers);
+    let actor = parse_actor();
+    let color_grade = parse_color_grade();
+    let viewport_size = parse_viewport_size();
+    let panorama = parse_panorama();
+    let outpainting = parse_outpainting();
+    if let Some(panorama) = panorama {
+        panorama.apply(&mut layers);
+    }
 
     if headless {
         println!("\nRunning in headless mode, tailored for the GitHub runner.");
@@ -27,6 +50,13 @@ fn main() {
             return;
         }
 
+        if let (Some(outpainting), Ok(prompt)) = (outpainting, &prompt_result) {
+            if let Err(e) = outpaint_input_image(&image_generator, outpainting, prompt, current_date) {
+                eprintln!("Error during outpainting: {}", e);
+                return;
+            }
+        }
+
         let (color_map, color_to_index_map) = extract_palette_or_exit(INPUT_IMAGE_PATH);
 
         if let Err(e) = create_parallax_layers_for_date(INPUT_IMAGE_PATH, current_date) {
@@ -34,21 +64,48 @@ fn main() {
             return;
         }
 
+        if export_layers {
+            if let Err(e) = export_layer_package(current_date, &layers) {
+                eprintln!("Error during layer package export: {}", e);
+            }
+        }
+
 
         let binding = prompt_result.unwrap();
-        let mut window_buffer = vec![0; WINDOW_WIDTH * WINDOW_HEIGHT];
-
-        let state = State::new(
+        let particles = parse_particle_overlays(&binding, current_date);
+        let text = parse_text_overlays(&binding, current_date);
+        let (viewport_width, viewport_height) = match resolve_viewport_size(panorama, viewport_size, current_date) {
+            Ok(size) => size,
+            Err(e) => {
+                eprintln!("Error during panorama sizing: {}", e);
+                return;
+            }
+        };
+        let mut window_buffer = vec![0; viewport_width * viewport_height];
+        let mut target = OffscreenTarget::default();
+
+        let mut state = State::new(
             current_date,
             &mut window_buffer,
-            None,
+            &mut target,
             binding.as_str(),
-            headless,
             Some(color_map),
             Some(color_to_index_map),
-        );
-
-        record_gif(state);
+        )
+        .with_layers(layers)
+        .with_particles(particles)
+        .with_actor(actor)
+        .with_color_grade(color_grade)
+        .with_text(text)
+        .with_viewport(viewport_width, viewport_height)
+        .with_timeline(timeline)
+        .with_camera_path(camera_path);
+
+        if let Err(e) = record_gif(&mut state, max_gif_bytes, &output_formats) {
+            eprintln!("Error during GIF recording: {}", e);
+            exit(1);
+        }
+        println!("Rendered {} frames offscreen", target.presented());
     }
 
     else {
@@ -96,33 +153,65 @@ fn main() {
             .expect("Invalid date format in image name");
         println!("You selected the image with date: {}", date_part);
 
-        let image_path = format!("./images/{}", selected_image);
-        let (color_map, color_to_index_map) = extract_palette_or_exit(image_path.as_str());
-
-        let mut window_buffer = vec![0; 1024 * 1024];
+        if export_layers {
+            if let Err(e) = export_layer_package(naive_date_part, &layers) {
+                eprintln!("Error during layer package export: {}", e);
+            }
+        }
 
-        let mut window =
-            Some(Window::new(
-                "Parallax Scrolling GIF Exporter",
-                WINDOW_WIDTH,
-                WINDOW_HEIGHT,
-                WindowOptions::default(),
-            ).unwrap_or_else(|e| {
-                panic!("{}", e);
-            }));
+        let prompt = fs::read_to_string(format!("./prompts/prompt_{}.txt", date_part)).unwrap_or_default();
+        let particles = parse_particle_overlays(&prompt, naive_date_part);
+        let text = parse_text_overlays(&prompt, naive_date_part);
 
+        let image_path = format!("./images/{}", selected_image);
+        let (color_map, color_to_index_map) = extract_palette_or_exit(image_path.as_str());
 
-        let state = State::new(
+        let (viewport_width, viewport_height) = match resolve_viewport_size(panorama, viewport_size, naive_date_part) {
+            Ok(size) => size,
+            Err(e) => {
+                eprintln!("Error during panorama sizing: {}", e);
+                return;
+            }
+        };
+        let mut window_buffer = vec![0; viewport_width * viewport_height];
+
+        #[cfg(feature = "window")]
+        let mut target = WindowTarget::new("Parallax Scrolling GIF Exporter", viewport_width, viewport_height).unwrap_or_else(|e| {
+            panic!("{}", e);
+        });
+        #[cfg(not(feature = "window"))]
+        let mut target = {
+            println!("Built without the 'window' feature, so the selected image is recorded offscreen without a preview.");
+            OffscreenTarget::default()
+        };
+
+        let mut state = State::new(
             naive_date_part,
             &mut window_buffer,
-            window.as_mut(),
+            &mut target,
             "NIX",
-            false,
             Some(color_map),
             Some(color_to_index_map),
-        );
-
-        record_gif(state);
+        )
+        .with_layers(layers)
+        .with_particles(particles)
+        .with_actor(actor)
+        .with_color_grade(color_grade)
+        .with_text(text)
+        .with_viewport(viewport_width, viewport_height)
+        .with_timeline(timeline)
+        .with_camera_path(camera_path);
+
+        // Without a window there are no preview controls, so the scene is recorded straight away
+        #[cfg(feature = "window")]
+        let result = run_preview(&mut state, LiveReload::new(naive_date_part, parse_layer_config_path(), panorama), max_gif_bytes, &output_formats);
+        #[cfg(not(feature = "window"))]
+        let result = record_gif(&mut state, max_gif_bytes, &output_formats);
+
+        if let Err(e) = result {
+            eprintln!("Error during preview: {}", e);
+            exit(1);
+        }
     }
 
 
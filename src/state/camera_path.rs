@@ -0,0 +1,262 @@
+use crate::state::structs::Camera;
+use crate::state::timeline::Timeline;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::f32::consts::PI;
+use std::fs;
+use std::str::FromStr;
+
+/// How the camera moves from the previous keyframe to a keyframe.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Easing {
+    /// Constant speed.
+    #[default]
+    Linear,
+    /// Starts slow and speeds up.
+    EaseIn,
+    /// Starts fast and slows down.
+    EaseOut,
+    /// Starts and ends slow.
+    EaseInOut,
+    /// Constant speed with a vertical sine wave on top, like a bobbing flight.
+    SineBob {
+        /// How far the camera bobs up and down, in pixels.
+        amplitude: f32,
+        /// How many full bobs happen between the two keyframes.
+        cycles: f32,
+    },
+}
+
+impl Easing {
+    /// Maps linear progress between two keyframes to eased progress.
+    ///
+    /// # Arguments
+    /// * `t` - The linear progress, from 0.0 to 1.0.
+    fn apply(self, t: f32) -> f32 {
+        match self {
+            Easing::Linear | Easing::SineBob { .. } => t,
+            Easing::EaseIn => t * t,
+            Easing::EaseOut => t * (2.0 - t),
+            Easing::EaseInOut => (1.0 - (PI * t).cos()) / 2.0,
+        }
+    }
+
+    /// The vertical offset added on top of the eased position.
+    fn vertical_offset(self, t: f32) -> f32 {
+        match self {
+            Easing::SineBob { amplitude, cycles } => amplitude * (2.0 * PI * cycles * t).sin(),
+            _ => 0.0,
+        }
+    }
+}
+
+impl FromStr for Easing {
+    type Err = String;
+
+    /// Parses `linear`, `ease-in`, `ease-out`, `ease-in-out`, `sine-bob` or `sine-bob(amplitude,cycles)`.
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.trim() {
+            "linear" => Ok(Easing::Linear),
+            "ease-in" => Ok(Easing::EaseIn),
+            "ease-out" => Ok(Easing::EaseOut),
+            "ease-in-out" => Ok(Easing::EaseInOut),
+            "sine-bob" => Ok(Easing::SineBob { amplitude: 16.0, cycles: 1.0 }),
+            bob if bob.starts_with("sine-bob(") && bob.ends_with(')') => {
+                let arguments = &bob["sine-bob(".len()..bob.len() - 1];
+                let (amplitude, cycles) = arguments.split_once(',').ok_or_else(|| format!("Expected sine-bob(amplitude,cycles), got '{}'.", bob))?;
+                Ok(Easing::SineBob {
+                    amplitude: amplitude.trim().parse().map_err(|_| format!("Invalid sine-bob amplitude '{}'.", amplitude))?,
+                    cycles: cycles.trim().parse().map_err(|_| format!("Invalid sine-bob cycles '{}'.", cycles))?,
+                })
+            }
+            _ => Err(format!("Unknown easing '{}', expected linear, ease-in, ease-out, ease-in-out or sine-bob.", name)),
+        }
+    }
+}
+
+/// A camera position at a given time.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CameraKeyframe {
+    /// The time on the timeline the camera reaches this position at, in seconds.
+    pub time: f32,
+    /// The x-coordinate of the camera.
+    pub x: f32,
+    /// The y-coordinate of the camera.
+    pub y: f32,
+    /// The zoom factor of the camera.
+    #[serde(default = "default_zoom")]
+    pub zoom: f32,
+    /// How the camera moves here from the previous keyframe.
+    #[serde(default)]
+    pub easing: Easing,
+}
+
+fn default_zoom() -> f32 {
+    1.0
+}
+
+/// A scripted camera movement made of keyframes.
+///
+/// The camera moves between consecutive keyframes using the easing of the later one, and holds
+/// its position after the last keyframe. Two keyframes at the same position make a pause.
+/// Keyframes are placed in seconds, so the path takes as long at any frame rate.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CameraPath {
+    /// The keyframes, sorted by time.
+    pub keyframes: Vec<CameraKeyframe>,
+}
+
+impl CameraPath {
+    /// Creates a camera path, sorting the keyframes by time.
+    ///
+    /// # Returns
+    /// The `CameraPath`, or an error if there are no keyframes, one is before the start or two share a time.
+    pub fn new(mut keyframes: Vec<CameraKeyframe>) -> Result<Self, Box<dyn Error>> {
+        keyframes.sort_by(|first, second| first.time.total_cmp(&second.time));
+
+        if keyframes.is_empty() {
+            return Err("A camera path needs at least one keyframe.".into());
+        }
+        if let Some(keyframe) = keyframes.iter().find(|keyframe| !(keyframe.time >= 0.0 && keyframe.time.is_finite())) {
+            return Err(format!("Invalid camera keyframe time {}, expected seconds from the start.", keyframe.time).into());
+        }
+        if let Some(pair) = keyframes.windows(2).find(|pair| pair[0].time == pair[1].time) {
+            return Err(format!("Two camera keyframes share the time {} seconds.", pair[0].time).into());
+        }
+
+        Ok(Self { keyframes })
+    }
+
+    /// Loads a camera path from a JSON file with a `keyframes` array.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the JSON file.
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        let CameraPath { keyframes } = serde_json::from_str(&contents)?;
+        Self::new(keyframes)
+    }
+
+    /// How long the path takes, from the start of the timeline to the last keyframe, in seconds.
+    pub fn duration(&self) -> f32 {
+        self.keyframes.last().map_or(0.0, |keyframe| keyframe.time)
+    }
+
+    /// The number of frames needed to play the whole path, up to and including the frame at the last keyframe.
+    ///
+    /// # Arguments
+    /// * `timeline` - The timeline whose frame rate places the frames.
+    pub fn frame_count(&self, timeline: &Timeline) -> usize {
+        (self.duration() * timeline.frames_per_second).round() as usize + 1
+    }
+
+    /// Moves the camera to where the path has it at a time.
+    ///
+    /// # Arguments
+    /// * `camera` - The camera to move, whose focal point is left as is.
+    /// * `time` - The time on the timeline, in seconds.
+    pub fn move_camera(&self, camera: &mut Camera, time: f32) {
+        (camera.x, camera.y, camera.zoom) = self.pose_at(time);
+    }
+
+    /// Computes the camera position and zoom at a time, in seconds.
+    ///
+    /// # Returns
+    /// The `(x, y, zoom)` of the camera.
+    fn pose_at(&self, time: f32) -> (f32, f32, f32) {
+        let next = self.keyframes.partition_point(|keyframe| keyframe.time < time);
+
+        let Some(to) = self.keyframes.get(next) else {
+            let last = self.keyframes[self.keyframes.len() - 1];
+            return (last.x, last.y, last.zoom);
+        };
+        if next == 0 || to.time == time {
+            return (to.x, to.y, to.zoom);
+        }
+
+        let from = self.keyframes[next - 1];
+        let t = (time - from.time) / (to.time - from.time);
+        let eased = to.easing.apply(t);
+
+        (
+            from.x + (to.x - from.x) * eased,
+            from.y + (to.y - from.y) * eased + to.easing.vertical_offset(t),
+            from.zoom + (to.zoom - from.zoom) * eased,
+        )
+    }
+}
+
+impl FromStr for CameraPath {
+    type Err = Box<dyn Error>;
+
+    /// Parses keyframes written as `seconds:x,y[,zoom][:easing]`, separated by semicolons.
+    ///
+    /// For example `0:0,0;2:400,0:ease-in-out;3:400,0;5:800,40,1.5:sine-bob(12,2)` eases to the
+    /// right, pauses for a second and then bobs twice while drifting down and zooming in.
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let keyframes = spec
+            .split(';')
+            .filter(|keyframe| !keyframe.trim().is_empty())
+            .map(|keyframe| -> Result<CameraKeyframe, Box<dyn Error>> {
+                let mut fields = keyframe.splitn(3, ':');
+                let time = fields.next().unwrap_or_default().trim();
+                let position = fields.next().ok_or_else(|| format!("Camera keyframe '{}' has no position.", keyframe))?;
+                let coordinates: Vec<&str> = position.split(',').map(str::trim).collect();
+                let (x, y, zoom) = match coordinates[..] {
+                    [x, y] => (x, y, "1"),
+                    [x, y, zoom] => (x, y, zoom),
+                    _ => return Err(format!("Camera keyframe position '{}' is not x,y or x,y,zoom.", position).into()),
+                };
+
+                Ok(CameraKeyframe {
+                    time: time.parse().map_err(|_| format!("Invalid camera keyframe time '{}'.", time))?,
+                    x: x.parse().map_err(|_| format!("Invalid camera keyframe x '{}'.", x))?,
+                    y: y.parse().map_err(|_| format!("Invalid camera keyframe y '{}'.", y))?,
+                    zoom: zoom.parse().map_err(|_| format!("Invalid camera keyframe zoom '{}'.", zoom))?,
+                    easing: fields.next().map(str::parse).transpose()?.unwrap_or_default(),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Self::new(keyframes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_position_follows_keyframes_easing_and_pauses() {
+        let path: CameraPath = "0:0,0;1:100,0:ease-in-out;2:100,0;3:200,50,2:sine-bob(10,1)".parse().unwrap();
+
+        assert_eq!(path.frame_count(&Timeline::default()), 31);
+        assert_eq!(path.frame_count(&Timeline::new(30.0).unwrap()), 91);
+        assert_eq!(path.pose_at(0.0), (0.0, 0.0, 1.0));
+        assert_eq!(path.pose_at(0.5), (50.0, 0.0, 1.0));
+        assert!(path.pose_at(0.2).0 < 20.0);
+        assert!(path.pose_at(0.25).0 > path.pose_at(0.2).0);
+        assert_eq!(path.pose_at(1.5), (100.0, 0.0, 1.0));
+
+        let (x, y, zoom) = path.pose_at(2.5);
+        assert_eq!((x, zoom), (150.0, 1.5));
+        assert!((y - 25.0).abs() < 1e-3);
+        let (_, y, _) = path.pose_at(2.2);
+        assert!(y > 10.0 + 5.0);
+
+        let mut camera = Camera::new(0.0, 0.0);
+        path.move_camera(&mut camera, 9.9);
+        assert_eq!((camera.x, camera.y, camera.zoom), (200.0, 50.0, 2.0));
+    }
+
+    #[test]
+    fn test_invalid_paths_are_rejected() {
+        assert!("".parse::<CameraPath>().is_err());
+        assert!("0:0,0;0:10,0".parse::<CameraPath>().is_err());
+        assert!("-1:0,0".parse::<CameraPath>().is_err());
+        assert!("0:0".parse::<CameraPath>().is_err());
+        assert!("0:0,0,1,1".parse::<CameraPath>().is_err());
+        assert!("0:0,0;5:1,1:wobble".parse::<CameraPath>().is_err());
+    }
+}
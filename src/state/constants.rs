@@ -1,14 +1,40 @@
 pub mod graphics {
-    pub const WINDOW_WIDTH: usize = 1024;
-    pub const WINDOW_HEIGHT: usize = 1024;
-    pub const MAX_GIF_FRAMES: usize = 10; // More frames equals smoother GIFs, but larger file sizes and thus slower rendering
-    pub const CAMERA_X_INCREMENT: f32 = 20.0; // Speed of camera movement in pixels per frame
+    pub const WINDOW_WIDTH: usize = 1024; // Default width of the viewport, overridden with --size
+    pub const WINDOW_HEIGHT: usize = 1024; // Default height of the viewport, overridden with --size
+    pub const RECORDING_SECONDS: f32 = 1.0; // Length of a recording without a camera path; longer GIFs are larger and thus slower to render
+    pub const FRAMES_PER_SECOND: f32 = 10.0; // Default frame rate of the timeline, overridden with --fps; more frames equals smoother but larger GIFs
+    pub const FRAME_RATE_RANGE: (f32, f32) = (1.0, 50.0); // Browsers slow down GIF frames shorter than 2 hundredths of a second, so faster rates would not play as timed
+    pub const MAX_FRAME_SECONDS: f32 = 600.0; // Longest a frame can be held with --delays, within GIF's limit of 655.35 seconds
+    pub const CAMERA_SPEED: f32 = 200.0; // Speed of camera movement in pixels of the scene per second
+    pub const PARALLAX_DIVISORS: [usize; 4] = [16, 6, 4, 1]; // Camera movement is divided by these per layer, back to front, both horizontally and vertically
+    pub const MAX_PALETTE_SIZE: usize = 256; // GIF palettes are indexed by a single byte
+    pub const ROWS_PER_CHUNK: usize = 64; // Rows of a frame mapped to palette indices per parallel job
+    pub const VIDEO_ENCODER: &str = "ffmpeg"; // Local encoder that raw frames are piped to for MP4 and WebM output
+    pub const LAYER_FRAMES_PER_SECOND: f32 = 10.0; // Default playback rate of animated layers, one frame per GIF frame at the default frame rate
+    pub const ACTOR_GROUND_LINE: f32 = 0.75; // Fraction of the scene height the actor's feet stand on, where the band of layer 3 meets that of layer 4
+    pub const ACTOR_FRAMES_PER_SECOND: f32 = 12.0; // Default playback rate of the actor's animation frames
+    pub const ACTOR_SPEED: f32 = 120.0; // How far the actor runs to the right per second, in pixels of the scene
+    pub const ACTOR_SCROLL_FACTOR: f32 = 0.25; // The actor follows the camera like layer 3, which it runs in front of
+    pub const ACTOR_DEPTH: usize = 3; // Number of layers drawn behind the actor, placing it between layers 3 and 4
+    pub const TEXT_SIZE_DIVISOR: usize = 200; // Text overlays without a size draw a font pixel per this many pixels of viewport height
+    pub const TEXT_MARGIN: usize = 4; // Font pixels between a text overlay and the edges of the viewport
+    pub const MAX_LUT_SIZE: usize = 256; // Largest LUT_3D_SIZE accepted from .cube files, far beyond the 33 or 65 entries per axis of common LUTs
+    #[cfg(any(feature = "window", test))]
+    pub const DIVISOR_PRESETS: [[usize; 4]; 4] = [PARALLAX_DIVISORS, [32, 12, 8, 2], [8, 4, 2, 1], [24, 8, 3, 1]]; // Divisor sets the preview cycles through, by sprite
+    #[cfg(any(feature = "window", test))]
+    pub const PREVIEW_SPEED_RANGE: (f32, f32) = (0.125, 8.0); // Slowest and fastest preview playback, relative to the timeline's frame rate
+    #[cfg(any(feature = "window", test))]
+    pub const LIVE_RELOAD_INTERVAL_MS: u64 = 500; // How often the preview checks the layer images and configuration for changes
 }
 
 pub mod file_paths {
     pub const INPUT_IMAGE_PATH: &str = "images/image_current.png";
     pub const CURRENT_GIF_PATH: &str = "gifs/gif_current.gif";
     pub const CURRENT_PROMPT_PATH: &str = "prompts/prompt_current.txt";
+    pub const ANIMATIONS_DIRECTORY: &str = "animations";
+    pub const PACKAGES_DIRECTORY: &str = "packages";
+    #[cfg(feature = "window")]
+    pub const SETTINGS_DIRECTORY: &str = "settings";
 }
 
 
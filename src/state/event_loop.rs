@@ -1,39 +1,151 @@
-use crate::graphics::gif::{initialize_gif_encoder, process_frame};
+use crate::graphics::gif::{initialize_gif_encoder, FrameFormat, GifPalette, PixelIndexCache};
+use crate::graphics::output::{create_animation_writers, OutputFormat};
+use crate::graphics::pipeline::FramePipeline;
 use crate::graphics::render_graphics::render_pixel_buffer;
+use crate::graphics::size_budget::fit_gif_to_size;
+use crate::graphics::text::draw_text_overlays;
 use crate::graphics::update_graphics::update_pixel_buffer;
-use crate::state::constants::graphics::MAX_GIF_FRAMES;
+use crate::state::constants::graphics::RECORDING_SECONDS;
 use crate::state::structs::State;
-use crate::utils::misc::{finalize_gif_encoding, is_window_open, should_process_frame, simulate_camera_movement};
-use std::fs::File;
-use std::time::Instant;
+use crate::utils::misc::{finalize_gif_encoding, is_window_open, simulate_camera_movement};
+use std::error::Error;
+use std::fs::{self, File};
+use std::thread;
 use timing_macro::timed;
 
+/// Renders the scene and records it as a GIF, along with any additional animation formats.
+///
+/// Without a size budget, frames are streamed into the encoding pipeline as they are rendered.
+/// With one, all frames are rendered first and then re-encoded until the GIF fits. The additional
+/// formats are not limited to 256 colors and always receive the frames at full quality.
+///
+/// Each frame is shown for its delay on the state's timeline, counted from the first recorded
+/// frame, and frames identical to the one before them are merged into it in the GIF.
+///
+/// With color grading, the GIF palette is graded the same way as the frames, so night or sunset
+/// frames are quantized against colors they actually contain.
+///
+/// # Arguments
+/// * `state` - The application state to render.
+/// * `max_gif_bytes` - The optional maximum size of the GIF file in bytes.
+/// * `output_formats` - The animation formats to write alongside the GIF.
 #[timed]
-pub fn record_gif(mut state: State) {
+pub fn record_gif(state: &mut State, max_gif_bytes: Option<u64>, output_formats: &[OutputFormat]) -> Result<(), Box<dyn Error>> {
     let (width, height) = (state.window_width as u16, state.window_height as u16);
     let path = format!("gifs/gif_{}.gif", state.target_date);
-    let mut image = File::create(&path).unwrap();
-    let mut encoder = initialize_gif_encoder(&mut image, width, height);
-    let mut frame_count = 0;
-    let mut last_update = Instant::now();
-
-    loop {
-        if !state.headless && !is_window_open(&state) {
-            break;
+    let timeline = state.timeline.clone();
+    let palette = match (&state.color_grade, &state.color_map) {
+        (Some(color_grade), Some(color_map)) => {
+            let (graded_map, graded_index_map) = color_grade.grade_palette(color_map)?;
+            GifPalette::new(Some(&graded_map), Some(&graded_index_map))?
         }
+        _ => GifPalette::new(state.color_map.as_deref(), state.color_to_index_map.as_ref())?,
+    };
 
-        update_pixel_buffer(&mut state);
-        render_pixel_buffer(&mut state);
-        simulate_camera_movement(&mut state);
+    let mut writers = create_animation_writers(output_formats, state.target_date, width as u32, height as u32, &timeline)?;
+    let mut output_error = None;
+    let mut write_to_outputs = |pixels: &[u32]| match writers.iter_mut().try_for_each(|writer| writer.write_frame(pixels)) {
+        Ok(()) => true,
+        Err(e) => {
+            output_error = Some(e);
+            false
+        }
+    };
+
+    let frame_count = match max_gif_bytes {
+        None => {
+            let mut image = File::create(&path)?;
+            let pixel_index_cache = PixelIndexCache::default();
+
+            // Frames are composited here in order, while quantization and encoding run on the pipeline's workers
+            thread::scope(|scope| -> Result<Option<usize>, Box<dyn Error>> {
+                let encoder = initialize_gif_encoder(&mut image, width, height);
+                let mut pipeline = FramePipeline::spawn(scope, encoder, FrameFormat::new(width, height), &palette, &pixel_index_cache);
+                let mut delays = (0..).map(|frame| timeline.gif_delay_at(frame));
+                let completed = capture_frames(state, |pixels| write_to_outputs(&pixels) && pipeline.submit(pixels, delays.next().unwrap_or_default()));
+                let frame_count = pipeline.finish()?;
+                Ok(completed.then_some(frame_count))
+            })?
+        }
+        Some(max_gif_bytes) => {
+            let mut frames = Vec::new();
+            let completed = capture_frames(state, |pixels| {
+                let written = write_to_outputs(&pixels);
+                frames.push(pixels);
+                written
+            });
+
+            if completed && output_error.is_none() {
+                let fitted = fit_gif_to_size(&frames, width, height, &palette, &timeline, max_gif_bytes)?;
+                fs::write(&path, &fitted.bytes)?;
+
+                println!("GIF fits in {} of {} bytes", fitted.bytes.len(), max_gif_bytes);
+                for trade_off in &fitted.trade_offs {
+                    println!("Traded away to fit the budget: {}", trade_off);
+                }
 
-        if should_process_frame(&last_update) {
-            if frame_count < MAX_GIF_FRAMES {
-                process_frame(state.window_buffer, &mut encoder, &mut frame_count, &state.color_map.clone(), &mut state.color_to_index_map.clone());
-                last_update = Instant::now();
+                Some(fitted.frame_count)
             } else {
-                finalize_gif_encoding(state, frame_count, path.as_str());
-                break;
+                None
             }
         }
+    };
+
+    if let Some(e) = output_error {
+        return Err(e);
+    }
+
+    for writer in writers {
+        println!("Animation written to file '{}'", writer.finish()?);
+    }
+
+    if let Some(frame_count) = frame_count {
+        finalize_gif_encoding(state, frame_count, path.as_str());
+    }
+
+    Ok(())
+}
+
+/// Runs the render loop and hands each captured frame to `capture` until `RECORDING_SECONDS` of the
+/// timeline are captured, or until the camera path has played in full when one is set.
+///
+/// Every rendered frame is captured and the timeline advances by exactly one frame after it, so
+/// the recording does not depend on how long rendering takes.
+///
+/// # Arguments
+/// * `state` - The application state to render.
+/// * `capture` - Receives a copy of each captured window buffer and returns `false` to stop early.
+///
+/// # Returns
+/// `false` if the window was closed before the recording finished, otherwise `true`.
+fn capture_frames(state: &mut State, mut capture: impl FnMut(Vec<u32>) -> bool) -> bool {
+    let frame_limit = state.camera_path.as_ref().map_or_else(|| state.timeline.frame_count(RECORDING_SECONDS), |path| path.frame_count(&state.timeline));
+
+    for _ in 0..frame_limit {
+        if !is_window_open(state) {
+            return false;
+        }
+
+        render_frame(state);
+        simulate_camera_movement(state);
+
+        if !capture(state.window_buffer.clone()) {
+            return true;
+        }
+    }
+
+    true
+}
+
+/// Renders the frame the camera is at, grades it and presents it on the render target.
+///
+/// # Arguments
+/// * `state` - The application state to render, whose window buffer receives the frame.
+pub fn render_frame(state: &mut State) {
+    update_pixel_buffer(state);
+    if let Some(color_grade) = &state.color_grade {
+        color_grade.apply(state.window_buffer, state.time);
     }
-}
\ No newline at end of file
+    draw_text_overlays(state);
+    render_pixel_buffer(state);
+}
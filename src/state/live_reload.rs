@@ -0,0 +1,124 @@
+use crate::graphics::parallax::{load_parallax_layers, Panorama, ParallaxLayer};
+use crate::graphics::sprites::SpriteMaps;
+use crate::state::constants::graphics::LIVE_RELOAD_INTERVAL_MS;
+use chrono::NaiveDate;
+use std::error::Error;
+use std::fs;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Which of the watched files changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// Only layer images changed, so the layers are kept as they are.
+    Images,
+    /// The layer configuration changed, so the layers are loaded from it again.
+    Config,
+}
+
+/// Watches the layer images of a date and the layer configuration for changes while previewing.
+///
+/// The modification times of the files are polled, which needs no platform specific watcher and
+/// also notices editors that save by replacing the file.
+pub struct LiveReload {
+    /// The JSON file the layers were loaded from, if any.
+    layer_config: Option<String>,
+    /// The panorama the layers are prepared for after loading them.
+    panorama: Option<Panorama>,
+    /// The layer images, with their modification times when last polled.
+    images: Vec<(String, Option<SystemTime>)>,
+    /// The modification time of the layer configuration when last polled.
+    config_modified: Option<SystemTime>,
+    /// When the files were last polled.
+    last_poll: Instant,
+}
+
+impl LiveReload {
+    /// Starts watching the layer images of a date and the layer configuration.
+    ///
+    /// # Arguments
+    /// * `target_date` - The date of the layer images.
+    /// * `layer_config` - The JSON file the layers were loaded from, or `None` for the default layers.
+    /// * `panorama` - The panorama the layers are prepared for, mirroring their repeats if it asks to.
+    pub fn new(target_date: NaiveDate, layer_config: Option<String>, panorama: Option<Panorama>) -> Self {
+        let images: Vec<(String, Option<SystemTime>)> = SpriteMaps::layer_paths(target_date).into_iter().map(|path| {
+            let modified = modified(&path);
+            (path, modified)
+        }).collect();
+        let config_modified = layer_config.as_deref().and_then(modified);
+
+        Self { layer_config, panorama, images, config_modified, last_poll: Instant::now() }
+    }
+
+    /// Checks the watched files for changes, at most once per `LIVE_RELOAD_INTERVAL_MS`.
+    ///
+    /// # Returns
+    /// The change since the last check, preferring a changed configuration, or `None` if nothing changed.
+    pub fn poll(&mut self) -> Option<Change> {
+        if self.last_poll.elapsed() < Duration::from_millis(LIVE_RELOAD_INTERVAL_MS) {
+            return None;
+        }
+        self.last_poll = Instant::now();
+        self.check()
+    }
+
+    /// Compares the modification times of the watched files with those of the last check.
+    fn check(&mut self) -> Option<Change> {
+        let mut images_changed = false;
+        for (path, last_modified) in &mut self.images {
+            let current = modified(path);
+            images_changed |= current != *last_modified;
+            *last_modified = current;
+        }
+
+        let config_modified = self.layer_config.as_deref().and_then(modified);
+        let config_changed = config_modified != self.config_modified;
+        self.config_modified = config_modified;
+
+        match (config_changed, images_changed) {
+            (true, _) => Some(Change::Config),
+            (false, true) => Some(Change::Images),
+            (false, false) => None,
+        }
+    }
+
+    /// Loads the layers from the layer configuration again, prepared for the panorama.
+    ///
+    /// # Returns
+    /// The layers, or an error if there is no configuration or it is invalid.
+    pub fn load_layers(&self) -> Result<Vec<ParallaxLayer>, Box<dyn Error>> {
+        let path = self.layer_config.as_deref().ok_or("There is no layer configuration to reload.")?;
+        let mut layers = load_parallax_layers(path)?;
+        if let Some(panorama) = self.panorama {
+            panorama.apply(&mut layers);
+        }
+        Ok(layers)
+    }
+}
+
+/// The modification time of a file, or `None` if it does not exist.
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    #[test]
+    fn test_changed_config_is_noticed_once() {
+        let path = std::env::temp_dir().join(format!("live_reload_{}.json", std::process::id()));
+        fs::write(&path, r#"[{"name": "sky", "sprite": 0, "scroll_factor": 0.1}]"#).unwrap();
+        let mut live_reload = LiveReload::new(NaiveDate::default(), Some(path.to_string_lossy().into_owned()), None);
+
+        assert_eq!(live_reload.check(), None);
+        File::options().write(true).open(&path).unwrap().set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        live_reload.last_poll = Instant::now();
+        assert_eq!(live_reload.poll(), None, "polled again before the interval passed");
+        assert_eq!(live_reload.check(), Some(Change::Config));
+        assert_eq!(live_reload.check(), None);
+        assert_eq!(live_reload.load_layers().unwrap()[0].name, "sky");
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,5 +1,11 @@
 pub mod event_loop;
 pub mod constants;
 pub mod structs;
+pub mod camera_path;
+pub mod timeline;
+#[cfg(any(feature = "window", test))]
+pub mod preview;
+#[cfg(any(feature = "window", test))]
+pub mod live_reload;
 
 
@@ -0,0 +1,255 @@
+#[cfg(feature = "window")]
+use crate::graphics::output::OutputFormat;
+use crate::graphics::parallax::ParallaxLayer;
+use crate::graphics::render_target::PreviewInput;
+#[cfg(feature = "window")]
+use crate::state::constants::file_paths::SETTINGS_DIRECTORY;
+use crate::state::constants::graphics::{DIVISOR_PRESETS, PREVIEW_SPEED_RANGE};
+#[cfg(feature = "window")]
+use crate::state::event_loop::{record_gif, render_frame};
+#[cfg(feature = "window")]
+use crate::state::live_reload::{Change, LiveReload};
+#[cfg(feature = "window")]
+use crate::state::structs::State;
+#[cfg(feature = "window")]
+use crate::utils::misc::is_window_open;
+#[cfg(feature = "window")]
+use std::error::Error;
+#[cfg(feature = "window")]
+use std::fs;
+#[cfg(feature = "window")]
+use std::thread;
+#[cfg(feature = "window")]
+use std::time::Instant;
+
+/// What the preview asks of its caller after handling an input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreviewAction {
+    /// Save the visible layers as a JSON file.
+    SaveSettings,
+    /// Record a GIF from the current settings.
+    ExportGif,
+}
+
+/// The playback state of the interactive preview, changed with the keyboard while the scene plays.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preview {
+    /// The playback position in frames, fractional when playing slower or faster than the timeline.
+    pub position: f32,
+    /// The number of frames after which the playback starts over, the length of the camera path if there is one.
+    loop_frames: Option<f32>,
+    /// How many frames the playback advances per shown frame.
+    pub speed: f32,
+    /// Whether the playback stands still, leaving only scrubbing to move it.
+    pub paused: bool,
+    /// The original opacity of each hidden layer, by its index in the layers.
+    hidden: Vec<Option<f32>>,
+    /// The index of the divisor set in `DIVISOR_PRESETS` the scroll factors were last set from.
+    divisor_preset: usize,
+}
+
+impl Preview {
+    /// Creates a playing preview at the first frame, at the speed of the timeline, with every layer shown.
+    ///
+    /// # Arguments
+    /// * `layer_count` - The number of layers that can be hidden.
+    /// * `loop_frames` - The number of frames after which the playback starts over, or `None` to play on.
+    pub fn new(layer_count: usize, loop_frames: Option<usize>) -> Self {
+        Self {
+            position: 0.0,
+            loop_frames: loop_frames.map(|frames| frames.max(1) as f32),
+            speed: 1.0,
+            paused: false,
+            hidden: vec![None; layer_count],
+            divisor_preset: 0,
+        }
+    }
+
+    /// Moves the playback position by a number of frames, wrapping it around when the playback loops.
+    ///
+    /// Keeping a looping position within its loop keeps it small enough for the fraction of a
+    /// frame to stay precise however long the preview plays.
+    ///
+    /// # Arguments
+    /// * `frames` - The number of frames to move by, negative to move back.
+    pub fn advance(&mut self, frames: f32) {
+        self.position = match self.loop_frames {
+            Some(loop_frames) => (self.position + frames).rem_euclid(loop_frames),
+            None => (self.position + frames).max(0.0),
+        };
+    }
+
+    /// Applies a preview input to the playback and the layers.
+    ///
+    /// Hiding a layer sets its opacity to zero, so recordings made while previewing leave it out
+    /// as well, and showing it again restores its opacity. Cycling the divisors sets every layer's
+    /// scroll factor from the next set in `DIVISOR_PRESETS`, by the layer's sprite.
+    ///
+    /// # Arguments
+    /// * `input` - The input to apply.
+    /// * `layers` - The layers to hide, show or change the scroll factors of.
+    ///
+    /// # Returns
+    /// The action the caller has to carry out, if the input asks for one.
+    pub fn handle(&mut self, input: PreviewInput, layers: &mut [ParallaxLayer]) -> Option<PreviewAction> {
+        let (min_speed, max_speed) = PREVIEW_SPEED_RANGE;
+
+        match input {
+            PreviewInput::TogglePause => self.paused = !self.paused,
+            PreviewInput::ScrubBackward => self.advance(-1.0),
+            PreviewInput::ScrubForward => self.advance(1.0),
+            PreviewInput::Faster => self.speed = (self.speed * 2.0).min(max_speed),
+            PreviewInput::Slower => self.speed = (self.speed / 2.0).max(min_speed),
+            PreviewInput::ToggleLayer(index) => {
+                let (Some(layer), Some(hidden)) = (layers.get_mut(index), self.hidden.get_mut(index)) else {
+                    return None;
+                };
+                match hidden.take() {
+                    Some(opacity) => layer.opacity = opacity,
+                    None => *hidden = Some(std::mem::replace(&mut layer.opacity, 0.0)),
+                }
+                println!("Layer '{}' {}", layer.name, if hidden.is_some() { "hidden" } else { "shown" });
+            }
+            PreviewInput::CycleDivisors => {
+                self.divisor_preset = (self.divisor_preset + 1) % DIVISOR_PRESETS.len();
+                let divisors = DIVISOR_PRESETS[self.divisor_preset];
+                for layer in layers.iter_mut() {
+                    layer.scroll_factor = 1.0 / divisors[layer.sprite.min(divisors.len() - 1)] as f32;
+                }
+                println!("Layer divisors set to {}", divisors.map(|divisor| divisor.to_string()).join(", "));
+            }
+            PreviewInput::SaveSettings => return Some(PreviewAction::SaveSettings),
+            PreviewInput::ExportGif => return Some(PreviewAction::ExportGif),
+        }
+
+        if matches!(input, PreviewInput::TogglePause | PreviewInput::Faster | PreviewInput::Slower) {
+            println!("Preview {} at {}x speed", if self.paused { "paused" } else { "playing" }, self.speed);
+        }
+        None
+    }
+
+    /// Shows every layer again and forgets the divisor set, for layers that were just reloaded.
+    ///
+    /// # Arguments
+    /// * `layer_count` - The number of layers that can be hidden.
+    pub fn reset_layers(&mut self, layer_count: usize) {
+        self.hidden = vec![None; layer_count];
+        self.divisor_preset = 0;
+    }
+
+    /// The layers that are shown, with their own opacity, as they would be saved.
+    ///
+    /// # Arguments
+    /// * `layers` - The layers as drawn, with the hidden ones at zero opacity.
+    pub fn visible_layers(&self, layers: &[ParallaxLayer]) -> Vec<ParallaxLayer> {
+        layers.iter().zip(&self.hidden).filter(|(_, hidden)| hidden.is_none()).map(|(layer, _)| layer.clone()).collect()
+    }
+}
+
+/// Plays the scene in the window until it is closed, handling the preview controls.
+///
+/// Space pauses and resumes, the arrows scrub a frame at a time, plus and minus change the speed,
+/// the number keys hide and show the layers, D cycles the layer divisors, S saves the visible
+/// layers to the settings directory and G records a GIF from the current frame on.
+///
+/// Changed layer images and a changed layer configuration are reloaded while the scene plays, so
+/// layers can be edited without restarting. A file that cannot be loaded keeps the previous scene.
+///
+/// # Arguments
+/// * `state` - The application state to preview.
+/// * `live_reload` - The watcher of the layer images and configuration.
+/// * `max_gif_bytes` - The optional maximum size of exported GIFs in bytes.
+/// * `output_formats` - The animation formats to write alongside exported GIFs.
+#[cfg(feature = "window")]
+pub fn run_preview(state: &mut State, mut live_reload: LiveReload, max_gif_bytes: Option<u64>, output_formats: &[OutputFormat]) -> Result<(), Box<dyn Error>> {
+    // The camera path loops, while the constant scroll plays on
+    let mut preview = Preview::new(state.layers.len(), state.camera_path.as_ref().map(|path| path.frame_count(&state.timeline)));
+    println!("Previewing: Space pauses, arrows scrub, +/- change speed, 1-9 toggle layers, D cycles divisors, S saves, G exports a GIF, Escape quits");
+
+    while is_window_open(state) {
+        let started = Instant::now();
+        if let Some(change) = live_reload.poll() {
+            let layers = match change {
+                Change::Config => live_reload.load_layers(),
+                Change::Images => Ok(state.layers.clone()),
+            };
+            match layers.and_then(|layers| state.reload(layers)) {
+                Ok(()) if change == Change::Config => {
+                    preview.reset_layers(state.layers.len());
+                    println!("Reloaded {} layers and their images", state.layers.len());
+                }
+                Ok(()) => println!("Reloaded the layer images"),
+                Err(e) => eprintln!("Live reload failed, keeping the previous scene: {}", e),
+            }
+        }
+        // The fraction of the position is kept, so slow motion moves the scene smoothly
+        state.seek(preview.position);
+        render_frame(state);
+
+        for input in state.target.poll_input() {
+            match preview.handle(input, &mut state.layers) {
+                Some(PreviewAction::SaveSettings) => {
+                    fs::create_dir_all(SETTINGS_DIRECTORY)?;
+                    let path = format!("{}/layers_{}.json", SETTINGS_DIRECTORY, state.target_date);
+                    fs::write(&path, serde_json::to_string_pretty(&preview.visible_layers(&state.layers))?)?;
+                    println!("Layers saved to '{}', load them with --layers {}", path, path);
+                }
+                Some(PreviewAction::ExportGif) => record_gif(state, max_gif_bytes, output_formats)?,
+                None => {}
+            }
+        }
+
+        if !preview.paused {
+            preview.advance(preview.speed);
+        }
+        thread::sleep(state.timeline.frame_duration(state.frame_index).saturating_sub(started.elapsed()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inputs_change_playback_and_layers() {
+        let mut layers = vec![ParallaxLayer::new("sky", 0, 0.1), ParallaxLayer { opacity: 0.5, ..ParallaxLayer::new("fog", 3, 1.0) }];
+        let mut preview = Preview::new(layers.len(), None);
+
+        for input in [PreviewInput::TogglePause, PreviewInput::ScrubBackward, PreviewInput::ScrubForward, PreviewInput::Slower, PreviewInput::ToggleLayer(1)] {
+            assert_eq!(preview.handle(input, &mut layers), None);
+        }
+
+        assert_eq!((preview.paused, preview.position, preview.speed), (true, 1.0, 0.5));
+        assert_eq!(layers[1].opacity, 0.0);
+        assert_eq!(preview.visible_layers(&layers), vec![layers[0].clone()]);
+
+        preview.handle(PreviewInput::ToggleLayer(1), &mut layers);
+        preview.handle(PreviewInput::CycleDivisors, &mut layers);
+        assert_eq!((layers[0].scroll_factor, layers[1].scroll_factor, layers[1].opacity), (1.0 / 32.0, 0.5, 0.5));
+        assert_eq!(preview.handle(PreviewInput::ExportGif, &mut layers), Some(PreviewAction::ExportGif));
+        assert_eq!(preview.handle(PreviewInput::SaveSettings, &mut layers), Some(PreviewAction::SaveSettings));
+
+        preview.handle(PreviewInput::Faster, &mut layers);
+        preview.handle(PreviewInput::ToggleLayer(0), &mut layers);
+        preview.reset_layers(layers.len());
+        assert_eq!(preview.speed, 1.0);
+        assert_eq!(preview.visible_layers(&layers).len(), 2);
+    }
+    #[test]
+    fn test_looping_playback_wraps_around() {
+        let mut preview = Preview::new(0, Some(10));
+
+        preview.handle(PreviewInput::ScrubBackward, &mut []);
+        assert_eq!(preview.position, 9.0);
+
+        for _ in 0..25 {
+            preview.handle(PreviewInput::ScrubForward, &mut []);
+        }
+        assert_eq!(preview.position, 4.0);
+
+        preview.advance(7.5);
+        assert_eq!(preview.position, 1.5);
+    }
+}
@@ -1,8 +1,15 @@
 use std::collections::HashMap;
 use chrono::NaiveDate;
+use crate::graphics::actor::Actor;
+use crate::graphics::grading::ColorTimeline;
+use crate::graphics::parallax::{animate_layer_sprites, default_parallax_layers, ParallaxLayer};
+use crate::graphics::particles::ParticleOverlay;
+use crate::graphics::render_target::RenderTarget;
 use crate::graphics::sprites::SpriteMaps;
-use minifb::Window;
-use crate::state::constants::graphics::{WINDOW_HEIGHT, WINDOW_WIDTH};
+use crate::graphics::text::TextOverlay;
+use crate::state::camera_path::CameraPath;
+use crate::state::constants::graphics::{CAMERA_SPEED, WINDOW_HEIGHT, WINDOW_WIDTH};
+use crate::state::timeline::Timeline;
 
 /// Represents a camera in the simulation.
 pub struct Camera {
@@ -10,6 +17,12 @@ pub struct Camera {
     pub x: f32,
     /// The y-coordinate of the camera.
     pub y: f32,
+    /// The zoom factor, where 1.0 shows the layers at their original size.
+    pub zoom: f32,
+    /// The x-coordinate in the window that zooming scales around.
+    pub focus_x: f32,
+    /// The y-coordinate in the window that zooming scales around.
+    pub focus_y: f32,
 }
 
 impl Camera {
@@ -22,9 +35,15 @@ impl Camera {
     ///
     /// # Returns
     ///
-    /// A new `Camera` object.
+    /// A new `Camera` object without zoom, focused on the center of the window.
     pub fn new(x: f32, y: f32) -> Self {
-        Camera { x, y }
+        Camera {
+            x,
+            y,
+            zoom: 1.0,
+            focus_x: WINDOW_WIDTH as f32 / 2.0,
+            focus_y: WINDOW_HEIGHT as f32 / 2.0,
+        }
     }
 }
 
@@ -34,20 +53,38 @@ pub struct State<'a> {
     pub target_date: chrono::NaiveDate,
     /// The camera object.
     pub camera: Camera,
+    /// The optional scripted camera movement, replacing the constant scroll when set.
+    pub camera_path: Option<CameraPath>,
+    /// The number of frames rendered so far.
+    pub frame_index: usize,
+    /// The clock that places the frames in time.
+    pub timeline: Timeline,
+    /// The time on the timeline of the frame being rendered, in seconds.
+    pub time: f32,
     /// The sprite maps used in the application.
     pub sprites: SpriteMaps,
+    /// The parallax layers, drawn back to front.
+    pub layers: Vec<ParallaxLayer>,
+    /// The procedurally animated overlays drawn between or above the layers.
+    pub particles: Vec<ParticleOverlay>,
+    /// The optional animated character running through the scene.
+    pub actor: Option<Actor>,
+    /// The optional color grading applied to each composited frame.
+    pub color_grade: Option<ColorTimeline>,
+    /// The titles, dates and captions drawn over each graded frame.
+    pub text: Vec<TextOverlay>,
     /// The buffer for the window.
     pub window_buffer: &'a mut Vec<u32>,
     /// The width of the window.
     pub window_width: usize,
     /// The height of the window.
     pub window_height: usize,
-    /// The optional window object.
-    pub window: Option<&'a mut Window>,
+    /// The number of window pixels per pixel of the scene the layers were loaded at.
+    pub render_scale: f32,
+    /// Where the rendered frames are shown, a window or an offscreen buffer.
+    pub target: &'a mut dyn RenderTarget,
     /// The prompt for the current state.
     pub prompt: &'a str,
-    /// Whether the application is running in headless mode
-    pub headless: bool,
     /// Color map for the application
     pub color_map: Option<Vec<u8>>,
     /// Map from color to index for palette management
@@ -58,26 +95,196 @@ impl State<'_> {
     pub fn new<'a>(
         target_date: NaiveDate,
         window_buffer: &'a mut Vec<u32>,
-        window: Option<&'a mut Window>,
+        target: &'a mut dyn RenderTarget,
         prompt: &'a str,
-        headless: bool,
         color_map: Option<Vec<u8>>,
         color_to_index_map: Option<HashMap<u32, u8>>,
     ) -> State<'a> {
         State {
             target_date,
             camera: Camera::new(0.0, 0.0),
+            camera_path: None,
+            frame_index: 0,
+            timeline: Timeline::default(),
+            time: 0.0,
             sprites: SpriteMaps::new(target_date),
+            layers: default_parallax_layers(),
+            particles: Vec::new(),
+            actor: None,
+            color_grade: None,
+            text: Vec::new(),
             window_buffer,
             window_width: WINDOW_WIDTH,
             window_height: WINDOW_HEIGHT,
-            window,
+            render_scale: 1.0,
+            target,
             prompt,
-            headless,
             color_map,
             color_to_index_map,
         }
     }
-}
 
+    /// Replaces the default parallax layers, generating the frames of rippling layers.
+    ///
+    /// # Arguments
+    /// * `layers` - The layers to draw back to front.
+    ///
+    /// # Returns
+    /// The `State` with the layers replaced.
+    pub fn with_layers(mut self, layers: Vec<ParallaxLayer>) -> Self {
+        animate_layer_sprites(&mut self.sprites, &layers);
+        self.layers = layers;
+        self
+    }
+
+    /// Adds procedurally animated overlays, such as rain or fireflies, to the scene.
+    ///
+    /// # Arguments
+    /// * `particles` - The overlays to draw, each above its number of layers.
+    ///
+    /// # Returns
+    /// The `State` with the overlays replaced.
+    pub fn with_particles(mut self, particles: Vec<ParticleOverlay>) -> Self {
+        self.particles = particles;
+        self
+    }
 
+    /// Adds an animated character running through the scene.
+    ///
+    /// # Arguments
+    /// * `actor` - The actor to draw, or `None` for an empty scene.
+    ///
+    /// # Returns
+    /// The `State` with the actor replaced.
+    pub fn with_actor(mut self, actor: Option<Actor>) -> Self {
+        self.actor = actor;
+        self
+    }
+
+    /// Grades the composited frames with colors that change over the animation.
+    ///
+    /// # Arguments
+    /// * `color_grade` - The color timeline to grade with, or `None` to keep the original colors.
+    ///
+    /// # Returns
+    /// The `State` with the color grading replaced.
+    pub fn with_color_grade(mut self, color_grade: Option<ColorTimeline>) -> Self {
+        self.color_grade = color_grade;
+        self
+    }
+
+    /// Draws text such as a title, the date or a caption over the frames.
+    ///
+    /// # Arguments
+    /// * `text` - The text overlays to draw, in order.
+    ///
+    /// # Returns
+    /// The `State` with the text overlays replaced.
+    pub fn with_text(mut self, text: Vec<TextOverlay>) -> Self {
+        self.text = text;
+        self
+    }
+
+    /// Renders into a viewport of a different size than the layer images.
+    ///
+    /// The layers are scaled to fill the viewport's height and repeat horizontally to fill its
+    /// width, so a wide strip shows more of the scene side by side and a thumbnail shows all of it
+    /// smaller. Their frames, and those of the actor, are resampled once here with a Lanczos filter
+    /// rather than on every frame, so this is called after the layers and the actor are set.
+    ///
+    /// # Arguments
+    /// * `width` - The width of the viewport in pixels.
+    /// * `height` - The height of the viewport in pixels.
+    ///
+    /// # Returns
+    /// The `State` with the window buffer resized and the camera focused on the viewport's center.
+    pub fn with_viewport(mut self, width: usize, height: usize) -> Self {
+        let scale = height as f32 / self.sprites.scene_height.max(1) as f32 / self.render_scale;
+        if scale != 1.0 {
+            self.sprites.scale(scale);
+            if let Some(actor) = &mut self.actor {
+                actor.scale_frames(scale);
+            }
+        }
+        println!("Rendering {}x{} layers into a {}x{} viewport", self.sprites.scene_width, self.sprites.scene_height, width, height);
+
+        self.render_scale *= scale;
+        self.window_width = width;
+        self.window_height = height;
+        self.window_buffer.resize(width * height, 0);
+        self.camera.focus_x = width as f32 / 2.0;
+        self.camera.focus_y = height as f32 / 2.0;
+        self
+    }
+
+    /// Loads the layer images from disk again and replaces the layers, keeping the viewport.
+    ///
+    /// The frames are prepared like `with_layers` and `with_viewport` prepare them, so rippling
+    /// layers are animated and the frames are scaled to the viewport, even if the images changed size.
+    /// On error the state is left unchanged.
+    ///
+    /// # Arguments
+    /// * `layers` - The layers to draw back to front, replacing the current ones.
+    ///
+    /// # Returns
+    /// `Ok(())` if the images are loaded, or an error if one of them cannot be read.
+    #[cfg(feature = "window")]
+    pub fn reload(&mut self, layers: Vec<ParallaxLayer>) -> Result<(), Box<dyn std::error::Error>> {
+        let mut sprites = SpriteMaps::load(self.target_date)?;
+        animate_layer_sprites(&mut sprites, &layers);
+
+        let render_scale = self.window_height as f32 / sprites.scene_height.max(1) as f32;
+        sprites.scale(render_scale);
+        if let Some(actor) = self.actor.as_mut().filter(|_| render_scale != self.render_scale) {
+            actor.scale_frames(render_scale / self.render_scale);
+        }
+
+        self.sprites = sprites;
+        self.layers = layers;
+        self.render_scale = render_scale;
+        Ok(())
+    }
+
+    /// Replaces the constant camera scroll with a scripted camera path.
+    ///
+    /// # Arguments
+    /// * `camera_path` - The camera path to follow, or `None` to keep the constant scroll.
+    ///
+    /// # Returns
+    /// The `State` with the camera placed at the start of the path.
+    pub fn with_camera_path(mut self, camera_path: Option<CameraPath>) -> Self {
+        if let Some(path) = &camera_path {
+            path.move_camera(&mut self.camera, self.time);
+        }
+        self.camera_path = camera_path;
+        self
+    }
+
+    /// Replaces the default frame rate.
+    ///
+    /// # Arguments
+    /// * `timeline` - The clock that places the frames in time.
+    ///
+    /// # Returns
+    /// The `State` with the timeline replaced.
+    pub fn with_timeline(mut self, timeline: Timeline) -> Self {
+        self.timeline = timeline;
+        self
+    }
+
+    /// Moves to a position on the timeline and places the camera where it is at that time.
+    ///
+    /// Without a camera path the camera scrolls `CAMERA_SPEED` pixels per second of the timeline,
+    /// so the scene moves at the same speed at any frame rate, in the preview and in recordings.
+    ///
+    /// # Arguments
+    /// * `position` - The position in frames, fractional between two frames for slow motion.
+    pub fn seek(&mut self, position: f32) {
+        self.frame_index = position as usize;
+        self.time = self.timeline.time_at(position);
+        match &self.camera_path {
+            Some(path) => path.move_camera(&mut self.camera, self.time),
+            None => self.camera.x = self.time * CAMERA_SPEED,
+        }
+    }
+}
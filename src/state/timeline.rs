@@ -0,0 +1,232 @@
+use crate::state::constants::graphics::{FRAMES_PER_SECOND, FRAME_RATE_RANGE, MAX_FRAME_SECONDS};
+use std::fmt;
+use std::str::FromStr;
+#[cfg(feature = "window")]
+use std::time::Duration;
+
+/// How long a run of frames is shown instead of the frame rate's interval.
+///
+/// Written as `<first>[-<last>]:<seconds>[~<end seconds>]`, for example `0:2` to hold the first
+/// frame for two seconds as a title card, or `0-4:0.4~0.1` to ease in by showing the first five
+/// frames for 0.4 seconds down to 0.1 seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DelaySegment {
+    /// The first frame of the segment.
+    pub first: usize,
+    /// The last frame of the segment, inclusive.
+    pub last: usize,
+    /// How long the first frame is shown, in seconds.
+    pub seconds: f32,
+    /// How long the last frame is shown, in seconds, with the frames between eased linearly.
+    /// Equal to `seconds` for a hold.
+    pub end_seconds: f32,
+}
+
+impl DelaySegment {
+    /// How long a frame of the segment is shown, in seconds.
+    fn seconds_at(&self, frame: usize) -> f64 {
+        let t = match self.last - self.first {
+            0 => 0.0,
+            length => (frame - self.first) as f64 / length as f64,
+        };
+        self.seconds as f64 + (self.end_seconds as f64 - self.seconds as f64) * t
+    }
+}
+
+impl FromStr for DelaySegment {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid frame delay '{}', expected <first>[-<last>]:<seconds>[~<end seconds>].", spec);
+        let (frames, seconds) = spec.trim().split_once(':').ok_or_else(invalid)?;
+        let (first, last) = frames.split_once('-').unwrap_or((frames, frames));
+        let (seconds, end_seconds) = seconds.split_once('~').unwrap_or((seconds, seconds));
+
+        let frame = |value: &str| value.trim().parse::<usize>().map_err(|_| invalid());
+        let duration = |value: &str| match value.trim().parse::<f32>() {
+            Ok(seconds) if (1.0 / FRAME_RATE_RANGE.1..=MAX_FRAME_SECONDS).contains(&seconds) => Ok(seconds),
+            _ => Err(format!("Invalid frame delay '{}', frames are shown {} to {} seconds.", spec, 1.0 / FRAME_RATE_RANGE.1, MAX_FRAME_SECONDS)),
+        };
+
+        let segment = Self { first: frame(first)?, last: frame(last)?, seconds: duration(seconds)?, end_seconds: duration(end_seconds)? };
+        if segment.last < segment.first {
+            return Err(format!("Invalid frame delay '{}', the last frame comes before the first.", spec));
+        }
+        Ok(segment)
+    }
+}
+
+impl fmt::Display for DelaySegment {
+    /// Writes the segment the way it is parsed, leaving out the last frame and end seconds of a hold.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.first)?;
+        if self.last != self.first {
+            write!(f, "-{}", self.last)?;
+        }
+        write!(f, ":{}", self.seconds)?;
+        if self.end_seconds != self.seconds {
+            write!(f, "~{}", self.end_seconds)?;
+        }
+        Ok(())
+    }
+}
+
+/// The fixed-timestep clock of an animation, which places every frame at a point in time.
+///
+/// Frame `n` is shown at `n / frames_per_second` seconds however long it took to render, so the
+/// preview and the recordings move the scene at the same speed. Delays are rounded to the units of
+/// the output format, hundredths of a second for GIFs, from the exact times each frame starts and
+/// ends. The rounding error is carried over to the next frame instead of adding up, so a 30 FPS
+/// GIF alternates between delays of 3 and 4 and still plays 30 frames per second.
+///
+/// Delay segments show frames longer or shorter than the frame rate's interval without changing
+/// what they show, which holds a frame or slows the motion down into and out of a segment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timeline {
+    /// How many frames are shown per second.
+    pub frames_per_second: f32,
+    /// The frames shown for their own delays, sorted by frame and not overlapping.
+    pub delays: Vec<DelaySegment>,
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        Self { frames_per_second: FRAMES_PER_SECOND, delays: Vec::new() }
+    }
+}
+
+impl Timeline {
+    /// Creates a timeline at a frame rate.
+    ///
+    /// # Arguments
+    /// * `frames_per_second` - How many frames are shown per second, within `FRAME_RATE_RANGE`.
+    ///
+    /// # Returns
+    /// The `Timeline`, or an error if the frame rate is out of range.
+    pub fn new(frames_per_second: f32) -> Result<Self, String> {
+        let (min_frame_rate, max_frame_rate) = FRAME_RATE_RANGE;
+        if !(min_frame_rate..=max_frame_rate).contains(&frames_per_second) {
+            return Err(format!("Invalid frame rate {}, expected {} to {} frames per second.", frames_per_second, min_frame_rate, max_frame_rate));
+        }
+        Ok(Self { frames_per_second, delays: Vec::new() })
+    }
+
+    /// Replaces the delays of runs of frames.
+    ///
+    /// # Arguments
+    /// * `delays` - The segments to show for their own delays.
+    ///
+    /// # Returns
+    /// The `Timeline` with the segments sorted, or an error if two of them overlap.
+    pub fn with_delays(mut self, mut delays: Vec<DelaySegment>) -> Result<Self, String> {
+        delays.sort_by_key(|segment| segment.first);
+        if let Some(pair) = delays.windows(2).find(|pair| pair[1].first <= pair[0].last) {
+            return Err(format!("The frame delays of frames {}-{} and {}-{} overlap.", pair[0].first, pair[0].last, pair[1].first, pair[1].last));
+        }
+        self.delays = delays;
+        Ok(self)
+    }
+
+    /// The time a position on the timeline is shown at.
+    ///
+    /// # Arguments
+    /// * `position` - The position in frames, fractional between two frames.
+    ///
+    /// # Returns
+    /// The time in seconds since the first frame, as the scene is sampled regardless of delays.
+    pub fn time_at(&self, position: f32) -> f32 {
+        position / self.frames_per_second
+    }
+
+    /// The number of frames needed to fill a duration, at least one.
+    ///
+    /// # Arguments
+    /// * `seconds` - The duration to fill.
+    pub fn frame_count(&self, seconds: f32) -> usize {
+        ((seconds * self.frames_per_second).round() as usize).max(1)
+    }
+
+    /// How long a frame is shown, in seconds, from its delay segment or the frame rate.
+    fn frame_seconds(&self, frame: usize) -> f64 {
+        match self.delays.iter().find(|segment| (segment.first..=segment.last).contains(&frame)) {
+            Some(segment) => segment.seconds_at(frame),
+            None => 1.0 / self.frames_per_second as f64,
+        }
+    }
+
+    /// When a frame starts being shown, in seconds since the first frame.
+    fn start_of(&self, frame: usize) -> f64 {
+        let interval = 1.0 / self.frames_per_second as f64;
+        let adjustment: f64 = self
+            .delays
+            .iter()
+            .flat_map(|segment| segment.first..(segment.last + 1).min(frame))
+            .map(|delayed| self.frame_seconds(delayed) - interval)
+            .sum();
+        frame as f64 * interval + adjustment
+    }
+
+    /// How long a frame is shown, rounded to a unit of time with the error carried over.
+    ///
+    /// # Arguments
+    /// * `frame` - The index of the frame.
+    /// * `units_per_second` - The number of units in a second, 100 for GIF delays, 1000 for milliseconds
+    ///   or the frame rate for the number of video frames.
+    ///
+    /// # Returns
+    /// The number of units between the rounded start and end of the frame.
+    pub fn delay_at(&self, frame: usize, units_per_second: f64) -> u32 {
+        let boundary = |frame: usize| (self.start_of(frame) * units_per_second).round() as u32;
+        boundary(frame + 1) - boundary(frame)
+    }
+
+    /// How long a frame of a GIF is shown, in hundredths of a second.
+    ///
+    /// # Arguments
+    /// * `frame` - The index of the frame.
+    pub fn gif_delay_at(&self, frame: usize) -> u16 {
+        self.delay_at(frame, 100.0) as u16
+    }
+
+    /// How long a frame lasts in real time, which paces the preview.
+    ///
+    /// # Arguments
+    /// * `frame` - The index of the frame.
+    #[cfg(feature = "window")]
+    pub fn frame_duration(&self, frame: usize) -> Duration {
+        Duration::from_secs_f64(self.frame_seconds(frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rounded_delays_do_not_drift() {
+        let timeline = Timeline::new(30.0).unwrap();
+
+        let delays: Vec<u16> = (0..6).map(|frame| timeline.gif_delay_at(frame)).collect();
+        let second: u32 = (0..30).map(|frame| timeline.gif_delay_at(frame) as u32).sum();
+
+        assert_eq!(delays, vec![3, 4, 3, 3, 4, 3]);
+        assert_eq!(second, 100);
+        assert_eq!((0..3).map(|frame| timeline.delay_at(frame, 1000.0)).collect::<Vec<_>>(), vec![33, 34, 33]);
+        assert_eq!((timeline.time_at(15.0), timeline.frame_count(1.0)), (0.5, 30));
+        assert!(Timeline::new(0.0).is_err() && Timeline::new(120.0).is_err());
+    }
+
+    #[test]
+    fn test_segments_hold_and_ease_frames() {
+        let segments = ["0:2", "3-5:0.4~0.2"].map(|spec| spec.parse::<DelaySegment>().unwrap());
+        let timeline = Timeline::default().with_delays(segments.to_vec()).unwrap();
+
+        let delays: Vec<u16> = (0..8).map(|frame| timeline.gif_delay_at(frame)).collect();
+
+        assert_eq!(delays, vec![200, 10, 10, 40, 30, 20, 10, 10]);
+        assert_eq!(timeline.time_at(3.0), Timeline::default().time_at(3.0));
+        assert_eq!(timeline.delays.iter().map(ToString::to_string).collect::<Vec<_>>(), vec!["0:2", "3-5:0.4~0.2"]);
+        assert!(Timeline::default().with_delays(vec![segments[1], "5:1".parse().unwrap()]).is_err());
+        assert!("2-1:1".parse::<DelaySegment>().is_err() && "0:0".parse::<DelaySegment>().is_err() && "0".parse::<DelaySegment>().is_err());
+    }
+}
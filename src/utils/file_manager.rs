@@ -14,7 +14,7 @@ impl FileManager {
     /// * `directory` - Path to directory to create
     fn ensure_directory_exists(directory: &str) -> io::Result<()> {
         let dir_path = directory;
-        fs::create_dir_all(&dir_path)?;
+        fs::create_dir_all(dir_path)?;
         println!("Directory '{}' exists or created successfully.", directory);
         Ok(())
     }
@@ -34,7 +34,7 @@ impl FileManager {
         println!("Image '{}' saved successfully.", timestamped_path);
 
         // Save current version
-        let mut file = File::create(&INPUT_IMAGE_PATH)?;
+        let mut file = File::create(INPUT_IMAGE_PATH)?;
         file.write_all(image_bytes)?;
         println!("Image '{}' saved successfully.", INPUT_IMAGE_PATH);
 
@@ -56,7 +56,7 @@ impl FileManager {
         println!("Prompt '{}' saved successfully.", timestamped_path);
 
         // Save current version
-        let mut file = File::create(&CURRENT_PROMPT_PATH)?;
+        let mut file = File::create(CURRENT_PROMPT_PATH)?;
         file.write_all(prompt.as_bytes())?;
         println!("Prompt '{}' saved successfully.", CURRENT_PROMPT_PATH);
 
@@ -70,7 +70,7 @@ impl FileManager {
     pub fn update_readme(prompt: &str) -> io::Result<()> {
         let readme_path = "README.md";
 
-        match fs::read_to_string(&readme_path) {
+        match fs::read_to_string(readme_path) {
             Ok(content) => {
                 let mut updated_content = String::new();
                 let mut found_screenshot = false;
@@ -87,7 +87,7 @@ impl FileManager {
                 }
 
                 if found_screenshot {
-                    fs::write(&readme_path, updated_content)?;
+                    fs::write(readme_path, updated_content)?;
                     println!("README updated successfully.");
                 }
 
@@ -1,20 +1,29 @@
-use std::collections::HashMap;
 use std::env;
+use std::io::Cursor;
 use std::error::Error;
 use std::process::exit;
 use std::time::Instant;
-use chrono::NaiveDate;
-use minifb::Key;
+use chrono::{Datelike, NaiveDate};
+use image::ImageFormat;
 use crate::{generators, utils};
-use crate::graphics::color::extract_palette;
-use crate::graphics::parallax::create_parallax_layers;
-use crate::state::constants::file_paths::CURRENT_GIF_PATH;
-use crate::state::constants::graphics::CAMERA_X_INCREMENT;
+use crate::generators::outpainter::{extend_horizontally, MirrorOutpainter, Outpainter, Outpainting};
+use crate::graphics::actor::Actor;
+use crate::graphics::color::{extract_palette, PaletteMaps};
+use crate::graphics::grading::ColorTimeline;
+use crate::graphics::output::OutputFormat;
+use crate::graphics::parallax::{create_parallax_layers, load_parallax_layers, Panorama, ParallaxLayer};
+use crate::graphics::particles::{ParticleKind, ParticleOverlay};
+use crate::graphics::sprites::SpriteMaps;
+use crate::graphics::text::TextOverlay;
+use crate::state::camera_path::CameraPath;
+use crate::state::constants::file_paths::{CURRENT_GIF_PATH, INPUT_IMAGE_PATH};
+use crate::state::constants::graphics::{WINDOW_HEIGHT, WINDOW_WIDTH};
 use crate::state::structs::State;
+use crate::state::timeline::{DelaySegment, Timeline};
 use timing_macro::timed;
 
-/// Utility functions for initializing and managing Python interpreters, generators,
-/// and handling image generation and processing.
+// Utility functions for initializing and managing Python interpreters, generators,
+// and handling image generation and processing.
 
 /// Prepares the Python interpreter for free-threaded use.
 /// This is required when using the `pyo3` crate to ensure Python's GIL (Global Interpreter Lock)
@@ -85,6 +94,38 @@ pub fn generate_and_save_image(
     Ok(prompt)
 }
 
+/// Extends the generated image to the left and right by outpainting, replacing it with the wider strip.
+///
+/// Both the dated and the current image are replaced, so the palette and the parallax layers are
+/// made from the strip, and the layers repeat less often as the camera scrolls.
+///
+/// # Arguments
+/// - `image_generator`: Reference to the image generator instance, used unless the outpainting is local.
+/// - `outpainting`: How far and with which backend the image is extended.
+/// - `prompt`: The prompt the image was generated from.
+/// - `current_date`: The current date used for naming the saved files.
+///
+/// # Returns
+/// `Ok(())` if the strip is successfully generated and saved, otherwise an error.
+#[timed]
+pub fn outpaint_input_image(
+    image_generator: &generators::image_generator::ImageGenerator,
+    outpainting: Outpainting,
+    prompt: &str,
+    current_date: NaiveDate,
+) -> Result<(), Box<dyn Error>> {
+    let image = image::open(INPUT_IMAGE_PATH)?.to_rgba8();
+    let outpainter: &dyn Outpainter = if outpainting.local { &MirrorOutpainter } else { image_generator };
+    let strip = extend_horizontally(outpainter, &image, outpainting.steps, prompt)?;
+
+    let mut png = Vec::new();
+    strip.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+    utils::file_manager::FileManager::save_image(&png, current_date)?;
+
+    println!("Image extended from {}x{} to {}x{}", image.width(), image.height(), strip.width(), strip.height());
+    Ok(())
+}
+
 /// Creates parallax layers for a given date based on an input image.
 ///
 /// # Arguments
@@ -99,13 +140,27 @@ pub fn create_parallax_layers_for_date(input_image_path: &str, current_date: Nai
 
     create_parallax_layers(input_image_path, current_date).map_err(|e| {
         eprintln!("Error creating parallax layers: {}", e);
-        Box::<dyn Error>::from(e)
+        e
     })?;
 
     println!("Parallax layers for date {} created successfully.", current_date);
     Ok(())
 }
 
+/// Looks up the value following a command-line flag.
+///
+/// # Arguments
+/// - `flag`: The flag to look for, such as `--size`.
+///
+/// # Returns
+/// The argument following the flag, an empty string if the flag is the last argument, or `None`
+/// if the flag is absent.
+fn flag_value(flag: &str) -> Option<String> {
+    let mut args = env::args().skip_while(|arg| arg != flag);
+    args.next()?;
+    Some(args.next().unwrap_or_default())
+}
+
 /// Parses command-line arguments to determine if the application should run in headless mode.
 ///
 /// # Returns
@@ -118,38 +173,313 @@ pub fn parse_headless_mode() -> bool {
     headless
 }
 
-/// Checks if the application window is open and not in a closed state.
+/// Parses command-line arguments to determine if the layers should be exported as a game engine package.
+///
+/// # Returns
+/// `true` if the `--export-layers` flag is present in the arguments, otherwise `false`.
+pub fn parse_export_layers() -> bool {
+    let export_layers = env::args().any(|arg| arg == "--export-layers");
+    println!("Layer package export activated: {}", export_layers);
+    export_layers
+}
+
+/// Parses command-line arguments for the path of an optional parallax layer configuration.
+///
+/// # Returns
+/// The path following the `--layers` flag, or `None` if the flag is absent.
+pub fn parse_layer_config_path() -> Option<String> {
+    flag_value("--layers")
+}
+
+/// Parses command-line arguments for an optional parallax layer configuration.
+///
+/// # Returns
+/// The layers loaded from the JSON file following the `--layers` flag, or `None` if the flag is absent.
+///
+/// # Panics
+/// Panics if the layers cannot be loaded.
+pub fn parse_layer_config() -> Option<Vec<ParallaxLayer>> {
+    let path = parse_layer_config_path()?;
+    let layers = load_parallax_layers(&path).unwrap_or_else(|e| panic!("Invalid layer configuration '{}': {}", path, e));
+    println!("Loaded {} parallax layers from '{}'", layers.len(), path);
+    Some(layers)
+}
+
+/// Parses command-line arguments for an optional camera path.
+///
+/// The value following the `--camera-path` flag is either a JSON file with a `keyframes` array,
+/// or keyframes written inline as `seconds:x,y[,zoom][:easing]` separated by semicolons.
+///
+/// # Returns
+/// The parsed `CameraPath`, or `None` if the flag is absent.
+///
+/// # Panics
+/// Panics if the camera path cannot be loaded or parsed.
+pub fn parse_camera_path() -> Option<CameraPath> {
+    let value = flag_value("--camera-path")?;
+    let camera_path = match value.ends_with(".json") {
+        true => CameraPath::load(&value),
+        false => value.parse::<CameraPath>(),
+    }
+    .unwrap_or_else(|e| panic!("Invalid camera path '{}': {}", value, e));
+    println!("Camera path with {} keyframes over {} seconds", camera_path.keyframes.len(), camera_path.duration());
+    Some(camera_path)
+}
+
+/// Parses command-line arguments for an optional animated character.
+///
+/// The value following the `--actor` flag is a sprite sheet with its frame size and optional
+/// frame rate, written as `path:<width>x<height>[:fps]`.
+///
+/// # Returns
+/// The loaded `Actor`, or `None` if the flag is absent.
+///
+/// # Panics
+/// Panics if the actor cannot be loaded.
+pub fn parse_actor() -> Option<Actor> {
+    let value = flag_value("--actor")?;
+    let actor = Actor::from_spec(&value).unwrap_or_else(|e| panic!("Invalid actor '{}': {}", value, e));
+    println!("Actor loaded from '{}' at {} frames per second", value, actor.frames_per_second);
+    Some(actor)
+}
+
+/// Parses command-line arguments for optional color grading over the animation.
+///
+/// # Returns
+/// The color timeline loaded from the JSON file following the `--grade` flag, or `None` if the flag is absent.
+///
+/// # Panics
+/// Panics if the color timeline or one of its LUTs cannot be loaded.
+pub fn parse_color_grade() -> Option<ColorTimeline> {
+    let value = flag_value("--grade")?;
+    let color_grade = ColorTimeline::load(&value).unwrap_or_else(|e| panic!("Invalid color grading '{}': {}", value, e));
+    println!("Color grading with {} keyframes and {} flashes", color_grade.keyframes.len(), color_grade.flashes.len());
+    Some(color_grade)
+}
+
+/// Parses command-line arguments for the size of the rendered viewport.
+///
+/// The viewport is independent of the size of the layer images, which are scaled to fill its
+/// height, so `--size 1920x480` renders a wide strip and `--size 256x256` a thumbnail.
+///
+/// # Returns
+/// The width and height following the `--size` flag, or `WINDOW_WIDTH` by `WINDOW_HEIGHT` if the flag is absent.
+///
+/// # Panics
+/// Panics if the size is not `<width>x<height>` with both between 1 and 65535, the largest GIF dimension.
+pub fn parse_viewport_size() -> (usize, usize) {
+    let Some(value) = flag_value("--size") else {
+        return (WINDOW_WIDTH, WINDOW_HEIGHT);
+    };
+    let size = value
+        .split_once('x')
+        .and_then(|(width, height)| Some((width.parse::<u16>().ok()?, height.parse::<u16>().ok()?)))
+        .filter(|&(width, height)| width > 0 && height > 0)
+        .unwrap_or_else(|| panic!("Invalid value '{}' for --size, expected <width>x<height>.", value));
+    println!("Viewport size: {}x{}", size.0, size.1);
+    (size.0 as usize, size.1 as usize)
+}
+
+/// Parses command-line arguments for an optional panoramic viewport.
+///
+/// The value following the `--panorama` flag is the number of scene widths the viewport shows side
+/// by side, optionally followed by `:mirrored` to mirror every other repeat. The viewport keeps the
+/// height given with `--size` and is made as wide as the tiles.
+///
+/// # Returns
+/// The parsed `Panorama`, or `None` if the flag is absent.
+///
+/// # Panics
+/// Panics if the panorama cannot be parsed.
+pub fn parse_panorama() -> Option<Panorama> {
+    let value = flag_value("--panorama")?;
+    let panorama = value.parse::<Panorama>().unwrap_or_else(|e| panic!("{}", e));
+    println!("Panorama of {} tiles{}", panorama.tiles, if panorama.mirrored { " with mirrored repeats" } else { "" });
+    Some(panorama)
+}
+
+/// Resolves the size of the rendered viewport, widening it to fit the tiles of a panorama.
 ///
 /// # Arguments
-/// - `state`: A reference to the current application state.
+/// - `panorama`: The panorama parsed by `parse_panorama`, if any.
+/// - `viewport_size`: The width and height parsed by `parse_viewport_size`.
+/// - `date`: The date of the layers, whose scene size sets the width of a tile.
 ///
 /// # Returns
-/// `true` if the window is open and the Escape key is not pressed, otherwise `false`.
-pub fn is_window_open(state: &State) -> bool {
-    if let Some(window) = &state.window {
-        window.is_open() && !window.is_key_down(Key::Escape)
-    } else {
-        true
+/// The width and height of the viewport, or an error if the layers cannot be read or the
+/// panorama is wider than a GIF can be.
+pub fn resolve_viewport_size(panorama: Option<Panorama>, viewport_size: (usize, usize), date: NaiveDate) -> Result<(usize, usize), Box<dyn Error>> {
+    match panorama {
+        Some(panorama) => panorama.viewport_size(viewport_size.1, SpriteMaps::scene_size(date)?),
+        None => Ok(viewport_size),
     }
 }
 
-/// Simulates camera movement by incrementing its x-coordinate.
+/// Parses command-line arguments for extending the generated image by outpainting.
+///
+/// The value following the `--outpaint` flag is the number of steps on each side, each adding half
+/// the image's width, optionally followed by `:local` to mirror the image instead of calling the API.
+///
+/// # Returns
+/// The parsed `Outpainting`, or `None` if the flag is absent.
+///
+/// # Panics
+/// Panics if the outpainting cannot be parsed.
+pub fn parse_outpainting() -> Option<Outpainting> {
+    let value = flag_value("--outpaint")?;
+    let outpainting = value.parse::<Outpainting>().unwrap_or_else(|e| panic!("{}", e));
+    println!("Outpainting {} steps on each side{}", outpainting.steps, if outpainting.local { " locally" } else { "" });
+    Some(outpainting)
+}
+
+/// Parses command-line arguments for the frame rate and frame delays of the timeline.
+///
+/// # Returns
+/// A timeline at the frames per second following the `--fps` flag, with the delay segments
+/// separated by semicolons following the `--delays` flag, or the default timeline without either.
+///
+/// # Panics
+/// Panics if the frame rate is not within `FRAME_RATE_RANGE` or a delay segment is invalid.
+pub fn parse_timeline() -> Timeline {
+    let timeline = match flag_value("--fps") {
+        Some(value) => {
+            let frames_per_second = value.parse::<f32>().unwrap_or_else(|_| panic!("Invalid value '{}' for --fps, expected frames per second.", value));
+            let timeline = Timeline::new(frames_per_second).unwrap_or_else(|e| panic!("{}", e));
+            println!("Timeline runs at {} frames per second", timeline.frames_per_second);
+            timeline
+        }
+        None => Timeline::default(),
+    };
+
+    let Some(value) = flag_value("--delays") else {
+        return timeline;
+    };
+    let timeline = value
+        .split(';')
+        .filter(|segment| !segment.trim().is_empty())
+        .map(str::parse::<DelaySegment>)
+        .collect::<Result<Vec<_>, _>>()
+        .and_then(|delays| timeline.with_delays(delays))
+        .unwrap_or_else(|e| panic!("{}", e));
+    println!("Frame delays: {}", timeline.delays.iter().map(ToString::to_string).collect::<Vec<_>>().join(";"));
+    timeline
+}
+
+/// Parses command-line arguments for an optional GIF size budget.
+///
+/// # Returns
+/// The number of bytes following the `--max-gif-bytes` flag, or `None` if the flag is absent.
+///
+/// # Panics
+/// Panics if the flag is not followed by a valid number of bytes.
+pub fn parse_max_gif_bytes() -> Option<u64> {
+    let value = flag_value("--max-gif-bytes")?;
+    let max_gif_bytes = value.parse::<u64>().unwrap_or_else(|_| {
+        panic!("Invalid value '{}' for --max-gif-bytes, expected a number of bytes.", value);
+    });
+    println!("GIF size budget: {} bytes", max_gif_bytes);
+    Some(max_gif_bytes)
+}
+
+/// Parses command-line arguments for additional animation formats to write alongside the GIF.
+///
+/// # Returns
+/// The comma-separated formats following the `--formats` flag, or an empty list if the flag is absent.
+///
+/// # Panics
+/// Panics if one of the formats is unknown.
+pub fn parse_output_formats() -> Vec<OutputFormat> {
+    let Some(value) = flag_value("--formats") else {
+        return Vec::new();
+    };
+    let formats = value
+        .split(',')
+        .map(|name| name.trim().parse::<OutputFormat>().unwrap_or_else(|e| panic!("{}", e)))
+        .collect::<Vec<_>>();
+    println!("Additional output formats: {}", formats.iter().map(OutputFormat::to_string).collect::<Vec<_>>().join(","));
+    formats
+}
+
+/// Parses command-line arguments for procedurally animated overlays.
+///
+/// The value following the `--particles` flag lists overlays as `kind[:count][@depth]` separated
+/// by commas, or is `auto` to pick the overlay matching the weather or season in the prompt. The
+/// particles are seeded with the number following `--seed`, or with the date so each day's GIF
+/// is reproducible.
 ///
 /// # Arguments
-/// - `state`: A mutable reference to the current application state.
-pub fn simulate_camera_movement(state: &mut State) {
-    state.camera.x += CAMERA_X_INCREMENT;
+/// - `prompt`: The prompt the image was generated from, used by `auto`.
+/// - `date`: The date of the image, used as the seed when `--seed` is absent.
+///
+/// # Returns
+/// The overlays to draw, or an empty list if the flag is absent or `auto` finds no match.
+///
+/// # Panics
+/// Panics if an overlay or the seed is invalid.
+pub fn parse_particle_overlays(prompt: &str, date: NaiveDate) -> Vec<ParticleOverlay> {
+    let Some(value) = flag_value("--particles") else {
+        return Vec::new();
+    };
+    let seed = match flag_value("--seed") {
+        Some(seed) => seed.parse::<u64>().unwrap_or_else(|_| panic!("Invalid value '{}' for --seed, expected a number.", seed)),
+        None => date.num_days_from_ce() as u64,
+    };
+
+    let overlays = match value.as_str() {
+        "auto" => ParticleKind::from_prompt(prompt).map(|kind| ParticleOverlay::new(kind, seed)).into_iter().collect(),
+        _ => ParticleOverlay::parse_list(&value, seed).unwrap_or_else(|e| panic!("{}", e)),
+    };
+    println!("Particle overlays with seed {}: {}", seed, overlays.iter().map(ToString::to_string).collect::<Vec<_>>().join(","));
+    overlays
+}
+
+/// Parses command-line arguments for text drawn over the frames.
+///
+/// The value following the `--text` flag is a JSON file listing the overlays, or `auto` to title
+/// the GIF with the prompt and mark it with the date. `{prompt}` and `{date}` in the text of an
+/// overlay are replaced by the prompt and the date.
+///
+/// # Arguments
+/// - `prompt`: The prompt the image was generated from.
+/// - `date`: The date of the image.
+///
+/// # Returns
+/// The text overlays to draw, or an empty list if the flag is absent.
+///
+/// # Panics
+/// Panics if the file cannot be read or is invalid.
+pub fn parse_text_overlays(prompt: &str, date: NaiveDate) -> Vec<TextOverlay> {
+    let Some(value) = flag_value("--text") else {
+        return Vec::new();
+    };
+
+    let overlays = match value.as_str() {
+        "auto" => TextOverlay::auto(),
+        path => TextOverlay::load(path).unwrap_or_else(|e| panic!("Invalid text overlays '{}': {}", path, e)),
+    };
+    let overlays: Vec<TextOverlay> = overlays.into_iter().map(|overlay| overlay.with_placeholders(prompt, date)).collect();
+    println!("Text overlays: {}", overlays.iter().map(|overlay| format!("'{}'", overlay.text)).collect::<Vec<_>>().join(", "));
+    overlays
 }
 
-/// Determines whether a frame should be processed based on the elapsed time.
+/// Checks if the render target still accepts frames.
 ///
 /// # Arguments
-/// - `last_update`: A reference to the timestamp of the last frame update.
+/// - `state`: A reference to the current application state.
 ///
 /// # Returns
-/// `true` if the elapsed time since the last update meets the threshold, otherwise `false`.
-pub fn should_process_frame(last_update: &Instant) -> bool {
-    last_update.elapsed() >= std::time::Duration::from_nanos(0)
+/// `false` once a window is closed or Escape is pressed, otherwise `true`. Offscreen targets are always open.
+pub fn is_window_open(state: &State) -> bool {
+    state.target.is_open()
+}
+
+/// Simulates camera movement by advancing the timeline by one frame, following the camera path
+/// or scrolling at `CAMERA_SPEED` without one.
+///
+/// # Arguments
+/// - `state`: A mutable reference to the current application state.
+pub fn simulate_camera_movement(state: &mut State) {
+    state.seek((state.frame_index + 1) as f32);
 }
 
 /// Finalizes the GIF encoding process and updates the README file.
@@ -158,7 +488,7 @@ pub fn should_process_frame(last_update: &Instant) -> bool {
 /// - `state`: The current application state.
 /// - `frame_count`: The total number of frames captured.
 /// - `path`: The file path where the GIF is saved.
-pub fn finalize_gif_encoding(state: State, frame_count: usize, path: &str) {
+pub fn finalize_gif_encoding(state: &State, frame_count: usize, path: &str) {
     println!("Finished capturing {} frames to file '{}'", frame_count, path);
 
     std::fs::copy(path, CURRENT_GIF_PATH).expect("Failed to copy GIF to 'current.gif'");
@@ -183,7 +513,7 @@ pub fn finalize_gif_encoding(state: State, frame_count: usize, path: &str) {
 /// - A vector of palette colors.
 /// - A hash map mapping pixel values to palette indices.
 #[timed]
-pub fn extract_palette_or_exit(image_path: &str) -> (Vec<u8>, HashMap<u32, u8>) {
+pub fn extract_palette_or_exit(image_path: &str) -> PaletteMaps {
     match extract_palette(image_path) {
         Ok(result) => result,
         Err(e) => {